@@ -0,0 +1,325 @@
+// Copyright (C) 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Statistics of a records file.
+//!
+//! Tells where the bytes of a records file go: which mounts and files, when during boot, how
+//! scattered the reads are and how much is read more than once.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::format::{union_ranges, DeviceNumber, InodeNumber, RecordsFile};
+
+/// Bytes read from one mount.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct MountBytes {
+    /// Mount point of the mount, or the device number if no file of the mount has a handle.
+    pub mount: String,
+
+    /// Number of files read from the mount.
+    pub files: u64,
+
+    /// Bytes read from the mount.
+    pub bytes: u64,
+}
+
+/// Bytes read from one file.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct FileBytes {
+    /// First path of the file.
+    pub path: String,
+
+    /// Number of records of the file.
+    pub records: u64,
+
+    /// Bytes read from the file.
+    pub bytes: u64,
+}
+
+/// Bytes read during one interval of time.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct TimeBucket {
+    /// Start of the interval in milliseconds.
+    pub start_ms: u64,
+
+    /// Number of records read during the interval.
+    pub records: u64,
+
+    /// Bytes read during the interval.
+    pub bytes: u64,
+}
+
+/// How scattered the reads of files are.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Fragmentation {
+    /// Average number of records per file.
+    pub mean_records_per_file: f64,
+
+    /// Largest number of records of a file.
+    pub max_records_per_file: u64,
+
+    /// Average length of a record in bytes.
+    pub mean_record_length: u64,
+
+    /// Number of files with a single record.
+    pub single_record_files: u64,
+}
+
+/// Reads of the same data through several paths or records.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct Duplicates {
+    /// Number of files with more than one path.
+    pub hardlinked_files: u64,
+
+    /// Number of inodes that are in the records file under more than one file id.
+    pub duplicate_inodes: u64,
+
+    /// Bytes that are read more than once, by overlapping records of the same inode.
+    pub duplicate_bytes: u64,
+}
+
+/// Statistics of a records file.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct AnalyzeReport {
+    /// Number of files.
+    pub files: u64,
+
+    /// Number of records.
+    pub records: u64,
+
+    /// Bytes read by all the records.
+    pub bytes: u64,
+
+    /// Bytes per mount, most read first.
+    pub mounts: Vec<MountBytes>,
+
+    /// The most read files, most read first.
+    pub top_files: Vec<FileBytes>,
+
+    /// Width of the intervals of `timeline` in milliseconds.
+    pub bucket_ms: u64,
+
+    /// Bytes read over time, from the interval of the first record to the one of the last.
+    pub timeline: Vec<TimeBucket>,
+
+    /// How scattered the reads of files are.
+    pub fragmentation: Fragmentation,
+
+    /// Reads of the same data through several paths or records.
+    pub duplicates: Duplicates,
+}
+
+impl fmt::Display for AnalyzeReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} files, {} records, {} bytes", self.files, self.records, self.bytes)?;
+        writeln!(f, "\nbytes per mount:")?;
+        for mount in &self.mounts {
+            writeln!(f, "{:>14} {:>8} files  {}", mount.bytes, mount.files, mount.mount)?;
+        }
+        writeln!(f, "\ntop {} files:", self.top_files.len())?;
+        for file in &self.top_files {
+            writeln!(f, "{:>14} {:>8} records  {}", file.bytes, file.records, file.path)?;
+        }
+        writeln!(f, "\nbytes per {}ms:", self.bucket_ms)?;
+        let max_bytes = self.timeline.iter().map(|bucket| bucket.bytes).max().unwrap_or(0).max(1);
+        for bucket in &self.timeline {
+            let bar = "#".repeat((bucket.bytes * 50 / max_bytes) as usize);
+            writeln!(f, "{:>10}ms {:>14} {}", bucket.start_ms, bucket.bytes, bar)?;
+        }
+        let fragmentation = &self.fragmentation;
+        writeln!(f, "\nfragmentation:")?;
+        writeln!(
+            f,
+            "  records per file: {:.2} mean, {} max",
+            fragmentation.mean_records_per_file, fragmentation.max_records_per_file
+        )?;
+        writeln!(f, "  record length: {} bytes mean", fragmentation.mean_record_length)?;
+        writeln!(f, "  single record files: {}", fragmentation.single_record_files)?;
+        let duplicates = &self.duplicates;
+        writeln!(f, "\nduplicates:")?;
+        writeln!(f, "  hardlinked files: {}", duplicates.hardlinked_files)?;
+        writeln!(f, "  inodes under several file ids: {}", duplicates.duplicate_inodes)?;
+        writeln!(f, "  bytes read more than once: {}", duplicates.duplicate_bytes)
+    }
+}
+
+/// Computes the statistics of `rf`. `top` is the number of files in `top_files` and
+/// `bucket_ms` the width of the intervals of `timeline`.
+pub fn analyze_records_file(rf: &RecordsFile, top: usize, bucket_ms: u64) -> AnalyzeReport {
+    let inner = &rf.inner;
+    let bucket_ms = bucket_ms.max(1);
+    let mut report = AnalyzeReport {
+        files: inner.inode_map.len() as u64,
+        records: inner.records.len() as u64,
+        bucket_ms,
+        ..Default::default()
+    };
+
+    let mut files: HashMap<_, FileBytes> = HashMap::new();
+    let mut buckets: HashMap<u64, TimeBucket> = HashMap::new();
+    let mut ranges_by_inode: HashMap<(DeviceNumber, InodeNumber), Vec<(u64, u64)>> = HashMap::new();
+    for record in &inner.records {
+        let info = match inner.inode_map.get(&record.file_id) {
+            Some(info) => info,
+            None => continue,
+        };
+        report.bytes += record.length;
+        let file = files.entry(&record.file_id).or_insert_with(|| FileBytes {
+            path: info.paths[0].clone(),
+            records: 0,
+            bytes: 0,
+        });
+        file.records += 1;
+        file.bytes += record.length;
+
+        let start_ms = record.timestamp / 1_000_000 / bucket_ms * bucket_ms;
+        let bucket =
+            buckets.entry(start_ms).or_insert(TimeBucket { start_ms, records: 0, bytes: 0 });
+        bucket.records += 1;
+        bucket.bytes += record.length;
+
+        ranges_by_inode
+            .entry((info.device_number, info.inode_number))
+            .or_default()
+            .push((record.offset, record.offset + record.length));
+    }
+
+    let mut mounts: HashMap<String, MountBytes> = HashMap::new();
+    for (id, file) in &files {
+        let info = &inner.inode_map[*id];
+        let mount = rf.mount_name(info);
+        let mount_bytes =
+            mounts.entry(mount.clone()).or_insert(MountBytes { mount, files: 0, bytes: 0 });
+        mount_bytes.files += 1;
+        mount_bytes.bytes += file.bytes;
+    }
+    report.mounts = mounts.into_values().collect();
+    report.mounts.sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.mount.cmp(&b.mount)));
+
+    let mut top_files: Vec<FileBytes> = files.into_values().collect();
+    if !top_files.is_empty() {
+        let fragmentation = &mut report.fragmentation;
+        fragmentation.mean_records_per_file = report.records as f64 / top_files.len() as f64;
+        fragmentation.max_records_per_file =
+            top_files.iter().map(|file| file.records).max().unwrap_or(0);
+        fragmentation.mean_record_length = report.bytes / report.records;
+        fragmentation.single_record_files =
+            top_files.iter().filter(|file| file.records == 1).count() as u64;
+    }
+    top_files.sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.path.cmp(&b.path)));
+    top_files.truncate(top);
+    report.top_files = top_files;
+
+    if let (Some(first), Some(last)) = (buckets.keys().min(), buckets.keys().max()) {
+        report.timeline = (*first..=*last)
+            .step_by(bucket_ms as usize)
+            .map(|start_ms| {
+                buckets.remove(&start_ms).unwrap_or(TimeBucket { start_ms, records: 0, bytes: 0 })
+            })
+            .collect();
+    }
+
+    let mut ids_by_inode: HashMap<(DeviceNumber, InodeNumber), u64> = HashMap::new();
+    for info in inner.inode_map.values() {
+        if info.paths.len() > 1 {
+            report.duplicates.hardlinked_files += 1;
+        }
+        *ids_by_inode.entry((info.device_number, info.inode_number)).or_default() += 1;
+    }
+    report.duplicates.duplicate_inodes =
+        ids_by_inode.values().filter(|ids| **ids > 1).count() as u64;
+    report.duplicates.duplicate_bytes = ranges_by_inode
+        .into_values()
+        .map(|ranges| {
+            let read: u64 = ranges.iter().map(|(start, end)| end - start).sum();
+            let unique: u64 = union_ranges(ranges).iter().map(|(start, end)| end - start).sum();
+            read - unique
+        })
+        .sum();
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{FileId, InodeInfo, Record};
+
+    fn add_file(rf: &mut RecordsFile, id: u64, inode_number: u64, paths: &[&str]) {
+        let paths = paths.iter().map(|path| path.to_string()).collect();
+        rf.insert_or_update_inode_info(FileId(id), InodeInfo::new(inode_number, 1 << 20, paths, 1));
+    }
+
+    fn add_record(rf: &mut RecordsFile, id: u64, offset: u64, length: u64, timestamp_ms: u64) {
+        rf.insert_record(Record {
+            file_id: FileId(id),
+            offset,
+            length,
+            timestamp: timestamp_ms * 1_000_000,
+        });
+    }
+
+    #[test]
+    fn test_analyze_records_file() {
+        let mut rf = RecordsFile::default();
+        add_file(&mut rf, 0, 10, &["/system/lib/libc.so", "/system/lib/libc_link.so"]);
+        add_file(&mut rf, 1, 20, &["/system/bin/init"]);
+        // Same inode as file 1, as in a records file built from several sources.
+        add_file(&mut rf, 2, 20, &["/system/bin/init_copy"]);
+        add_record(&mut rf, 0, 0, 8192, 5);
+        add_record(&mut rf, 0, 4096, 8192, 250);
+        add_record(&mut rf, 1, 0, 4096, 30);
+        add_record(&mut rf, 2, 0, 4096, 40);
+
+        let report = analyze_records_file(&rf, 1, 100);
+        assert_eq!((report.files, report.records, report.bytes), (3, 4, 24576));
+        assert_eq!(
+            report.mounts,
+            vec![MountBytes { mount: "device 0:1".to_owned(), files: 3, bytes: 24576 }]
+        );
+        assert_eq!(
+            report.top_files,
+            vec![FileBytes { path: "/system/lib/libc.so".to_owned(), records: 2, bytes: 16384 }]
+        );
+        assert_eq!(
+            report.timeline,
+            vec![
+                TimeBucket { start_ms: 0, records: 3, bytes: 16384 },
+                TimeBucket { start_ms: 100, records: 0, bytes: 0 },
+                TimeBucket { start_ms: 200, records: 1, bytes: 8192 },
+            ]
+        );
+        assert_eq!(report.fragmentation.max_records_per_file, 2);
+        assert_eq!(report.fragmentation.single_record_files, 2);
+        assert_eq!(report.fragmentation.mean_record_length, 6144);
+        assert_eq!(
+            report.duplicates,
+            Duplicates { hardlinked_files: 1, duplicate_inodes: 1, duplicate_bytes: 4096 + 4096 }
+        );
+
+        let text = report.to_string();
+        assert!(text.starts_with("3 files, 4 records, 24576 bytes\n"), "{}", text);
+    }
+
+    #[test]
+    fn test_analyze_empty_records_file() {
+        let report = analyze_records_file(&RecordsFile::default(), 10, 100);
+        assert_eq!(report.bytes, 0);
+        assert!(report.timeline.is_empty());
+        assert_eq!(report.fragmentation, Fragmentation::default());
+    }
+}
@@ -15,6 +15,8 @@
 pub(crate) static DEFAULT_IO_DEPTH: u16 = 2;
 pub(crate) static DEFAULT_MAX_FDS: u16 = 128;
 pub(crate) static DEFAULT_EXIT_ON_ERROR: bool = false;
+pub(crate) static DEFAULT_QUEUE_DEPTH: u16 = 64;
+pub(crate) static DEFAULT_IOPRIO_LEVEL: u8 = 4;
 
 mod args_argh;
 use args_argh as args_internal;
@@ -23,27 +25,104 @@ use std::path::Path;
 use std::path::PathBuf;
 use std::process::exit;
 
+pub use args_internal::AnalyzeFormat;
+pub use args_internal::ImportFormat;
+pub use args_internal::IoPriorityClass;
 pub use args_internal::OutputFormat;
 pub use args_internal::ReplayArgs;
+pub use args_internal::ReplayEngine;
+pub use args_internal::ReplayStrategy;
 pub use args_internal::TracerType;
-pub use args_internal::{DumpArgs, MainArgs, RecordArgs, SubCommands};
+pub use args_internal::{
+    AnalyzeArgs, DiffArgs, DumpArgs, ImportArgs, MainArgs, MergeArgs, RecordArgs, SubCommands,
+    TrimArgs, UpgradeArgs, VerifyArgs,
+};
 use serde::Deserialize;
 use serde::Serialize;
 
+use crate::filter::DumpFilter;
+use crate::format::DigestAlgorithm;
+use crate::replay::validate_settings;
 use crate::Error;
 use log::error;
 
+/// Latest version of the config file schema.
+pub(crate) const CONFIG_VERSION: u32 = 2;
+
+// Config files written before the schema was versioned.
+fn default_config_version() -> u32 {
+    1
+}
+
 // Deserialized form of the config file
-#[derive(Clone, Debug, Default, Deserialize, Serialize, Eq, PartialEq)]
+#[derive(Clone, Debug, Deserialize, Serialize, Eq, PartialEq)]
 pub struct ConfigFile {
+    // Version of the schema the config file follows. Config files without a
+    // version are version 1. Versions newer than CONFIG_VERSION are rejected.
+    #[serde(default = "default_config_version")]
+    pub version: u32,
     // Files to be excluded in prefetch. These files might have been
     // added in the record file while recording,but we do not want to
     // replay these files. These can be two types of files:
     // 1) installation-specific files (e.g. files in /data) and
     // 2) large files which we do not want to load in replay (e.g. APK files).
     pub files_to_exclude_regex: Vec<String>,
-    // Files that are not in the record file, but need to be loaded during replay
+    // Files that are not in the record file, but need to be loaded during replay.
+    // Entries are glob patterns. Matching files are read in full.
     pub additional_replay_files: Vec<String>,
+    // Whether additional replay files are read before or after the recorded data.
+    #[serde(default)]
+    pub additional_replay_files_position: AdditionalFilesPosition,
+    // If not empty, only files with a path matching one of these regexes are
+    // replayed. Exclusions apply on top of inclusions.
+    #[serde(default)]
+    pub files_to_include_regex: Vec<String>,
+    // Files with a path matching a higher priority pattern are replayed first.
+    // A file takes the priority of the first pattern it matches. Files that
+    // match none have priority 0. Recorded order is kept within a priority.
+    #[serde(default)]
+    pub priorities: Vec<PathPriority>,
+    // Maximum number of bytes replayed from a single file.
+    #[serde(default)]
+    pub max_bytes_per_file: Option<u64>,
+    // Maximum number of bytes replayed in total.
+    #[serde(default)]
+    pub max_total_bytes: Option<u64>,
+}
+
+impl Default for ConfigFile {
+    fn default() -> Self {
+        Self {
+            version: CONFIG_VERSION,
+            files_to_exclude_regex: vec![],
+            additional_replay_files: vec![],
+            additional_replay_files_position: Default::default(),
+            files_to_include_regex: vec![],
+            priorities: vec![],
+            max_bytes_per_file: None,
+            max_total_bytes: None,
+        }
+    }
+}
+
+/// Replay priority of the files with a path matching `regex`.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, Eq, PartialEq)]
+pub struct PathPriority {
+    /// Regex matched against the path of a file.
+    pub regex: String,
+    /// Priority of the matching files. Higher priorities are replayed first.
+    pub priority: i32,
+}
+
+/// Where additional replay files are scheduled relative to the recorded records.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AdditionalFilesPosition {
+    /// Additional files are read before the recorded records.
+    Before,
+    /// Additional files are read after the recorded records.
+    #[default]
+    After,
 }
 
 fn verify_and_fix(args: &mut MainArgs) -> Result<(), Error> {
@@ -57,20 +136,122 @@ fn verify_and_fix(args: &mut MainArgs) -> Result<(), Error> {
             if let Some(p) = &arg.int_path {
                 ensure_path_doesnt_exist(p)?;
             }
+            ensure_max_bytes(arg.max_bytes)?;
+            match (arg.digest, &arg.mac_key_path) {
+                (Some(DigestAlgorithm::HmacSha256), None) => {
+                    return Err(Error::InvalidArgs {
+                        arg_name: "digest".to_string(),
+                        arg_value: "hmac_sha256".to_string(),
+                        error: "A MAC needs --mac-key-path".to_string(),
+                    });
+                }
+                (Some(digest), Some(_)) if digest != DigestAlgorithm::HmacSha256 => {
+                    return Err(Error::InvalidArgs {
+                        arg_name: "digest".to_string(),
+                        arg_value: format!("{digest:?}").to_lowercase(),
+                        error: "--mac-key-path needs --digest hmac_sha256".to_string(),
+                    });
+                }
+                (None, Some(_)) => arg.digest = Some(DigestAlgorithm::HmacSha256),
+                _ => {}
+            }
         }
         SubCommands::Replay(arg) => {
             ensure_path_exists(&arg.path)?;
             if !arg.config_path.as_os_str().is_empty() {
                 ensure_path_exists(&arg.config_path)?;
             }
+            if let Some(cgroup_path) = &arg.cgroup_path {
+                ensure_dir_exists(cgroup_path)?;
+            }
+            validate_settings(
+                arg.engine,
+                arg.queue_depth,
+                arg.strategy,
+                arg.ioprio_level,
+                arg.nice,
+                arg.max_memory_pressure,
+                arg.stop_property.as_deref(),
+            )?;
         }
         SubCommands::Dump(arg) => {
             ensure_path_exists(&arg.path)?;
+            if arg.files_only && matches!(arg.format, OutputFormat::Perfetto | OutputFormat::Chrome)
+            {
+                return Err(Error::InvalidArgs {
+                    arg_name: "format".to_string(),
+                    arg_value: format!("{:?}", arg.format).to_lowercase(),
+                    error: "Files are printed as json, csv or text".to_string(),
+                });
+            }
+            DumpFilter::from_args(arg)?;
+        }
+        SubCommands::Verify(arg) => {
+            ensure_path_exists(&arg.path)?;
+        }
+        SubCommands::Upgrade(arg) => {
+            ensure_path_exists(&arg.path)?;
+            ensure_sign_has_key(arg.sign, arg.mac_key_path.as_deref())?;
+        }
+        SubCommands::Trim(arg) => {
+            ensure_path_exists(&arg.path)?;
+            ensure_max_bytes(Some(arg.max_bytes))?;
+            ensure_sign_has_key(arg.sign, arg.mac_key_path.as_deref())?;
+        }
+        SubCommands::Merge(arg) => {
+            if arg.path.is_empty() {
+                return Err(Error::InvalidArgs {
+                    arg_name: "path".to_string(),
+                    arg_value: String::new(),
+                    error: "No records file to merge".to_string(),
+                });
+            }
+            for path in &arg.path {
+                ensure_path_exists(path)?;
+            }
+        }
+        SubCommands::Analyze(arg) => {
+            ensure_path_exists(&arg.path)?;
+        }
+        SubCommands::Diff(arg) => {
+            ensure_path_exists(&arg.old_path)?;
+            ensure_path_exists(&arg.new_path)?;
+            if matches!(arg.format, OutputFormat::Perfetto | OutputFormat::Chrome) {
+                return Err(Error::InvalidArgs {
+                    arg_name: "format".to_string(),
+                    arg_value: format!("{:?}", arg.format).to_lowercase(),
+                    error: "Differences are printed as json, csv or text".to_string(),
+                });
+            }
+        }
+        SubCommands::Import(arg) => {
+            ensure_path_exists(&arg.path)?;
+            ensure_sign_has_key(arg.sign, arg.mac_key_path.as_deref())?;
+            // Imported records never have a MAC, so one is only added when asked for.
+            if let (Some(key_path), false) = (&arg.mac_key_path, arg.sign) {
+                return Err(Error::InvalidArgs {
+                    arg_name: "mac_key_path".to_string(),
+                    arg_value: key_path.display().to_string(),
+                    error: "Imported records have no MAC, pass --sign to add one".to_string(),
+                });
+            }
         }
     }
     Ok(())
 }
 
+/// Returns error if `--sign` is given without a key to sign with.
+fn ensure_sign_has_key(sign: bool, mac_key_path: Option<&Path>) -> Result<(), Error> {
+    if sign && mac_key_path.is_none() {
+        return Err(Error::InvalidArgs {
+            arg_name: "sign".to_string(),
+            arg_value: sign.to_string(),
+            error: "Signing needs --mac-key-path".to_string(),
+        });
+    }
+    Ok(())
+}
+
 /// Returns error if the given path at `p` exist.
 pub(crate) fn ensure_path_doesnt_exist(p: &Path) -> Result<(), Error> {
     if p.exists() {
@@ -97,6 +278,32 @@ pub(crate) fn ensure_path_exists(p: &Path) -> Result<(), Error> {
     }
 }
 
+/// Returns error if the given directory at `p` doesn't exist.
+pub(crate) fn ensure_dir_exists(p: &Path) -> Result<(), Error> {
+    if p.is_dir() {
+        Ok(())
+    } else {
+        Err(Error::InvalidArgs {
+            arg_name: "path".to_string(),
+            arg_value: p.display().to_string(),
+            error: "Directory does not exist".to_string(),
+        })
+    }
+}
+
+/// Returns error if the byte budget `max_bytes` leaves nothing to replay.
+pub(crate) fn ensure_max_bytes(max_bytes: Option<u64>) -> Result<(), Error> {
+    if max_bytes == Some(0) {
+        Err(Error::InvalidArgs {
+            arg_name: "max_bytes".to_string(),
+            arg_value: "0".to_string(),
+            error: "Byte budget must be greater than zero".to_string(),
+        })
+    } else {
+        Ok(())
+    }
+}
+
 /// Builds `MainArgs` from command line arguments. On error prints error/help message
 /// and exits.
 pub fn args_from_env() -> MainArgs {
@@ -16,10 +16,15 @@ use std::{option::Option, path::PathBuf, result::Result::Ok, str::FromStr};
 
 use argh::FromArgs;
 use serde::Deserialize;
+use serde::Serialize;
 
 use crate::args::DEFAULT_EXIT_ON_ERROR;
+use crate::args::DEFAULT_IOPRIO_LEVEL;
 use crate::args::DEFAULT_IO_DEPTH;
 use crate::args::DEFAULT_MAX_FDS;
+use crate::args::DEFAULT_QUEUE_DEPTH;
+use crate::format::{Compression, DigestAlgorithm};
+use crate::trim::TrimPolicy;
 use crate::Error;
 
 /// prefetch-rs
@@ -40,6 +45,20 @@ pub enum SubCommands {
     Replay(ReplayArgs),
     /// Dump prefetch data in human readable format
     Dump(DumpArgs),
+    /// Checks prefetch data against the filesystem
+    Verify(VerifyArgs),
+    /// Rewrites prefetch data in the current format
+    Upgrade(UpgradeArgs),
+    /// Merges prefetch data of several boots
+    Merge(MergeArgs),
+    /// Compares two prefetch data files
+    Diff(DiffArgs),
+    /// Prints statistics of prefetch data
+    Analyze(AnalyzeArgs),
+    /// Cuts prefetch data down to a byte budget
+    Trim(TrimArgs),
+    /// Builds prefetch data from its human readable form
+    Import(ImportArgs),
 }
 
 #[cfg(target_os = "android")]
@@ -61,6 +80,18 @@ fn parse_tracing_instance(value: &str) -> Result<Option<String>, String> {
     Ok(Some(value.to_string()))
 }
 
+fn parse_cpu_mask(value: &str) -> Result<u64, String> {
+    let mask = match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => value.parse(),
+    }
+    .map_err(|e| format!("invalid cpu mask {value}: {e}"))?;
+    if mask == 0 {
+        return Err("cpu mask must have at least one cpu".to_owned());
+    }
+    Ok(mask)
+}
+
 #[derive(Eq, PartialEq, Debug, Default, FromArgs)]
 /// Records prefect data.
 #[argh(subcommand, name = "record")]
@@ -135,6 +166,37 @@ pub struct RecordArgs {
     #[argh(option)]
     pub include_mount_prefix: Vec<PathBuf>,
 
+    /// compression of the records in the records file. One of none or zstd.
+    ///
+    /// zstd interns the directories of the recorded paths and compresses the
+    /// records. Replay and dump read either form. Defaults to none.
+    #[argh(option, default = "Default::default()")]
+    pub compression: Compression,
+
+    /// digest that covers the records file on top of its CRC32. One of crc32,
+    /// sha256 or hmac_sha256. Defaults to crc32, or to hmac_sha256 if
+    /// "--mac-key-path" is specified. hmac_sha256 is the only digest allowed
+    /// with "--mac-key-path".
+    #[argh(option)]
+    pub digest: Option<DigestAlgorithm>,
+
+    /// file path of the secret that keys the MAC of the records file.
+    ///
+    /// Replay given the same secret refuses records files that were not
+    /// written with it.
+    #[argh(option)]
+    pub mac_key_path: Option<PathBuf>,
+
+    /// maximum number of bytes the records may read. Records are cut down
+    /// with "--trim-policy" when they read more.
+    #[argh(option)]
+    pub max_bytes: Option<u64>,
+
+    /// which records are kept when "--max-bytes" is exceeded. One of earliest,
+    /// densest or per_mount. Defaults to earliest.
+    #[argh(option, default = "Default::default()")]
+    pub trim_policy: TrimPolicy,
+
     #[cfg(target_os = "android")]
     /// store build_finger_print to tie the pack format
     #[argh(option, default = "default_build_finger_print_path()")]
@@ -230,7 +292,7 @@ impl Default for TracerType {
     }
 }
 
-#[derive(Eq, PartialEq, Debug, Default, FromArgs)]
+#[derive(Clone, Eq, PartialEq, Debug, Default, FromArgs)]
 /// Prefetch data from the recorded file.
 #[argh(subcommand, name = "replay")]
 pub struct ReplayArgs {
@@ -257,12 +319,230 @@ pub struct ReplayArgs {
     #[argh(option, default = "PathBuf::new()")]
     pub config_path: PathBuf,
 
+    /// file path of the secret that keys the MAC of the records file.
+    ///
+    /// When specified, records files without a MAC keyed with this secret
+    /// are refused. Records files are not authenticated otherwise.
+    #[argh(option)]
+    pub mac_key_path: Option<PathBuf>,
+
+    /// engine used to issue replay IO. One of thread_pool or io_uring.
+    ///
+    /// io_uring falls back to thread_pool if the kernel does not support
+    /// io_uring or if the process is not allowed to use it.
+    #[argh(option, default = "Default::default()")]
+    pub engine: ReplayEngine,
+
+    /// number of IO kept in flight by the io_uring engine.
+    #[argh(option, long = "queue-depth", default = "DEFAULT_QUEUE_DEPTH")]
+    pub queue_depth: u16,
+
+    /// method used to bring data into the page cache. One of pread, readahead, fadvise
+    /// or mmap.
+    ///
+    /// The io_uring engine only supports pread.
+    #[argh(option, default = "Default::default()")]
+    pub strategy: ReplayStrategy,
+
+    /// if true, replays the records once with each strategy and reports wall time and
+    /// bytes brought into the page cache for each of them.
+    ///
+    /// Data of the replayed files is dropped from the page cache before each run.
+    #[argh(option, default = "false")]
+    pub benchmark: bool,
+
+    /// if specified, a record is not replayed more than these many milliseconds
+    /// ahead of its recorded time.
+    ///
+    /// Recorded times are relative to the earliest record and replay time is
+    /// measured from the start of replay.
+    #[argh(option, long = "max-ahead-ms")]
+    pub max_ahead_ms: Option<u64>,
+
+    /// if specified, replay does not read more than these many MiB ahead of the
+    /// data that was read by the recorded time.
+    #[argh(option, long = "max-ahead-mib")]
+    pub max_ahead_mib: Option<u64>,
+
+    /// if specified, a json report of the replay is written to this path.
+    ///
+    /// The report has counts of replayed records, bytes, opened and skipped
+    /// files, per file errors, per worker wall time and page cache residency
+    /// of the records before and after replay. Measuring residency adds to
+    /// the replay time.
+    #[argh(option)]
+    pub report_path: Option<PathBuf>,
+
+    /// if true, page cache residency of each record is checked before reading it
+    /// and only the parts of the record that are not in the page cache are read.
+    #[argh(option, default = "false")]
+    pub skip_resident: bool,
+
+    /// if true, the directories of the files in the pack are read and the
+    /// files are opened in recorded order before any data is read. This warms
+    /// the dentry and inode caches.
+    #[argh(option, default = "false")]
+    pub warm_metadata: bool,
+
+    /// io priority class of the replay threads. One of realtime, best_effort
+    /// or idle. Threads keep the default io priority if not specified.
+    #[argh(option)]
+    pub ioprio_class: Option<IoPriorityClass>,
+
+    /// io priority level, from 0 (highest) to 7 (lowest), within the io
+    /// priority class. Defaults to DEFAULT_IOPRIO_LEVEL.
+    #[argh(option, default = "DEFAULT_IOPRIO_LEVEL")]
+    pub ioprio_level: u8,
+
+    /// nice value, from -20 to 19, of the replay threads.
+    #[argh(option)]
+    pub nice: Option<i32>,
+
+    /// bitmask of the cpus the replay threads can run on, in hex (0xf) or
+    /// decimal.
+    #[argh(option, from_str_fn(parse_cpu_mask))]
+    pub cpu_affinity: Option<u64>,
+
+    /// cgroup directory the replay threads are moved to.
+    ///
+    /// Thread ids are written to cgroup.threads, or to tasks on cgroup v1
+    /// hierarchies.
+    #[argh(option)]
+    pub cgroup_path: Option<PathBuf>,
+
+    /// android task profile, from /etc/task_profiles.json, the replay
+    /// threads are moved to.
+    ///
+    /// Only profiles made of JoinCgroup actions are supported.
+    #[argh(option)]
+    pub task_profile: Option<String>,
+
+    /// if specified, records are split by the physical extents of their data and,
+    /// within windows of these many milliseconds of recorded time, reads are
+    /// ordered by device and physical block.
+    ///
+    /// Records that cannot be mapped keep their order after the mapped records
+    /// of their window.
+    #[argh(option, long = "extent-sort-window-ms")]
+    pub extent_sort_window_ms: Option<u64>,
+
+    /// if specified, replay stops these many milliseconds after it starts.
+    #[argh(option, long = "deadline-ms")]
+    pub deadline_ms: Option<u64>,
+
+    /// if specified, replay stops once this system property is set to true.
+    /// Only supported on android.
+    #[argh(option)]
+    pub stop_property: Option<String>,
+
+    /// if specified, replay stops once memory pressure reaches this percentage.
+    ///
+    /// Memory pressure is the share of time some tasks were stalled on memory
+    /// over the last 10 seconds, as reported by /proc/pressure/memory.
+    #[argh(option)]
+    pub max_memory_pressure: Option<u32>,
+
     #[cfg(target_os = "android")]
     /// store build_finger_print to tie the pack format
     #[argh(option, default = "default_build_finger_print_path()")]
     pub build_fingerprint_path: PathBuf,
 }
 
+/// Engine used to issue IO during replay.
+#[derive(Deserialize, Clone, Copy, Default, Eq, PartialEq, Debug)]
+pub enum ReplayEngine {
+    /// `io_depth` threads, each issuing one blocking read at a time.
+    #[default]
+    ThreadPool,
+    /// A single thread that keeps up to `queue_depth` reads in flight through io_uring.
+    IoUring,
+}
+
+impl FromStr for ReplayEngine {
+    type Err = Error;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(match s.to_lowercase().as_str() {
+            "thread_pool" => Self::ThreadPool,
+            "io_uring" => Self::IoUring,
+            _ => {
+                return Err(Error::InvalidArgs {
+                    arg_name: "engine".to_owned(),
+                    arg_value: s.to_owned(),
+                    error: "unknown value".to_owned(),
+                })
+            }
+        })
+    }
+}
+
+/// IO scheduling class of the replay threads.
+#[derive(Deserialize, Clone, Copy, Eq, PartialEq, Debug)]
+pub enum IoPriorityClass {
+    /// Served before any other class.
+    Realtime,
+    /// Served along with other best effort IO as per the priority level.
+    BestEffort,
+    /// Served only when no other IO is pending.
+    Idle,
+}
+
+impl FromStr for IoPriorityClass {
+    type Err = Error;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(match s.to_lowercase().as_str() {
+            "realtime" => Self::Realtime,
+            "best_effort" => Self::BestEffort,
+            "idle" => Self::Idle,
+            _ => {
+                return Err(Error::InvalidArgs {
+                    arg_name: "ioprio_class".to_owned(),
+                    arg_value: s.to_owned(),
+                    error: "unknown value".to_owned(),
+                })
+            }
+        })
+    }
+}
+
+/// Method used to bring the data of a record into the page cache.
+#[derive(Deserialize, Serialize, Clone, Copy, Default, Eq, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ReplayStrategy {
+    /// Copies data into a bounce buffer with pread64.
+    #[default]
+    Pread,
+    /// Uses the readahead(2) syscall.
+    Readahead,
+    /// Uses posix_fadvise(POSIX_FADV_WILLNEED).
+    Fadvise,
+    /// Maps the range with MAP_POPULATE.
+    Mmap,
+}
+
+impl ReplayStrategy {
+    /// All the strategies, in the order they are benchmarked.
+    pub const ALL: [ReplayStrategy; 4] = [Self::Pread, Self::Readahead, Self::Fadvise, Self::Mmap];
+}
+
+impl FromStr for ReplayStrategy {
+    type Err = Error;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(match s.to_lowercase().as_str() {
+            "pread" => Self::Pread,
+            "readahead" => Self::Readahead,
+            "fadvise" => Self::Fadvise,
+            "mmap" => Self::Mmap,
+            _ => {
+                return Err(Error::InvalidArgs {
+                    arg_name: "strategy".to_owned(),
+                    arg_value: s.to_owned(),
+                    error: "unknown value".to_owned(),
+                })
+            }
+        })
+    }
+}
+
 /// dump records file in given format
 #[derive(Eq, PartialEq, Debug, Default, FromArgs)]
 #[argh(subcommand, name = "dump")]
@@ -270,16 +550,267 @@ pub struct DumpArgs {
     /// file path from where the records will be read
     #[argh(option)]
     pub path: PathBuf,
-    /// output format. One of json or csv.
-    /// Note: In csv format, few fields are excluded from the output.
+    /// output format. One of json, csv, text, perfetto or chrome.
+    /// Note: In csv and text formats, few fields are excluded from the output.
+    /// Perfetto and chrome print the records as a trace timeline, the former as
+    /// a Perfetto protobuf trace and the latter as a Chrome JSON trace.
+    #[argh(option)]
+    pub format: OutputFormat,
+
+    /// only dump files with a path that matches this regex
+    #[argh(option)]
+    pub path_regex: Option<String>,
+
+    /// only dump files on the filesystem with this device number
+    #[argh(option)]
+    pub device: Option<u64>,
+
+    /// only dump files on a mount whose mount point starts with this prefix.
+    /// Files without a file handle are on "device <major>:<minor>".
+    #[argh(option)]
+    pub mount_prefix: Option<String>,
+
+    /// only dump records read at or after this many milliseconds
+    #[argh(option)]
+    pub start_ms: Option<u64>,
+
+    /// only dump records read before this many milliseconds
+    #[argh(option)]
+    pub end_ms: Option<u64>,
+
+    /// only dump records of at least this many bytes
+    #[argh(option)]
+    pub min_length: Option<u64>,
+
+    /// only dump the file with this id. Can be repeated.
+    #[argh(option)]
+    pub file_id: Vec<u64>,
+
+    /// if true, the files are dumped, with their total bytes, instead of the
+    /// records. Only json, csv and text formats are supported.
+    #[argh(option, default = "false")]
+    pub files_only: bool,
+}
+
+/// check that the files of a records file are unchanged on the filesystem
+///
+/// Each path of each file is stat'ed to compare inode number, device number
+/// and file size with the recorded ones, and records are checked to be within
+/// the file. File data is not read. A json summary of the mismatches is
+/// printed, and the command fails if any file changed.
+#[derive(Eq, PartialEq, Debug, Default, FromArgs)]
+#[argh(subcommand, name = "verify")]
+pub struct VerifyArgs {
+    /// file path from where the records will be read
+    #[argh(option, default = "default_path()")]
+    pub path: PathBuf,
+}
+
+/// rewrite a records file of an older format version in the current version
+///
+/// Fields added to the format since the records file was written keep their
+/// defaults. The checksum is recomputed.
+#[derive(Eq, PartialEq, Debug, Default, FromArgs)]
+#[argh(subcommand, name = "upgrade")]
+pub struct UpgradeArgs {
+    /// file path from where the records will be read
+    #[argh(option, default = "default_path()")]
+    pub path: PathBuf,
+
+    /// file path where the upgraded records will be written. The records file
+    /// is rewritten in place if not specified.
+    #[argh(option)]
+    pub output_path: Option<PathBuf>,
+
+    /// compression of the records in the upgraded records file. One of none or
+    /// zstd. The compression of the records file is kept if not specified.
+    #[argh(option)]
+    pub compression: Option<Compression>,
+
+    /// file path of the secret that keys the MAC of the records file. Needed
+    /// for records files with a MAC, whose MAC is checked before upgrading.
+    #[argh(option)]
+    pub mac_key_path: Option<PathBuf>,
+
+    /// if true, "--mac-key-path" adds a MAC to a records file that has none.
+    /// Without it, records files without a MAC are refused so that a MAC is
+    /// never given to records of unknown origin by mistake.
+    #[argh(option, default = "false")]
+    pub sign: bool,
+}
+
+/// merge the records files of several boots into one
+///
+/// Files are unified by device and inode number or by path. Only the parts of
+/// files read in at least "--min-count" of the records files are kept, each
+/// with the median of the times it was read at.
+#[derive(Eq, PartialEq, Debug, Default, FromArgs)]
+#[argh(subcommand, name = "merge")]
+pub struct MergeArgs {
+    /// file path from where records will be read. Repeat for each records file.
+    #[argh(option)]
+    pub path: Vec<PathBuf>,
+
+    /// file path where the merged records will be written to
+    #[argh(option)]
+    pub output_path: PathBuf,
+
+    /// number of records files a part of a file must be read in to be kept.
+    /// Defaults to a majority of the records files.
+    #[argh(option)]
+    pub min_count: Option<usize>,
+
+    /// compression of the records in the merged records file. One of none or
+    /// zstd. Defaults to none.
+    #[argh(option, default = "Default::default()")]
+    pub compression: Compression,
+
+    /// file path of the secret that keys the MAC of records files. When
+    /// specified, the records files read must carry a MAC keyed with it and
+    /// the merged records file is written with one.
+    #[argh(option)]
+    pub mac_key_path: Option<PathBuf>,
+}
+
+impl MergeArgs {
+    /// Returns the minimum count, a majority of the records files if not specified.
+    pub fn get_min_count(&self) -> usize {
+        self.min_count.unwrap_or(self.path.len() / 2 + 1)
+    }
+}
+
+/// compare two records files, typically recorded on two builds
+///
+/// Files are matched by path. Files read on one side only, bytes read per
+/// file on either side and shifts in the time each file is first read are
+/// printed. The text format is meant to be read by people, with a line per
+/// file giving the change in bytes read and the shift in its first read.
+#[derive(Eq, PartialEq, Debug, Default, FromArgs)]
+#[argh(subcommand, name = "diff")]
+pub struct DiffArgs {
+    /// file path from where the old records will be read
     #[argh(option)]
+    pub old_path: PathBuf,
+
+    /// file path from where the new records will be read
+    #[argh(option)]
+    pub new_path: PathBuf,
+
+    /// output format. One of json, csv or text. Defaults to json.
+    /// Note: In csv format, only the differences per file are printed.
+    #[argh(option, default = "OutputFormat::Json")]
     pub format: OutputFormat,
 }
 
+/// print statistics of a records file
+///
+/// Bytes per mount and per file, bytes read over time, how scattered the
+/// reads of files are and how much data is read more than once.
+#[derive(Eq, PartialEq, Debug, Default, FromArgs)]
+#[argh(subcommand, name = "analyze")]
+pub struct AnalyzeArgs {
+    /// file path from where the records will be read
+    #[argh(option, default = "default_path()")]
+    pub path: PathBuf,
+
+    /// output format. One of json or text. Defaults to text.
+    #[argh(option, default = "Default::default()")]
+    pub format: AnalyzeFormat,
+
+    /// number of the most read files to print. Defaults to 20.
+    #[argh(option, default = "20")]
+    pub top: usize,
+
+    /// width in milliseconds of the intervals of time bytes read are counted
+    /// over. Defaults to 100.
+    #[argh(option, default = "100")]
+    pub bucket_ms: u64,
+}
+
+/// build a records file from the json printed by dump or from csv
+///
+/// The csv has the columns printed by dump plus the device and inode number
+/// of each file, named device and inode. The records file is checked and
+/// written with a new checksum.
+#[derive(Eq, PartialEq, Debug, Default, FromArgs)]
+#[argh(subcommand, name = "import")]
+pub struct ImportArgs {
+    /// file path from where the json or csv will be read
+    #[argh(option)]
+    pub path: PathBuf,
+
+    /// input format. One of json or csv.
+    #[argh(option, default = "Default::default()")]
+    pub format: ImportFormat,
+
+    /// file path where the records will be written to
+    #[argh(option)]
+    pub output_path: PathBuf,
+
+    /// compression of the records in the records file. One of none or zstd.
+    /// Defaults to none.
+    #[argh(option, default = "Default::default()")]
+    pub compression: Compression,
+
+    /// file path of the secret that keys the MAC of the records file. When
+    /// specified, the records file is written with a MAC keyed with it.
+    #[argh(option)]
+    pub mac_key_path: Option<PathBuf>,
+
+    /// if true, "--mac-key-path" adds a MAC to the imported records, which
+    /// never have one. Without it, "--mac-key-path" is refused so that a MAC
+    /// is never given to records of unknown origin by mistake.
+    #[argh(option, default = "false")]
+    pub sign: bool,
+}
+
+/// cut a records file down to a byte budget
+///
+/// Records the policy picks are kept until they read "--max-bytes". Files
+/// no record is left for are removed.
+#[derive(Eq, PartialEq, Debug, Default, FromArgs)]
+#[argh(subcommand, name = "trim")]
+pub struct TrimArgs {
+    /// file path from where the records will be read
+    #[argh(option, default = "default_path()")]
+    pub path: PathBuf,
+
+    /// file path where the trimmed records will be written. The records file
+    /// is rewritten in place if not specified.
+    #[argh(option)]
+    pub output_path: Option<PathBuf>,
+
+    /// maximum number of bytes the records may read
+    #[argh(option)]
+    pub max_bytes: u64,
+
+    /// which records are kept. One of earliest, densest or per_mount.
+    ///
+    /// earliest keeps the records read first. densest keeps whole the files
+    /// with the most of their size read first. per_mount shares the budget
+    /// evenly by the mounts. Defaults to earliest.
+    #[argh(option, default = "Default::default()")]
+    pub policy: TrimPolicy,
+
+    /// file path of the secret that keys the MAC of the records file. Needed
+    /// for records files with a MAC, whose MAC is checked before trimming.
+    #[argh(option)]
+    pub mac_key_path: Option<PathBuf>,
+
+    /// if true, "--mac-key-path" adds a MAC to a records file that has none.
+    /// Without it, records files without a MAC are refused so that a MAC is
+    /// never given to records of unknown origin by mistake.
+    #[argh(option, default = "false")]
+    pub sign: bool,
+}
+
 #[derive(Deserialize, Eq, PartialEq, Debug)]
 pub enum OutputFormat {
     Json,
     Csv,
+    Text,
+    Perfetto,
+    Chrome,
 }
 
 impl FromStr for OutputFormat {
@@ -288,6 +819,9 @@ impl FromStr for OutputFormat {
         Ok(match s.to_lowercase().as_str() {
             "csv" => Self::Csv,
             "json" => Self::Json,
+            "text" => Self::Text,
+            "perfetto" => Self::Perfetto,
+            "chrome" => Self::Chrome,
             _ => {
                 return Err(Error::InvalidArgs {
                     arg_name: "format".to_owned(),
@@ -299,6 +833,109 @@ impl FromStr for OutputFormat {
     }
 }
 
+/// Output formats of `analyze`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum AnalyzeFormat {
+    Json,
+    #[default]
+    Text,
+}
+
+impl FromStr for AnalyzeFormat {
+    type Err = Error;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(match s.to_lowercase().as_str() {
+            "json" => Self::Json,
+            "text" => Self::Text,
+            _ => {
+                return Err(Error::InvalidArgs {
+                    arg_name: "format".to_owned(),
+                    arg_value: s.to_owned(),
+                    error: "unknown value".to_owned(),
+                })
+            }
+        })
+    }
+}
+
+/// Input formats of `import`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ImportFormat {
+    #[default]
+    Json,
+    Csv,
+}
+
+impl FromStr for ImportFormat {
+    type Err = Error;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(match s.to_lowercase().as_str() {
+            "json" => Self::Json,
+            "csv" => Self::Csv,
+            _ => {
+                return Err(Error::InvalidArgs {
+                    arg_name: "format".to_owned(),
+                    arg_value: s.to_owned(),
+                    error: "unknown value".to_owned(),
+                })
+            }
+        })
+    }
+}
+
+impl FromStr for Compression {
+    type Err = Error;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(match s.to_lowercase().as_str() {
+            "none" => Self::None,
+            "zstd" => Self::Zstd,
+            _ => {
+                return Err(Error::InvalidArgs {
+                    arg_name: "compression".to_owned(),
+                    arg_value: s.to_owned(),
+                    error: "unknown value".to_owned(),
+                })
+            }
+        })
+    }
+}
+
+impl FromStr for DigestAlgorithm {
+    type Err = Error;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(match s.to_lowercase().as_str() {
+            "crc32" => Self::Crc32,
+            "sha256" => Self::Sha256,
+            "hmac_sha256" => Self::HmacSha256,
+            _ => {
+                return Err(Error::InvalidArgs {
+                    arg_name: "digest".to_owned(),
+                    arg_value: s.to_owned(),
+                    error: "unknown value".to_owned(),
+                })
+            }
+        })
+    }
+}
+
+impl FromStr for TrimPolicy {
+    type Err = Error;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(match s.to_lowercase().as_str() {
+            "earliest" => Self::Earliest,
+            "densest" => Self::Densest,
+            "per_mount" => Self::PerMount,
+            _ => {
+                return Err(Error::InvalidArgs {
+                    arg_name: "policy".to_owned(),
+                    arg_value: s.to_owned(),
+                    error: "unknown value".to_owned(),
+                })
+            }
+        })
+    }
+}
+
 impl Default for OutputFormat {
     fn default() -> Self {
         Self::Json
@@ -0,0 +1,409 @@
+// Copyright (C) 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Compares two records files, typically recorded on two builds.
+//!
+//! Files are matched by path as inode numbers change from build to build. The comparison tells
+//! which files are read only on one side, how many bytes of each file are read on either side
+//! and how much later or earlier each file is first read.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::Write;
+
+use serde::Serialize;
+
+use crate::format::{union_ranges, FileId, RecordsFile};
+use crate::Error;
+
+/// How a file differs between the two records files.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Change {
+    /// The file is only in the new records file.
+    Added,
+    /// The file is only in the old records file.
+    Removed,
+    /// The file is in both records files.
+    Common,
+}
+
+/// Difference of one file between the two records files.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct FileDiff {
+    /// Whether the file is in either or both records files.
+    pub change: Change,
+
+    /// First path of the file, in the new records file if it is there.
+    pub path: String,
+
+    /// Bytes of the file read in the old records file.
+    pub old_bytes: u64,
+
+    /// Bytes of the file read in the new records file.
+    pub new_bytes: u64,
+
+    /// Bytes of the file read in the new records file only.
+    pub bytes_added: u64,
+
+    /// Bytes of the file read in the old records file only.
+    pub bytes_removed: u64,
+
+    /// Time the file is first read at in the old records file, in nanoseconds.
+    pub old_first_read_ns: Option<u64>,
+
+    /// Time the file is first read at in the new records file, in nanoseconds.
+    pub new_first_read_ns: Option<u64>,
+
+    /// How much later the file is first read in the new records file, in nanoseconds.
+    pub first_read_shift_ns: Option<i64>,
+}
+
+/// Outcome of comparing two records files.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct DiffReport {
+    /// Number of files only in the new records file.
+    pub files_added: u64,
+
+    /// Number of files only in the old records file.
+    pub files_removed: u64,
+
+    /// Number of files in both records files.
+    pub files_common: u64,
+
+    /// Bytes read in the old records file.
+    pub old_bytes: u64,
+
+    /// Bytes read in the new records file.
+    pub new_bytes: u64,
+
+    /// Bytes read in the new records file only.
+    pub bytes_added: u64,
+
+    /// Bytes read in the old records file only.
+    pub bytes_removed: u64,
+
+    /// Differences per file, added files first, then removed and common ones, each by path.
+    pub files: Vec<FileDiff>,
+}
+
+impl DiffReport {
+    /// Writes the differences per file as csv.
+    pub fn write_csv(&self, writer: &mut dyn Write) -> Result<(), Error> {
+        let mut wtr = csv::Writer::from_writer(writer);
+        for file in &self.files {
+            wtr.serialize(file).map_err(|e| Error::Serialize { error: e.to_string() })?;
+        }
+        wtr.flush()?;
+        Ok(())
+    }
+}
+
+impl fmt::Display for DiffReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "files: {} added, {} removed, {} common",
+            self.files_added, self.files_removed, self.files_common
+        )?;
+        writeln!(
+            f,
+            "bytes: {} old, {} new, {} added, {} removed",
+            self.old_bytes, self.new_bytes, self.bytes_added, self.bytes_removed
+        )?;
+        for file in &self.files {
+            let shift = match file.first_read_shift_ns {
+                Some(shift) => format!("{:+.3}ms", shift as f64 / 1_000_000.0),
+                None => "-".to_owned(),
+            };
+            writeln!(
+                f,
+                "{:<8} {:>+12} {:>12} {}",
+                format!("{:?}", file.change).to_lowercase(),
+                file.new_bytes as i64 - file.old_bytes as i64,
+                shift,
+                file.path
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// Byte ranges read and first read time of a file.
+#[derive(Default)]
+struct FileReads {
+    // Sorted and disjoint [start, end) ranges.
+    ranges: Vec<(u64, u64)>,
+    // None if the file has no records.
+    first_read_ns: Option<u64>,
+}
+
+impl FileReads {
+    fn bytes(&self) -> u64 {
+        self.ranges.iter().map(|(start, end)| end - start).sum()
+    }
+
+    // Returns the number of bytes read by both `self` and `other`.
+    fn common_bytes(&self, other: &Self) -> u64 {
+        let (mut i, mut j, mut common) = (0, 0, 0);
+        while i < self.ranges.len() && j < other.ranges.len() {
+            let (a, b) = (self.ranges[i], other.ranges[j]);
+            common += a.1.min(b.1).saturating_sub(a.0.max(b.0));
+            if a.1 < b.1 {
+                i += 1;
+            } else {
+                j += 1;
+            }
+        }
+        common
+    }
+}
+
+fn file_reads(rf: &RecordsFile) -> HashMap<&FileId, FileReads> {
+    let mut reads: HashMap<&FileId, FileReads> = HashMap::new();
+    for record in &rf.inner.records {
+        let file = reads.entry(&record.file_id).or_default();
+        file.ranges.push((record.offset, record.offset + record.length));
+        file.first_read_ns =
+            Some(file.first_read_ns.map_or(record.timestamp, |ns| ns.min(record.timestamp)));
+    }
+    for file in reads.values_mut() {
+        file.ranges = union_ranges(std::mem::take(&mut file.ranges));
+    }
+    reads
+}
+
+/// Compares `old` with `new`. Files are the same file if they share a path. A new file is the same
+/// file as at most one old file, the first one by file id.
+pub fn diff_records_files(old: &RecordsFile, new: &RecordsFile) -> DiffReport {
+    let old_reads = file_reads(old);
+    let new_reads = file_reads(new);
+    let no_reads = FileReads::default();
+
+    let mut new_ids_by_path = HashMap::new();
+    for (id, info) in new.inner.inode_map.iter() {
+        for path in &info.paths {
+            new_ids_by_path.entry(path.as_str()).or_insert(id);
+        }
+    }
+
+    let mut files = vec![];
+    let mut old_files: Vec<_> = old.inner.inode_map.iter().collect();
+    old_files.sort_by_key(|(id, _)| *id);
+    let mut matched_new_ids = HashSet::new();
+    for (old_id, old_info) in old_files {
+        let old_file = old_reads.get(old_id).unwrap_or(&no_reads);
+        let new_id = old_info.paths.iter().find_map(|path| {
+            new_ids_by_path.get(path.as_str()).filter(|id| !matched_new_ids.contains(*id))
+        });
+        let diff = match new_id {
+            Some(new_id) => {
+                matched_new_ids.insert(*new_id);
+                let new_file = new_reads.get(new_id).unwrap_or(&no_reads);
+                let common = old_file.common_bytes(new_file);
+                FileDiff {
+                    change: Change::Common,
+                    path: new.inner.inode_map[*new_id].paths[0].clone(),
+                    old_bytes: old_file.bytes(),
+                    new_bytes: new_file.bytes(),
+                    bytes_added: new_file.bytes() - common,
+                    bytes_removed: old_file.bytes() - common,
+                    old_first_read_ns: old_file.first_read_ns,
+                    new_first_read_ns: new_file.first_read_ns,
+                    first_read_shift_ns: old_file
+                        .first_read_ns
+                        .zip(new_file.first_read_ns)
+                        .map(|(old_ns, new_ns)| new_ns as i64 - old_ns as i64),
+                }
+            }
+            None => FileDiff {
+                change: Change::Removed,
+                path: old_info.paths[0].clone(),
+                old_bytes: old_file.bytes(),
+                new_bytes: 0,
+                bytes_added: 0,
+                bytes_removed: old_file.bytes(),
+                old_first_read_ns: old_file.first_read_ns,
+                new_first_read_ns: None,
+                first_read_shift_ns: None,
+            },
+        };
+        files.push(diff);
+    }
+    for (new_id, new_info) in new.inner.inode_map.iter() {
+        if matched_new_ids.contains(new_id) {
+            continue;
+        }
+        let new_file = new_reads.get(new_id).unwrap_or(&no_reads);
+        files.push(FileDiff {
+            change: Change::Added,
+            path: new_info.paths[0].clone(),
+            old_bytes: 0,
+            new_bytes: new_file.bytes(),
+            bytes_added: new_file.bytes(),
+            bytes_removed: 0,
+            old_first_read_ns: None,
+            new_first_read_ns: new_file.first_read_ns,
+            first_read_shift_ns: None,
+        });
+    }
+    files.sort_by(|a, b| (a.change, &a.path).cmp(&(b.change, &b.path)));
+
+    let mut report = DiffReport::default();
+    for file in &files {
+        match file.change {
+            Change::Added => report.files_added += 1,
+            Change::Removed => report.files_removed += 1,
+            Change::Common => report.files_common += 1,
+        }
+        report.old_bytes += file.old_bytes;
+        report.new_bytes += file.new_bytes;
+        report.bytes_added += file.bytes_added;
+        report.bytes_removed += file.bytes_removed;
+    }
+    report.files = files;
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{InodeInfo, Record};
+
+    // Offset, length and timestamp of a record.
+    type TestRecord = (u64, u64, u64);
+
+    fn records_file(files: &[(u64, &str, &[TestRecord])]) -> RecordsFile {
+        let mut rf = RecordsFile::default();
+        for (id, path, records) in files {
+            rf.insert_or_update_inode_info(
+                FileId(*id),
+                InodeInfo::new(*id, 1 << 20, vec![path.to_string()], 1),
+            );
+            for (offset, length, timestamp) in records.iter() {
+                rf.insert_record(Record {
+                    file_id: FileId(*id),
+                    offset: *offset,
+                    length: *length,
+                    timestamp: *timestamp,
+                });
+            }
+        }
+        rf
+    }
+
+    #[test]
+    fn test_diff_records_files() {
+        let old = records_file(&[
+            (0, "/system/lib/libc.so", &[(0, 8192, 100), (16384, 4096, 300)]),
+            (1, "/system/bin/gone", &[(0, 4096, 200)]),
+        ]);
+        let new = records_file(&[
+            (5, "/system/lib/libc.so", &[(4096, 8192, 150), (4096, 4096, 400)]),
+            (6, "/system/bin/new", &[(0, 12288, 50)]),
+        ]);
+
+        let report = diff_records_files(&old, &new);
+        assert_eq!((report.files_added, report.files_removed, report.files_common), (1, 1, 1));
+        assert_eq!(report.old_bytes, 16384);
+        assert_eq!(report.new_bytes, 20480);
+        assert_eq!(
+            report.files[2],
+            FileDiff {
+                change: Change::Common,
+                path: "/system/lib/libc.so".to_owned(),
+                old_bytes: 12288,
+                new_bytes: 8192,
+                bytes_added: 4096,
+                bytes_removed: 8192,
+                old_first_read_ns: Some(100),
+                new_first_read_ns: Some(150),
+                first_read_shift_ns: Some(50),
+            }
+        );
+        assert_eq!(report.files[0].change, Change::Added);
+        assert_eq!(report.files[0].path, "/system/bin/new");
+        assert_eq!(report.files[1].change, Change::Removed);
+        assert_eq!(report.files[1].bytes_removed, 4096);
+        assert_eq!(report.bytes_added, 4096 + 12288);
+        assert_eq!(report.bytes_removed, 8192 + 4096);
+
+        let mut csv = vec![];
+        report.write_csv(&mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        assert_eq!(csv.lines().count(), 4, "{}", csv);
+        assert!(csv.contains("removed,/system/bin/gone,4096,0,0,4096,200,,"), "{}", csv);
+    }
+
+    #[test]
+    fn test_diff_shared_path_and_no_records() {
+        let mut old = records_file(&[
+            (0, "/system/lib/libc.so", &[(0, 4096, 100)]),
+            (1, "/system/lib/libc.so.old", &[(0, 4096, 200)]),
+            (2, "/system/bin/idle", &[]),
+        ]);
+        old.inner.inode_map.get_mut(&FileId(1)).unwrap().paths.push("/system/lib/libc.so".into());
+        let new = records_file(&[
+            (5, "/system/lib/libc.so", &[(0, 8192, 150)]),
+            (6, "/system/bin/idle", &[(0, 4096, 300)]),
+        ]);
+
+        let report = diff_records_files(&old, &new);
+        assert_eq!((report.files_added, report.files_removed, report.files_common), (0, 1, 2));
+        assert_eq!(report.new_bytes, 8192 + 4096);
+        assert_eq!(report.files[0].path, "/system/lib/libc.so.old");
+        assert_eq!(report.files[0].change, Change::Removed);
+        assert_eq!(
+            report.files[1],
+            FileDiff {
+                change: Change::Common,
+                path: "/system/bin/idle".to_owned(),
+                old_bytes: 0,
+                new_bytes: 4096,
+                bytes_added: 4096,
+                bytes_removed: 0,
+                old_first_read_ns: None,
+                new_first_read_ns: Some(300),
+                first_read_shift_ns: None,
+            }
+        );
+        assert_eq!(report.files[2].path, "/system/lib/libc.so");
+        assert_eq!(report.files[2].new_bytes, 8192);
+        assert_eq!(report.files[2].first_read_shift_ns, Some(50));
+    }
+
+    #[test]
+    fn test_diff_text() {
+        let old = records_file(&[
+            (0, "/system/lib/libc.so", &[(0, 8192, 1_000_000)]),
+            (1, "/system/bin/gone", &[(0, 4096, 200)]),
+        ]);
+        let new = records_file(&[
+            (5, "/system/lib/libc.so", &[(0, 4096, 3_500_000)]),
+            (6, "/system/bin/new", &[(0, 12288, 50)]),
+        ]);
+
+        let text = diff_records_files(&old, &new).to_string();
+        assert_eq!(
+            text.lines().collect::<Vec<_>>(),
+            vec![
+                "files: 1 added, 1 removed, 1 common",
+                "bytes: 12288 old, 16384 new, 12288 added, 8192 removed",
+                "added          +12288            - /system/bin/new",
+                "removed         -4096            - /system/bin/gone",
+                "common          -4096     +2.500ms /system/lib/libc.so",
+            ]
+        );
+    }
+}
@@ -125,6 +125,17 @@ pub enum Error {
         path: String,
     },
 
+    /// Indicates that the file is skipped for prefetching
+    /// because it changed since it was recorded.
+    ///
+    #[error("Skipped prefetching stale file from path: {path}: {reason}")]
+    StaleFile {
+        /// Path to file for which prefetching is skipped.
+        path: String,
+        /// What changed.
+        reason: String,
+    },
+
     /// Represents spurious InodeInfo or missing Record.
     ///
     #[error(
@@ -158,6 +169,23 @@ pub enum Error {
         error: String,
     },
 
+    /// Represents a records file that is not authentic.
+    #[error("Integrity check failed: {error}")]
+    Integrity {
+        /// Detailed error message.
+        error: String,
+    },
+
+    /// Represents files that changed since their records were made.
+    #[error("{files_mismatched} of {files_checked} files changed since they were recorded")]
+    FilesChanged {
+        /// Number of files checked.
+        files_checked: u64,
+
+        /// Number of files that changed.
+        files_mismatched: u64,
+    },
+
     /// Represents a failure from thread pool.
     #[error("Thread pool error: {error}")]
     ThreadPool {
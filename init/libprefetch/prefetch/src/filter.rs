@@ -0,0 +1,357 @@
+// Copyright (C) 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Selects the part of a records file that `dump` prints.
+//!
+//! The filters narrow the records file itself, before it is printed, so that every output
+//! format shows the same records.
+
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+
+use regex::Regex;
+use serde::Serialize;
+
+use crate::args::DumpArgs;
+use crate::format::{DeviceNumber, FileId, InodeInfo, InodeNumber, PathString, RecordsFile};
+use crate::Error;
+
+/// Validated form of the filters of `DumpArgs`. Records pass if they pass every filter that is
+/// set.
+#[derive(Debug, Default)]
+pub struct DumpFilter {
+    path_regex: Option<Regex>,
+    device_number: Option<DeviceNumber>,
+    mount_prefix: Option<String>,
+    start_ns: Option<u64>,
+    end_ns: Option<u64>,
+    min_length: Option<u64>,
+    file_ids: Vec<FileId>,
+}
+
+impl DumpFilter {
+    /// Builds the filter of `args`.
+    pub fn from_args(args: &DumpArgs) -> Result<Self, Error> {
+        let path_regex = args
+            .path_regex
+            .as_ref()
+            .map(|pattern| {
+                Regex::new(pattern).map_err(|e| Error::InvalidArgs {
+                    arg_name: "path_regex".to_owned(),
+                    arg_value: pattern.to_owned(),
+                    error: e.to_string(),
+                })
+            })
+            .transpose()?;
+        if let (Some(start), Some(end)) = (args.start_ms, args.end_ms) {
+            if start >= end {
+                return Err(Error::InvalidArgs {
+                    arg_name: "end_ms".to_owned(),
+                    arg_value: end.to_string(),
+                    error: format!("must be greater than start_ms {start}"),
+                });
+            }
+        }
+        let to_ns = |arg_name: &str, ms: Option<u64>| {
+            ms.map(|ms| {
+                ms.checked_mul(1_000_000).ok_or_else(|| Error::InvalidArgs {
+                    arg_name: arg_name.to_owned(),
+                    arg_value: ms.to_string(),
+                    error: "too large to be converted to nanoseconds".to_owned(),
+                })
+            })
+            .transpose()
+        };
+        Ok(Self {
+            path_regex,
+            device_number: args.device,
+            mount_prefix: args.mount_prefix.clone(),
+            start_ns: to_ns("start_ms", args.start_ms)?,
+            end_ns: to_ns("end_ms", args.end_ms)?,
+            min_length: args.min_length,
+            file_ids: args.file_id.iter().map(|id| FileId(*id)).collect(),
+        })
+    }
+
+    fn matches_file(&self, rf: &RecordsFile, id: &FileId, info: &InodeInfo) -> bool {
+        self.path_regex
+            .as_ref()
+            .is_none_or(|regex| info.paths.iter().any(|path| regex.is_match(path)))
+            && self.device_number.is_none_or(|device| device == info.device_number)
+            && self
+                .mount_prefix
+                .as_ref()
+                .is_none_or(|prefix| rf.mount_name(info).starts_with(prefix))
+            && (self.file_ids.is_empty() || self.file_ids.contains(id))
+    }
+
+    fn matches_timestamp_and_length(&self, timestamp: u64, length: u64) -> bool {
+        self.start_ns.is_none_or(|start| timestamp >= start)
+            && self.end_ns.is_none_or(|end| timestamp < end)
+            && self.min_length.is_none_or(|min| length >= min)
+    }
+
+    /// Returns `rf` with only the records, and the files and directories they refer to, that
+    /// pass the filter.
+    pub fn apply(&self, rf: &RecordsFile) -> RecordsFile {
+        let mut filtered = rf.clone();
+        let inner = &mut filtered.inner;
+        let files: HashSet<&FileId> = rf
+            .inner
+            .inode_map
+            .iter()
+            .filter(|(id, info)| self.matches_file(rf, id, info))
+            .map(|(id, _)| id)
+            .collect();
+        inner.records.retain(|record| {
+            files.contains(&record.file_id)
+                && self.matches_timestamp_and_length(record.timestamp, record.length)
+        });
+        let directories: HashSet<&FileId> = rf
+            .inner
+            .directories
+            .iter()
+            .filter(|(id, info)| self.matches_file(rf, id, info))
+            .map(|(id, _)| id)
+            .collect();
+        inner.directory_records.retain(|record| {
+            directories.contains(&record.file_id)
+                && self.matches_timestamp_and_length(record.timestamp, record.length)
+        });
+        filtered.remove_orphans();
+        filtered
+    }
+}
+
+/// A file of a records file and how much of it is read.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct FileSummary {
+    /// Id of the file in the records file.
+    pub file_id: u64,
+
+    /// Paths of the file.
+    pub paths: Vec<PathString>,
+
+    /// Device number of the filesystem the file is on.
+    pub device_number: DeviceNumber,
+
+    /// Inode number of the file.
+    pub inode_number: InodeNumber,
+
+    /// Size of the file.
+    pub file_size: u64,
+
+    /// Number of records of the file.
+    pub records: u64,
+
+    /// Sum of the lengths of the records of the file.
+    pub bytes: u64,
+}
+
+/// Returns the files of `rf` in the order they are first read.
+pub fn file_summaries(rf: &RecordsFile) -> Vec<FileSummary> {
+    let mut summaries: Vec<FileSummary> = vec![];
+    let mut indexes: HashMap<&FileId, usize> = HashMap::new();
+    for record in &rf.inner.records {
+        let info = match rf.inner.inode_map.get(&record.file_id) {
+            Some(info) => info,
+            None => continue,
+        };
+        let index = *indexes.entry(&record.file_id).or_insert_with(|| {
+            summaries.push(FileSummary {
+                file_id: record.file_id.0,
+                paths: info.paths.clone(),
+                device_number: info.device_number,
+                inode_number: info.inode_number,
+                file_size: info.file_size,
+                records: 0,
+                bytes: 0,
+            });
+            summaries.len() - 1
+        });
+        summaries[index].records += 1;
+        summaries[index].bytes += record.length;
+    }
+    summaries
+}
+
+/// Writes `summaries` as csv, with the first of the sorted paths of each file.
+pub fn write_file_summaries_csv(
+    summaries: &[FileSummary],
+    writer: &mut dyn Write,
+) -> Result<(), Error> {
+    #[derive(Serialize)]
+    struct TempSummary<'a> {
+        file_id: u64,
+        file: &'a str,
+        device_number: DeviceNumber,
+        inode_number: InodeNumber,
+        file_size: u64,
+        records: u64,
+        bytes: u64,
+    }
+
+    let mut wtr = csv::Writer::from_writer(writer);
+    for summary in summaries {
+        let file = summary.paths.iter().min().map_or("", |path| path.as_str());
+        wtr.serialize(TempSummary {
+            file_id: summary.file_id,
+            file,
+            device_number: summary.device_number,
+            inode_number: summary.inode_number,
+            file_size: summary.file_size,
+            records: summary.records,
+            bytes: summary.bytes,
+        })
+        .map_err(|e| Error::Serialize { error: e.to_string() })?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+/// Writes `summaries` as aligned columns of text, with the first of the sorted paths of each
+/// file.
+pub fn write_file_summaries_text(
+    summaries: &[FileSummary],
+    writer: &mut dyn Write,
+) -> Result<(), Error> {
+    writeln!(writer, "{:>8} {:>12} {:>8} {:>12} file", "file_id", "bytes", "records", "file_size")?;
+    for summary in summaries {
+        let file = summary.paths.iter().min().map_or("", |path| path.as_str());
+        writeln!(
+            writer,
+            "{:>8} {:>12} {:>8} {:>12} {}",
+            summary.file_id, summary.bytes, summary.records, summary.file_size, file
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::Record;
+
+    fn records_file() -> RecordsFile {
+        let mut rf = RecordsFile::default();
+        for (id, device, path) in
+            [(0, 1, "/system/bin/init"), (1, 1, "/system/lib/libc.so"), (2, 2, "/vendor/lib/a.so")]
+        {
+            rf.insert_or_update_inode_info(
+                FileId(id),
+                InodeInfo::new(id + 10, 1 << 20, vec![path.to_owned()], device),
+            );
+        }
+        for (id, offset, length, timestamp_ms) in
+            [(1, 0, 4096, 1), (0, 0, 16384, 2), (1, 4096, 8192, 3), (2, 0, 4096, 4)]
+        {
+            rf.insert_record(Record {
+                file_id: FileId(id),
+                offset,
+                length,
+                timestamp: timestamp_ms * 1_000_000,
+            });
+        }
+        rf
+    }
+
+    fn filter(args: DumpArgs) -> RecordsFile {
+        let filtered = DumpFilter::from_args(&args).unwrap().apply(&records_file());
+        filtered.check().unwrap();
+        filtered
+    }
+
+    fn file_ids(rf: &RecordsFile) -> Vec<u64> {
+        rf.inner.records.iter().map(|record| record.file_id.0).collect()
+    }
+
+    #[test]
+    fn test_dump_filter() {
+        assert_eq!(file_ids(&filter(DumpArgs::default())), vec![1, 0, 1, 2]);
+        let by_path =
+            filter(DumpArgs { path_regex: Some("/lib/".to_owned()), ..Default::default() });
+        assert_eq!(file_ids(&by_path), vec![1, 1, 2]);
+        assert_eq!(by_path.inner.inode_map.len(), 2);
+        assert_eq!(file_ids(&filter(DumpArgs { device: Some(2), ..Default::default() })), vec![2]);
+        assert_eq!(
+            file_ids(&filter(DumpArgs {
+                mount_prefix: Some("device 0:1".to_owned()),
+                ..Default::default()
+            })),
+            vec![1, 0, 1]
+        );
+        assert_eq!(
+            file_ids(&filter(DumpArgs {
+                start_ms: Some(2),
+                end_ms: Some(4),
+                ..Default::default()
+            })),
+            vec![0, 1]
+        );
+        assert_eq!(
+            file_ids(&filter(DumpArgs { min_length: Some(8192), ..Default::default() })),
+            vec![0, 1]
+        );
+        let by_id = filter(DumpArgs { file_id: vec![0, 2], ..Default::default() });
+        assert_eq!(file_ids(&by_id), vec![0, 2]);
+        assert!(!by_id.inner.inode_map.contains_key(&FileId(1)));
+
+        assert!(DumpFilter::from_args(&DumpArgs {
+            path_regex: Some("(".to_owned()),
+            ..Default::default()
+        })
+        .is_err());
+        assert!(DumpFilter::from_args(&DumpArgs {
+            start_ms: Some(2),
+            end_ms: Some(2),
+            ..Default::default()
+        })
+        .is_err());
+        assert!(DumpFilter::from_args(&DumpArgs { end_ms: Some(u64::MAX), ..Default::default() })
+            .is_err());
+    }
+
+    #[test]
+    fn test_file_summaries() {
+        let summaries = file_summaries(&records_file());
+        assert_eq!(summaries.iter().map(|s| s.file_id).collect::<Vec<_>>(), vec![1, 0, 2]);
+        assert_eq!(
+            summaries[0],
+            FileSummary {
+                file_id: 1,
+                paths: vec!["/system/lib/libc.so".to_owned()],
+                device_number: 1,
+                inode_number: 11,
+                file_size: 1 << 20,
+                records: 2,
+                bytes: 12288,
+            }
+        );
+
+        let mut csv = vec![];
+        write_file_summaries_csv(&summaries, &mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        assert_eq!(
+            csv.lines().take(2).collect::<Vec<_>>(),
+            vec![
+                "file_id,file,device_number,inode_number,file_size,records,bytes",
+                "1,/system/lib/libc.so,1,11,1048576,2,12288"
+            ]
+        );
+
+        let mut text = vec![];
+        write_file_summaries_text(&summaries, &mut text).unwrap();
+        assert_eq!(String::from_utf8(text).unwrap().lines().count(), 4);
+    }
+}
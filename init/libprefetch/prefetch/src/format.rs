@@ -32,11 +32,23 @@ use serde::{Deserialize, Serializer};
 
 use crate::error::Error;
 
+mod freshness;
+mod handle;
+mod integrity;
+mod pack;
+
+use freshness::Freshness;
+use handle::FileHandle;
+pub(crate) use handle::MountId;
+pub use integrity::{read_mac_key, DigestAlgorithm};
+use pack::Bytes;
+pub use pack::Compression;
+
 static MAGIC_UUID: [u8; 16] = [
     0x10, 0x54, 0x3c, 0xb8, 0x60, 0xdb, 0x49, 0x45, 0xa1, 0xd5, 0xde, 0xa7, 0xd2, 0x3b, 0x05, 0x49,
 ];
 static MAJOR_VERSION: u16 = 0;
-static MINOR_VERSION: u16 = 1;
+static MINOR_VERSION: u16 = 6;
 
 /// Represents inode number which is unique within a filesystem.
 pub(crate) type InodeNumber = u64;
@@ -80,6 +92,16 @@ pub(crate) struct SerializableHashMap<
     pub map: HashMap<K, V>,
 }
 
+impl<K, V> SerializableHashMap<K, V>
+where
+    K: Ord + Serialize + Clone + Hash + PartialEq,
+    V: Serialize + Clone,
+{
+    fn is_empty_map(&self) -> bool {
+        self.map.is_empty()
+    }
+}
+
 impl<K, V> Deref for SerializableHashMap<K, V>
 where
     K: Ord + Serialize + Clone + Hash + PartialEq,
@@ -129,6 +151,21 @@ pub struct InodeInfo {
 
     // Block device number on which the file is located.
     pub(crate) device_number: DeviceNumber,
+
+    // File handle of the file. Replay opens the file by handle, which skips the path walk and
+    // works after renames, and falls back to the paths.
+    //
+    // Added in minor version 3. Skipped when missing so that records files without handles
+    // serialize, and so checksum, as they were written.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) handle: Option<FileHandle>,
+
+    // Metadata of the file when it was recorded. Replay skips files that changed since.
+    //
+    // Added in minor version 4. Skipped when missing so that records files of older minor
+    // versions serialize, and so checksum, as they were written.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) freshness: Option<Freshness>,
 }
 
 impl InodeInfo {
@@ -139,7 +176,7 @@ impl InodeInfo {
         paths: Vec<String>,
         device_number: DeviceNumber,
     ) -> Self {
-        Self { inode_number, file_size, paths, device_number }
+        Self { inode_number, file_size, paths, device_number, handle: None, freshness: None }
     }
 }
 
@@ -252,6 +289,19 @@ pub(crate) fn coalesce_records(records: Vec<Record>, group_by_file_id: bool) ->
     coalesced
 }
 
+/// Returns the union of `ranges` of [start, end) offsets as sorted and disjoint ranges.
+pub(crate) fn union_ranges(mut ranges: Vec<(u64, u64)>) -> Vec<(u64, u64)> {
+    ranges.sort_unstable();
+    let mut union: Vec<(u64, u64)> = vec![];
+    for (start, end) in ranges {
+        match union.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => union.push((start, end)),
+        }
+    }
+    union
+}
+
 // Records file header.
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct Header {
@@ -272,6 +322,20 @@ pub struct Header {
 
     /// Checksum of the `RecordsFile` with `digest` being empty vector.
     digest: u32,
+
+    /// Compression of the records that follow the header. The digest is always computed over
+    /// the uncompressed records. Added in minor version 5.
+    #[serde(default, skip_serializing_if = "Compression::is_none")]
+    compression: Compression,
+
+    /// Digest that covers the records file on top of `digest`. Added in minor version 6.
+    #[serde(default, skip_serializing_if = "DigestAlgorithm::is_crc32")]
+    digest_algorithm: DigestAlgorithm,
+
+    /// SHA-256 or MAC of the `RecordsFile` with both digests being empty, as
+    /// `digest_algorithm` says. Added in minor version 6.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    strong_digest: Option<Bytes>,
 }
 
 fn check_version_number<'de, D>(
@@ -298,11 +362,19 @@ where
     check_version_number(deserializer, MAJOR_VERSION, "major")
 }
 
+// Minor versions only add fields, which records files of older minor versions lack and which
+// take their defaults. Newer minor versions may carry data this reader would drop.
 fn check_minor_number<'de, D>(deserializer: D) -> Result<u16, D::Error>
 where
     D: Deserializer<'de>,
 {
-    check_version_number(deserializer, MINOR_VERSION, "minor")
+    let found = u16::deserialize(deserializer)?;
+    if found > MINOR_VERSION {
+        return Err(serde::de::Error::custom(format!(
+            "Failed to parse minor version. Expected: {MINOR_VERSION} Found: {found}"
+        )));
+    }
+    Ok(found)
 }
 
 fn check_magic<'de, D>(deserializer: D) -> Result<[u8; 16], D::Error>
@@ -329,6 +401,22 @@ where
     Ok(parsed)
 }
 
+// Opens the file of `inode` by the first of its paths that opens. Returns the error of the first
+// path if none does.
+fn open_by_path(id: &FileId, inode: &InodeInfo) -> Result<File, Error> {
+    let mut first_error = None;
+    for path in &inode.paths {
+        debug!("Opening {} file {}", id.0, path);
+        match OpenOptions::new().read(true).write(false).open(path) {
+            Ok(file) => return Ok(file),
+            Err(source) => {
+                first_error.get_or_insert(Error::Open { source, path: path.to_owned() });
+            }
+        }
+    }
+    Err(first_error.unwrap())
+}
+
 // Helper inner struct of RecordsFile meant to verify checksum.
 #[derive(Clone, Debug, Default, Deserialize, Serialize, Eq, PartialEq)]
 pub(crate) struct RecordsFileInner {
@@ -347,10 +435,33 @@ pub(crate) struct RecordsFileInner {
     //
     // One instance per part of the file that needs to be prefetched.
     pub records: Vec<Record>,
+
+    /// Helps to get to a directory path from a given `FileId`.
+    /// One instance per directory whose entries were read while recording. Directories are
+    /// kept apart from `inode_map` as they cannot be read like files during replay.
+    ///
+    /// Added in minor version 2. Skipped when empty so that records files without directories
+    /// serialize, and so checksum, as they were written.
+    #[serde(default, skip_serializing_if = "SerializableHashMap::is_empty_map")]
+    pub(crate) directories: SerializableHashMap<FileId, InodeInfo>,
+
+    /// Reads of directory entries in the order they were recorded. Each record refers to a
+    /// directory in `directories`.
+    ///
+    /// Added in minor version 2. Skipped when empty, like `directories`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) directory_records: Vec<Record>,
+
+    /// Mount point of each mount the file handles in `inode_map` were taken on. Mount ids
+    /// change across boots, mount points do not.
+    ///
+    /// Added in minor version 3. Skipped when empty, like `directories`.
+    #[serde(default, skip_serializing_if = "SerializableHashMap::is_empty_map")]
+    pub(crate) mounts: SerializableHashMap<MountId, PathString>,
 }
 
 /// Deserialized form of records file.
-#[derive(Clone, Debug, Default, Deserialize, Serialize, Eq, PartialEq)]
+#[derive(Clone, Debug, Default, Serialize, Eq, PartialEq)]
 #[serde(remote = "Self")]
 pub struct RecordsFile {
     /// Helps the prefetch tool to parse rest of the file
@@ -361,7 +472,10 @@ pub struct RecordsFile {
 }
 
 impl RecordsFile {
-    /// Given file id, looks up path of the file and returns open File handle.
+    /// Given file id, opens the file by its file handle, or else by the first of its paths that
+    /// opens, and returns open File handle. Exclusion is decided on the first path.
+    ///
+    /// Files that changed since they were recorded fail with `Error::StaleFile`.
     pub fn open_file(&self, id: FileId, exclude_files_regex: &[Regex]) -> Result<File, Error> {
         if let Some(inode) = self.inner.inode_map.get(&id) {
             let path = inode.paths.first().unwrap();
@@ -371,22 +485,122 @@ impl RecordsFile {
                     return Err(Error::SkipPrefetch { path: path.to_owned() });
                 }
             }
-            debug!("Opening {} file {}", id.0, path);
-            OpenOptions::new()
-                .read(true)
-                .write(false)
-                .open(path)
-                .map_err(|source| Error::Open { source, path: path.to_owned() })
+            let file = match self.open_by_handle(&id, inode) {
+                Some(file) => file,
+                None => open_by_path(&id, inode)?,
+            };
+            if let Some(freshness) = &inode.freshness {
+                freshness
+                    .check(inode, &file)
+                    .map_err(|reason| Error::StaleFile { path: path.to_owned(), reason })?;
+            }
+            Ok(file)
         } else {
             Err(Error::IdNoFound { id })
         }
     }
 
+    // Opens the file of `inode` by its file handle, if it has one. Handles are only unique
+    // within a mount, and a mount point may be mounted over since recording, so the opened file
+    // must be the recorded inode.
+    fn open_by_handle(&self, id: &FileId, inode: &InodeInfo) -> Option<File> {
+        let handle = inode.handle.as_ref()?;
+        let mount_point = self.inner.mounts.get(&handle.mount_id)?;
+        match handle::open_by_handle_at_mount(mount_point, handle) {
+            Ok(file) => match file.metadata() {
+                Ok(stat)
+                    if stat.ino() == inode.inode_number && stat.dev() == inode.device_number =>
+                {
+                    debug!("Opened {} file by handle", id.0);
+                    Some(file)
+                }
+                Ok(_) => {
+                    debug!("File handle of {} file opened another inode", id.0);
+                    None
+                }
+                Err(e) => {
+                    debug!("Stat of {} file opened by handle failed: {e}", id.0);
+                    None
+                }
+            },
+            Err(e) => {
+                debug!("Opening {} file by handle failed: {e}", id.0);
+                None
+            }
+        }
+    }
+
+    /// Stores the file handle of each file, taken from the first of its paths that yields one,
+    /// along with the mount point the handle was taken on. Files on filesystems that do not
+    /// support file handles keep being opened by path.
+    pub(crate) fn insert_file_handles(&mut self) -> Result<(), Error> {
+        let mount_points = handle::mount_points()?;
+        for inode in self.inner.inode_map.values_mut() {
+            for path in &inode.paths {
+                let handle = match handle::name_to_handle(path) {
+                    Ok(handle) => handle,
+                    Err(e) => {
+                        debug!("{e}");
+                        continue;
+                    }
+                };
+                if let Some(mount_point) = mount_points.get(&handle.mount_id) {
+                    self.inner.mounts.insert(handle.mount_id, mount_point.clone());
+                    inode.handle = Some(handle);
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Stores the digest of the first block of each file whose freshness is known. Files that
+    /// fail to open or read are checked on metadata alone.
+    pub(crate) fn insert_first_block_digests(&mut self) {
+        for (id, inode) in self.inner.inode_map.map.iter_mut() {
+            let file_size = inode.file_size;
+            let freshness = match inode.freshness.as_mut() {
+                Some(freshness) => freshness,
+                None => continue,
+            };
+            let crc = inode.paths.iter().find_map(|path| {
+                let file = OpenOptions::new().read(true).write(false).open(path).ok()?;
+                freshness::first_block_crc(&file, file_size).ok()
+            });
+            if crc.is_none() {
+                debug!("failed to read the first block of file {}", id.0);
+            }
+            freshness.first_block_crc = crc;
+        }
+    }
+
     /// Inserts given record in RecordsFile
     pub fn insert_record(&mut self, records: Record) {
         self.inner.records.push(records);
     }
 
+    /// Inserts given directory record in RecordsFile
+    pub fn insert_directory_record(&mut self, record: Record) {
+        self.inner.directory_records.push(record);
+    }
+
+    /// Builds InodeInfo of a directory from args and inserts it in RecordsFile.
+    pub fn insert_or_update_directory(&mut self, id: FileId, stat: &Metadata, path: PathString) {
+        let info = InodeInfo {
+            inode_number: stat.ino(),
+            file_size: stat.len(),
+            paths: vec![path],
+            device_number: stat.dev(),
+            handle: None,
+            freshness: None,
+        };
+        if let Some(directory) = self.inner.directories.get_mut(&id) {
+            directory.paths.extend(info.paths);
+        } else {
+            self.inner.directories.insert(id, info);
+        }
+    }
+
     /// Inserts given InodeInfo into in RecordsFile.
     pub fn insert_or_update_inode_info(&mut self, id: FileId, info: InodeInfo) {
         if let Some(inode) = self.inner.inode_map.get_mut(&id) {
@@ -428,6 +642,23 @@ impl RecordsFile {
             }
         }
 
+        // Directories are checked alike against directory records.
+        let mut unique_directories = HashSet::new();
+        for record in &self.inner.directory_records {
+            if !self.inner.directories.contains_key(&record.file_id) {
+                missing_file_ids.push(record.file_id.clone());
+            }
+            unique_directories.insert(record.file_id.clone());
+        }
+        for (file_id, inode_info) in &self.inner.directories.map {
+            if inode_info.paths.is_empty() {
+                missing_paths.push(inode_info.clone());
+            }
+            if !unique_directories.contains(file_id) {
+                stale_inodes.push(inode_info.clone());
+            }
+        }
+
         if !stale_inodes.is_empty() || !missing_paths.is_empty() || !missing_file_ids.is_empty() {
             return Err(Error::StaleInode { stale_inodes, missing_paths, missing_file_ids });
         }
@@ -435,6 +666,24 @@ impl RecordsFile {
         Ok(())
     }
 
+    /// Removes the files and directories no record refers to, and the mounts of their handles
+    /// no other file refers to, so that a records file whose records were dropped still checks.
+    pub(crate) fn remove_orphans(&mut self) {
+        let inner = &mut self.inner;
+        let files: HashSet<&FileId> = inner.records.iter().map(|record| &record.file_id).collect();
+        inner.inode_map.retain(|id, _| files.contains(id));
+        let directories: HashSet<&FileId> =
+            inner.directory_records.iter().map(|record| &record.file_id).collect();
+        inner.directories.retain(|id, _| directories.contains(id));
+        let mount_ids: HashSet<MountId> = inner
+            .inode_map
+            .values()
+            .chain(inner.directories.values())
+            .filter_map(|info| info.handle.as_ref().map(|handle| handle.mount_id))
+            .collect();
+        inner.mounts.retain(|id, _| mount_ids.contains(id));
+    }
+
     /// Builds InodeInfo from args and inserts inode info in RecordsFile.
     pub fn insert_or_update_inode(&mut self, id: FileId, stat: &Metadata, path: PathString) {
         self.insert_or_update_inode_info(
@@ -444,6 +693,8 @@ impl RecordsFile {
                 file_size: stat.len(),
                 paths: vec![path],
                 device_number: stat.dev(),
+                handle: None,
+                freshness: Some(Freshness::new(stat)),
             },
         )
     }
@@ -484,21 +735,128 @@ impl RecordsFile {
         Ok(())
     }
 
-    fn compute_digest(&mut self) -> Result<u32, Error> {
+    /// Serialize records as aligned columns of text.
+    pub fn serialize_records_to_text(&self, writer: &mut dyn Write) -> Result<(), Error> {
+        writeln!(
+            writer,
+            "{:>16} {:>12} {:>12} {:>12} file",
+            "timestamp", "offset", "length", "file_size"
+        )?;
+        for record in &self.inner.records {
+            if let Some(path) =
+                self.inner.inode_map.get(&record.file_id).and_then(|info| info.paths.iter().min())
+            {
+                let file_size = self.inner.inode_map[&record.file_id].file_size;
+                writeln!(
+                    writer,
+                    "{:>16} {:>12} {:>12} {:>12} {}",
+                    record.timestamp, record.offset, record.length, file_size, path
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the mount point of the mount the file of `info` is on, or its device number if
+    /// the file has no handle.
+    pub(crate) fn mount_name(&self, info: &InodeInfo) -> String {
+        info.handle
+            .as_ref()
+            .and_then(|handle| self.inner.mounts.get(&handle.mount_id))
+            .cloned()
+            .unwrap_or_else(|| {
+                let device = info.device_number;
+                format!("device {}:{}", libc::major(device), libc::minor(device))
+            })
+    }
+
+    // Clears the digests and returns the records file serialized with plain records, which is
+    // what the digests cover.
+    fn clear_digests_and_serialize(&mut self) -> Result<Vec<u8>, Error> {
         self.header.digest = Default::default();
-        let serialized = serde_cbor::to_vec(self)
-            .map_err(|source| Error::Serialize { error: source.to_string() })?;
+        self.header.strong_digest = None;
+        serde_cbor::to_vec(&self.plain())
+            .map_err(|source| Error::Serialize { error: source.to_string() })
+    }
+
+    // Computes the digests of the records file. `key` keys the MAC, if the records file has one.
+    fn add_digests(&mut self, key: Option<&[u8]>) -> Result<(), Error> {
+        let serialized = self.clear_digests_and_serialize()?;
+        let strong_digest = match (self.header.digest_algorithm, key) {
+            (DigestAlgorithm::Crc32, _) => None,
+            (DigestAlgorithm::Sha256, _) => Some(integrity::sha256(&serialized)),
+            (DigestAlgorithm::HmacSha256, Some(key)) => {
+                Some(integrity::hmac_sha256(key, &serialized)?)
+            }
+            (DigestAlgorithm::HmacSha256, None) => {
+                return Err(Error::Integrity {
+                    error: "a MAC key is needed to write a records file with a MAC".to_owned(),
+                })
+            }
+        };
+        self.header.digest = crc32(&serialized);
+        self.header.strong_digest = strong_digest.map(Bytes);
+        Ok(())
+    }
+
+    /// Sets the digest that covers the records file on top of its CRC32. Records files with
+    /// `DigestAlgorithm::HmacSha256` are written with `add_mac_and_serialize`.
+    pub fn set_digest_algorithm(&mut self, algorithm: DigestAlgorithm) {
+        self.header.digest_algorithm = algorithm;
+    }
+
+    /// Returns true if the records file carries a MAC.
+    pub fn has_mac(&self) -> bool {
+        self.header.digest_algorithm == DigestAlgorithm::HmacSha256
+    }
+
+    /// Checks that the records file carries a MAC keyed with `key`. Records files without a MAC
+    /// fail the check.
+    pub fn verify_mac(&self, key: &[u8]) -> Result<(), Error> {
+        let mac = match (self.header.digest_algorithm, &self.header.strong_digest) {
+            (DigestAlgorithm::HmacSha256, Some(mac)) => mac,
+            _ => {
+                return Err(Error::Integrity { error: "records file has no MAC".to_owned() });
+            }
+        };
+        let serialized = self.clone().clear_digests_and_serialize()?;
+        integrity::verify_hmac_sha256(key, &serialized, &mac.0)
+    }
+
+    /// Returns true if the records file was written in an older minor version of the format.
+    pub fn needs_upgrade(&self) -> bool {
+        self.header.minor_number < MINOR_VERSION
+    }
+
+    /// Moves the records file to the current minor version of the format. Fields added since
+    /// keep their defaults. The digest is recomputed when the records file is serialized.
+    pub fn upgrade(&mut self) {
+        self.header.minor_number = MINOR_VERSION;
+    }
 
-        let mut hasher = Hasher::new();
-        hasher.update(&serialized);
+    /// Sets how the records are stored when the records file is serialized.
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.header.compression = compression;
+    }
 
-        Ok(hasher.finalize())
+    /// Returns the records file serialized with uncompressed records, whatever its compression.
+    pub fn plain(&self) -> impl Serialize + '_ {
+        PlainRecordsFile(self)
     }
 
     /// Convenience wrapper around serialize that adds checksum/digest to the file
     /// to verify file consistency during replay/deserialize.
     pub fn add_checksum_and_serialize(&mut self) -> Result<Vec<u8>, Error> {
-        self.header.digest = self.compute_digest()?;
+        self.add_digests(None)?;
+
+        serde_cbor::to_vec(self).map_err(|source| Error::Serialize { error: source.to_string() })
+    }
+
+    /// Like `add_checksum_and_serialize` but also adds a MAC keyed with `key` that replay can
+    /// check the records file against.
+    pub fn add_mac_and_serialize(&mut self, key: &[u8]) -> Result<Vec<u8>, Error> {
+        self.header.digest_algorithm = DigestAlgorithm::HmacSha256;
+        self.add_digests(Some(key))?;
 
         serde_cbor::to_vec(self).map_err(|source| Error::Serialize { error: source.to_string() })
     }
@@ -512,26 +870,77 @@ impl Default for Header {
             date: SystemTime::now(),
             digest: 0,
             magic: MAGIC_UUID,
+            compression: Compression::None,
+            digest_algorithm: DigestAlgorithm::Crc32,
+            strong_digest: None,
         }
     }
 }
 
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut hasher = Hasher::new();
+    hasher.update(bytes);
+    hasher.finalize()
+}
+
+// Records file as stored. Records are stored either as is or compressed, as the header says.
+#[derive(Deserialize)]
+struct StoredRecordsFile {
+    header: Header,
+    #[serde(default)]
+    inner: Option<RecordsFileInner>,
+    #[serde(default)]
+    compressed_inner: Option<Bytes>,
+}
+
+// Records file with compressed records.
+#[derive(Serialize)]
+struct CompressedRecordsFile<'a> {
+    header: &'a Header,
+    compressed_inner: Bytes,
+}
+
+// Records file with uncompressed records, which is what the digest covers.
+struct PlainRecordsFile<'a>(&'a RecordsFile);
+
+impl Serialize for PlainRecordsFile<'_> {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        RecordsFile::serialize(self.0, serializer)
+    }
+}
+
 // Wrapper around deserialize to check any inconsistencies in the file format.
 impl<'de> Deserialize<'de> for RecordsFile {
     fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
     where
         D: Deserializer<'de>,
     {
-        let rf = Self::deserialize(deserializer)?;
+        let StoredRecordsFile { header, inner, compressed_inner } =
+            StoredRecordsFile::deserialize(deserializer)?;
+        let inner = match (header.compression, inner, compressed_inner) {
+            (Compression::None, Some(inner), None) => inner,
+            (Compression::Zstd, None, Some(bytes)) => pack::decompress_inner(&bytes.0)
+                .map_err(|e| serde::de::Error::custom(format!("{e}")))?,
+            (compression, _, _) => {
+                return Err(serde::de::Error::custom(format!(
+                    "records do not match compression {compression:?}"
+                )))
+            }
+        };
+        let rf = Self { header, inner };
 
         rf.check().map_err(|e| {
             serde::de::Error::custom(format!("failed to validate records file: {e}"))
         })?;
 
-        let mut zero_digest = rf.clone();
-        zero_digest.header.digest = 0;
-        let digest =
-            zero_digest.compute_digest().map_err(|e| serde::de::Error::custom(format!("{e}")))?;
+        let serialized = rf
+            .clone()
+            .clear_digests_and_serialize()
+            .map_err(|e| serde::de::Error::custom(format!("{e}")))?;
+        let digest = crc32(&serialized);
 
         if digest != rf.header.digest {
             return Err(serde::de::Error::custom(format!(
@@ -540,6 +949,22 @@ impl<'de> Deserialize<'de> for RecordsFile {
             )));
         }
 
+        // The MAC needs the key, which is checked by `verify_mac`.
+        let strong_digest = rf.header.strong_digest.as_ref();
+        let strong_digest_ok = match rf.header.digest_algorithm {
+            DigestAlgorithm::Crc32 => strong_digest.is_none(),
+            DigestAlgorithm::Sha256 => {
+                strong_digest.map(|d| d.0 == integrity::sha256(&serialized)).unwrap_or(false)
+            }
+            DigestAlgorithm::HmacSha256 => strong_digest.is_some(),
+        };
+        if !strong_digest_ok {
+            return Err(serde::de::Error::custom(format!(
+                "file consistency check failed. {:?} digest does not match",
+                rf.header.digest_algorithm
+            )));
+        }
+
         Ok(rf)
     }
 }
@@ -553,7 +978,13 @@ impl Serialize for RecordsFile {
         self.check().map(|_| self).map_err(|e| {
             serde::ser::Error::custom(format!("failed to validate records file: {e}"))
         })?;
-        Self::serialize(self, serializer)
+        let compressed_inner = match self.header.compression {
+            Compression::None => return Self::serialize(self, serializer),
+            Compression::Zstd => pack::compress_inner(&self.inner)
+                .map_err(|e| serde::ser::Error::custom(format!("{e}")))?,
+        };
+        CompressedRecordsFile { header: &self.header, compressed_inner: Bytes(compressed_inner) }
+            .serialize(serializer)
     }
 }
 
@@ -602,9 +1033,41 @@ pub mod tests {
         );
     }
 
+    #[test]
+    fn test_older_minor_version() {
+        // Records files of minor version 1 have none of the fields added since.
+        let mut rf = RecordsFile::default();
+        rf.header.minor_number = 1;
+        rf.insert_or_update_inode_info(
+            FileId(0),
+            InodeInfo::new(1, 10, vec!["/system/bin/hello".to_owned()], 2),
+        );
+        rf.insert_record(Record { file_id: FileId(0), offset: 0, length: 10, timestamp: 30 });
+        let serialized = rf.add_checksum_and_serialize().unwrap();
+
+        let mut deserialized: RecordsFile = serde_cbor::from_slice(&serialized).unwrap();
+        assert_eq!(deserialized, rf);
+        assert!(deserialized.needs_upgrade());
+
+        deserialized.upgrade();
+        let upgraded = deserialized.add_checksum_and_serialize().unwrap();
+        let upgraded: RecordsFile = serde_cbor::from_slice(&upgraded).unwrap();
+        assert!(!upgraded.needs_upgrade());
+        assert_eq!(upgraded.header.minor_number, MINOR_VERSION);
+        assert_ne!(upgraded.header.digest, rf.header.digest);
+        assert_eq!(upgraded.inner, rf.inner);
+    }
+
     #[test]
     fn deserialize_inode_info_without_path() {
-        let inode = InodeInfo { inode_number: 1, file_size: 10, paths: vec![], device_number: 1 };
+        let inode = InodeInfo {
+            inode_number: 1,
+            file_size: 10,
+            paths: vec![],
+            device_number: 1,
+            handle: None,
+            freshness: None,
+        };
         let serialized = serde_cbor::to_vec(&inode).unwrap();
         let deserialized: Result<InodeInfo, serde_cbor::Error> =
             serde_cbor::from_slice(&serialized);
@@ -613,6 +1076,244 @@ pub mod tests {
             "No paths found for in InodeInfo".to_owned()
         );
     }
+    #[test]
+    fn test_directories_round_trip() {
+        let mut rf = RecordsFile::default();
+        rf.insert_or_update_inode_info(
+            FileId(0),
+            InodeInfo::new(1, 10, vec!["/system/bin/hello".to_owned()], 2),
+        );
+        rf.insert_record(Record { file_id: FileId(0), offset: 0, length: 10, timestamp: 30 });
+        // Packs without directories serialize as before directories were recorded.
+        let without_directories = serde_cbor::to_vec(&rf).unwrap();
+        assert!(!without_directories.windows(11).any(|w| w == b"directories"));
+
+        rf.inner
+            .directories
+            .insert(FileId(1), InodeInfo::new(3, 4096, vec!["/system/bin".to_owned()], 2));
+        rf.insert_directory_record(Record {
+            file_id: FileId(1),
+            offset: 0,
+            length: 4096,
+            timestamp: 20,
+        });
+        rf.check().unwrap();
+        let serialized = rf.add_checksum_and_serialize().unwrap();
+        let deserialized: RecordsFile = serde_cbor::from_slice(&serialized).unwrap();
+        assert_eq!(deserialized, rf);
+    }
+
+    #[test]
+    fn test_compressed_round_trip() {
+        let mut rf = RecordsFile::default();
+        for i in 0..500 {
+            let path = format!("/system/lib64/libfoo_{i}.so");
+            rf.insert_or_update_inode_info(FileId(i), InodeInfo::new(i, 8192, vec![path], 2));
+            rf.insert_record(Record { file_id: FileId(i), offset: 0, length: 4096, timestamp: i });
+        }
+        let plain = rf.add_checksum_and_serialize().unwrap();
+        let plain_digest = rf.header.digest;
+
+        rf.set_compression(Compression::Zstd);
+        let compressed = rf.add_checksum_and_serialize().unwrap();
+        assert!(compressed.len() * 4 < plain.len(), "{} {}", compressed.len(), plain.len());
+        // The digest covers the header, which names the compression, and the plain records.
+        assert_ne!(rf.header.digest, plain_digest);
+
+        let deserialized: RecordsFile = serde_cbor::from_slice(&compressed).unwrap();
+        assert_eq!(deserialized, rf);
+        let deserialized: RecordsFile = serde_cbor::from_slice(&plain).unwrap();
+        assert_eq!(deserialized.inner, rf.inner);
+    }
+
+    fn records_file_with_one_file() -> RecordsFile {
+        let mut rf = RecordsFile::default();
+        rf.insert_or_update_inode_info(
+            FileId(0),
+            InodeInfo::new(1, 10, vec!["/system/bin/hello".to_owned()], 2),
+        );
+        rf.insert_record(Record { file_id: FileId(0), offset: 0, length: 10, timestamp: 30 });
+        rf
+    }
+
+    #[test]
+    fn test_sha256_digest() {
+        let mut rf = records_file_with_one_file();
+        rf.set_digest_algorithm(DigestAlgorithm::Sha256);
+        let serialized = rf.add_checksum_and_serialize().unwrap();
+        let deserialized: RecordsFile = serde_cbor::from_slice(&serialized).unwrap();
+        assert_eq!(deserialized, rf);
+
+        // A changed path with a recomputed CRC32 but the old SHA-256.
+        let mut tampered = rf.clone();
+        tampered.inner.inode_map.get_mut(&FileId(0)).unwrap().paths[0] = "/data/evil".to_owned();
+        let strong_digest = tampered.header.strong_digest.clone();
+        tampered.add_checksum_and_serialize().unwrap();
+        tampered.header.strong_digest = strong_digest;
+        let deserialized: Result<RecordsFile, serde_cbor::Error> =
+            serde_cbor::from_slice(&serde_cbor::to_vec(&tampered).unwrap());
+        assert!(
+            deserialized.as_ref().unwrap_err().to_string().contains("Sha256 digest does not match"),
+            "{:?}",
+            deserialized
+        );
+    }
+
+    #[test]
+    fn test_mac() {
+        let key = b"device secret";
+        let mut rf = records_file_with_one_file();
+        let serialized = rf.add_mac_and_serialize(key).unwrap();
+        let deserialized: RecordsFile = serde_cbor::from_slice(&serialized).unwrap();
+        assert!(deserialized.has_mac());
+        deserialized.verify_mac(key).unwrap();
+        assert!(deserialized.verify_mac(b"other secret").is_err());
+
+        // Writing a records file with a MAC needs the key.
+        let mut copy = deserialized.clone();
+        assert!(copy.add_checksum_and_serialize().is_err());
+    }
+
+    #[test]
+    fn test_tampered_mac() {
+        let key = b"device secret";
+        let mut rf = records_file_with_one_file();
+        rf.add_mac_and_serialize(key).unwrap();
+
+        // Changed records under the original MAC.
+        let mut tampered = rf.clone();
+        tampered.inner.inode_map.get_mut(&FileId(0)).unwrap().paths[0] = "/data/evil".to_owned();
+        let mac = tampered.header.strong_digest.clone();
+        tampered.add_mac_and_serialize(b"other secret").unwrap();
+        tampered.header.strong_digest = mac;
+        assert!(tampered.verify_mac(key).is_err());
+
+        // Changed records with a MAC keyed with another secret.
+        tampered.add_mac_and_serialize(b"other secret").unwrap();
+        assert!(tampered.verify_mac(key).is_err());
+
+        // Changed records without a MAC.
+        tampered.set_digest_algorithm(DigestAlgorithm::Sha256);
+        let serialized = tampered.add_checksum_and_serialize().unwrap();
+        let deserialized: RecordsFile = serde_cbor::from_slice(&serialized).unwrap();
+        assert_eq!(
+            deserialized.verify_mac(key).unwrap_err().to_string(),
+            "Integrity check failed: records file has no MAC"
+        );
+
+        // Flipped bytes.
+        let mut serialized = rf.add_mac_and_serialize(key).unwrap();
+        let last = serialized.len() - 1;
+        serialized[last] ^= 1;
+        assert!(serde_cbor::from_slice::<RecordsFile>(&serialized).is_err());
+    }
+
+    #[test]
+    fn test_compression_mismatch() {
+        let mut rf = RecordsFile::default();
+        rf.add_checksum_and_serialize().unwrap();
+        // Plain records with a header that claims compressed ones.
+        let mut value = serde_cbor::value::to_value(rf.plain()).unwrap();
+        if let serde_cbor::Value::Map(map) = &mut value {
+            if let Some(serde_cbor::Value::Map(header)) =
+                map.get_mut(&serde_cbor::Value::Text("header".to_owned()))
+            {
+                header.insert(
+                    serde_cbor::Value::Text("compression".to_owned()),
+                    serde_cbor::Value::Text("zstd".to_owned()),
+                );
+            }
+        }
+        let deserialized: Result<RecordsFile, serde_cbor::Error> =
+            serde_cbor::from_slice(&serde_cbor::to_vec(&value).unwrap());
+        assert!(
+            deserialized.as_ref().unwrap_err().to_string().contains("records do not match"),
+            "{:?}",
+            deserialized
+        );
+    }
+
+    #[test]
+    fn test_open_file_falls_back_to_other_paths() {
+        let dir = crate::tracer::tests::setup_test_dir();
+        let path = dir.join("file").to_str().unwrap().to_owned();
+        let link = dir.join("link").to_str().unwrap().to_owned();
+        std::fs::write(&path, b"hello").unwrap();
+        std::fs::hard_link(&path, &link).unwrap();
+        let mut rf = RecordsFile::default();
+        rf.insert_or_update_inode(FileId(0), &std::fs::metadata(&path).unwrap(), path.clone());
+        rf.insert_or_update_inode(FileId(0), &std::fs::metadata(&link).unwrap(), link.clone());
+        // Links change the status change time. The digest tells the data did not change.
+        rf.insert_first_block_digests();
+
+        std::fs::remove_file(&path).unwrap();
+        rf.open_file(FileId(0), &[]).unwrap();
+        std::fs::remove_file(&link).unwrap();
+        let e = rf.open_file(FileId(0), &[]).unwrap_err();
+        assert!(matches!(&e, Error::Open { path: p, .. } if *p == path), "{:?}", e);
+    }
+
+    #[test]
+    fn test_open_file_by_handle_after_rename() {
+        let dir = crate::tracer::tests::setup_test_dir();
+        let path = dir.join("file").to_str().unwrap().to_owned();
+        std::fs::write(&path, b"hello").unwrap();
+        let mut rf = RecordsFile::default();
+        rf.insert_or_update_inode(FileId(0), &std::fs::metadata(&path).unwrap(), path.clone());
+        rf.insert_file_handles().unwrap();
+        rf.insert_first_block_digests();
+        if rf.inner.inode_map[&FileId(0)].handle.is_none() {
+            // Not all filesystems support file handles.
+            return;
+        }
+        assert_eq!(rf.inner.mounts.len(), 1);
+
+        std::fs::rename(&path, dir.join("renamed")).unwrap();
+        match rf.open_file(FileId(0), &[]) {
+            Ok(mut file) => {
+                let mut contents = String::new();
+                std::io::Read::read_to_string(&mut file, &mut contents).unwrap();
+                assert_eq!(contents, "hello");
+            }
+            // Opening by handle needs CAP_DAC_READ_SEARCH. Without it, replay falls back to
+            // paths.
+            Err(e) => assert!(matches!(e, Error::Open { .. }), "{:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_open_file_by_handle_of_another_inode() {
+        let dir = crate::tracer::tests::setup_test_dir();
+        let path = dir.join("file").to_str().unwrap().to_owned();
+        std::fs::write(&path, b"hello").unwrap();
+        let mut rf = RecordsFile::default();
+        rf.insert_or_update_inode(FileId(0), &std::fs::metadata(&path).unwrap(), path.clone());
+        rf.insert_file_handles().unwrap();
+        if rf.inner.inode_map[&FileId(0)].handle.is_none() {
+            // Not all filesystems support file handles.
+            return;
+        }
+
+        // The handle opens a file other than the recorded inode, so it is not used and the
+        // renamed file cannot be found by path.
+        rf.inner.inode_map.get_mut(&FileId(0)).unwrap().inode_number += 1;
+        std::fs::rename(&path, dir.join("renamed")).unwrap();
+        let e = rf.open_file(FileId(0), &[]).unwrap_err();
+        assert!(matches!(e, Error::Open { .. }), "{:?}", e);
+    }
+
+    #[test]
+    fn check_directory_missing_records() {
+        let mut rf = RecordsFile::default();
+        rf.inner.directories.insert(FileId(0), InodeInfo::new(0, 1, vec!["dir".to_owned()], 2));
+        let e = rf.check().unwrap_err();
+        assert!(
+            matches!(&e, Error::StaleInode { stale_inodes, .. } if stale_inodes.len() == 1),
+            "{:?}",
+            e
+        );
+    }
+
     #[test]
     fn test_serialize_records_to_csv() {
         let mut rf = RecordsFile::default();
@@ -625,6 +1326,8 @@ pub mod tests {
                     file_size: i * 10,
                     paths: vec![format!("/hello/{}", i)],
                     device_number: i + 10,
+                    handle: None,
+                    freshness: None,
                 },
             )
         }
@@ -741,6 +1444,8 @@ pub mod tests {
                 file_size: 1,
                 paths: vec!["hello".to_owned()],
                 device_number: 2,
+                handle: None,
+                freshness: None,
             },
         );
         rf.insert_record(Record { file_id: FileId(0), offset: 10, length: 20, timestamp: 30 });
@@ -752,6 +1457,8 @@ pub mod tests {
                 file_size: 2,
                 paths: vec!["world".to_owned()],
                 device_number: 3,
+                handle: None,
+                freshness: None,
             },
         );
         let e = rf.check().unwrap_err();
@@ -764,7 +1471,7 @@ pub mod tests {
                         inode_number: 1,\n        \
                         file_size: 2,\n        \
                         paths: [\n            \"world\",\n        ],\n        \
-                        device_number: 3,\n    },\n] \n\
+                        device_number: 3,\n        handle: None,\n        freshness: None,\n    },\n] \n\
                 missing_paths:[]"
         );
     }
@@ -779,6 +1486,8 @@ pub mod tests {
                 file_size: 1,
                 paths: vec!["hello".to_owned()],
                 device_number: 2,
+                handle: None,
+                freshness: None,
             },
         );
         rf.insert_record(Record { file_id: FileId(0), offset: 10, length: 20, timestamp: 30 });
@@ -800,7 +1509,14 @@ pub mod tests {
         let mut rf = RecordsFile::default();
         rf.inner.inode_map.insert(
             FileId(0),
-            InodeInfo { inode_number: 0, file_size: 1, paths: vec![], device_number: 2 },
+            InodeInfo {
+                inode_number: 0,
+                file_size: 1,
+                paths: vec![],
+                device_number: 2,
+                handle: None,
+                freshness: None,
+            },
         );
         rf.insert_record(Record { file_id: FileId(0), offset: 10, length: 20, timestamp: 30 });
 
@@ -815,7 +1531,7 @@ pub mod tests {
                         inode_number: 0,\n        \
                         file_size: 1,\n        \
                         paths: [],\n        \
-                        device_number: 2,\n    },\n]"
+                        device_number: 2,\n        handle: None,\n        freshness: None,\n    },\n]"
         );
     }
 }
@@ -0,0 +1,180 @@
+// Copyright (C) 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Tells whether a file changed since it was recorded.
+//!
+//! Updates that keep the build fingerprint, like APEX or mainline module updates, replace files
+//! under the same paths. Reading such files brings data into the page cache that nobody asked
+//! for.
+
+use std::fs::{File, Metadata};
+use std::os::unix::fs::{FileExt, MetadataExt};
+
+use crc32fast::Hasher;
+use serde::{Deserialize, Serialize};
+
+use crate::format::InodeInfo;
+
+// Number of bytes at the start of a file covered by the digest.
+const FIRST_BLOCK_SIZE: u64 = 4096;
+
+/// Metadata of a file as recorded, compared against the file at replay.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub(crate) struct Freshness {
+    // Modification time in nanoseconds since the epoch.
+    pub(crate) mtime_ns: i64,
+
+    // Status change time in nanoseconds since the epoch.
+    pub(crate) ctime_ns: i64,
+
+    // CRC32 of the first block of the file, if it was read.
+    #[serde(default)]
+    pub(crate) first_block_crc: Option<u32>,
+}
+
+fn mtime_ns(stat: &Metadata) -> i64 {
+    stat.mtime().saturating_mul(1_000_000_000).saturating_add(stat.mtime_nsec())
+}
+
+fn ctime_ns(stat: &Metadata) -> i64 {
+    stat.ctime().saturating_mul(1_000_000_000).saturating_add(stat.ctime_nsec())
+}
+
+/// Returns the CRC32 of the first block of `file` of size `file_size`.
+pub(crate) fn first_block_crc(file: &File, file_size: u64) -> std::io::Result<u32> {
+    let mut buffer = vec![0u8; file_size.min(FIRST_BLOCK_SIZE) as usize];
+    file.read_exact_at(&mut buffer, 0)?;
+    let mut hasher = Hasher::new();
+    hasher.update(&buffer);
+    Ok(hasher.finalize())
+}
+
+impl Freshness {
+    /// Returns the freshness of a file with `stat`. The digest is filled in separately as it
+    /// needs reading the file.
+    pub(crate) fn new(stat: &Metadata) -> Self {
+        Self { mtime_ns: mtime_ns(stat), ctime_ns: ctime_ns(stat), first_block_crc: None }
+    }
+
+    /// Checks `file`, opened for `info`, against the recorded metadata. Returns why the file is
+    /// stale if it is.
+    ///
+    /// Renames and links change the status change time of a file but not its data. A changed
+    /// status change time alone is confirmed with the digest, if there is one.
+    pub(crate) fn check(&self, info: &InodeInfo, file: &File) -> Result<(), String> {
+        let stat = file.metadata().map_err(|e| format!("stat failed: {e}"))?;
+        if stat.ino() != info.inode_number {
+            return Err(format!(
+                "inode number changed from {} to {}",
+                info.inode_number,
+                stat.ino()
+            ));
+        }
+        if stat.len() != info.file_size {
+            return Err(format!("file size changed from {} to {}", info.file_size, stat.len()));
+        }
+        if mtime_ns(&stat) != self.mtime_ns {
+            return Err("modification time changed".to_owned());
+        }
+        if ctime_ns(&stat) == self.ctime_ns {
+            return Ok(());
+        }
+        match self.first_block_crc {
+            Some(expected) => match first_block_crc(file, stat.len()) {
+                Ok(crc) if crc == expected => Ok(()),
+                Ok(_) => Err("first block changed".to_owned()),
+                Err(e) => Err(format!("reading first block failed: {e}")),
+            },
+            None => Err("status change time changed".to_owned()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tracer::tests::setup_test_dir;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::time::{Duration, SystemTime};
+
+    fn recorded(path: &std::path::Path, digest: bool) -> (InodeInfo, Freshness) {
+        let stat = fs::metadata(path).unwrap();
+        let info = InodeInfo::new(stat.ino(), stat.len(), vec![], stat.dev());
+        let mut freshness = Freshness::new(&stat);
+        if digest {
+            freshness.first_block_crc =
+                Some(first_block_crc(&File::open(path).unwrap(), stat.len()).unwrap());
+        }
+        (info, freshness)
+    }
+
+    #[test]
+    fn test_unchanged_file_is_fresh() {
+        let path = setup_test_dir().join("file");
+        fs::write(&path, vec![1u8; 10000]).unwrap();
+        let (info, freshness) = recorded(&path, true);
+        assert_eq!(freshness.check(&info, &File::open(&path).unwrap()), Ok(()));
+    }
+
+    #[test]
+    fn test_changed_files_are_stale() {
+        let dir = setup_test_dir();
+        let path = dir.join("file");
+        fs::write(&path, vec![1u8; 100]).unwrap();
+        let (info, freshness) = recorded(&path, true);
+
+        // Same size, different data and modification time.
+        fs::write(&path, vec![2u8; 100]).unwrap();
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(10))
+            .unwrap();
+        assert_eq!(
+            freshness.check(&info, &File::open(&path).unwrap()),
+            Err("modification time changed".to_owned())
+        );
+
+        fs::write(&path, vec![2u8; 200]).unwrap();
+        assert_eq!(
+            freshness.check(&info, &File::open(&path).unwrap()),
+            Err("file size changed from 100 to 200".to_owned())
+        );
+
+        let replaced = dir.join("replaced");
+        fs::write(&replaced, vec![1u8; 100]).unwrap();
+        fs::rename(&replaced, &path).unwrap();
+        assert!(freshness
+            .check(&info, &File::open(&path).unwrap())
+            .unwrap_err()
+            .starts_with("inode number changed"));
+    }
+
+    #[test]
+    fn test_status_change_is_confirmed_with_digest() {
+        let path = setup_test_dir().join("file");
+        fs::write(&path, vec![1u8; 100]).unwrap();
+        let (info, freshness) = recorded(&path, true);
+        let (_, no_digest) = recorded(&path, false);
+
+        // Changing permissions changes the status change time only.
+        std::thread::sleep(Duration::from_millis(10));
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        let file = File::open(&path).unwrap();
+        assert_eq!(freshness.check(&info, &file), Ok(()));
+        assert_eq!(no_digest.check(&info, &file), Err("status change time changed".to_owned()));
+    }
+}
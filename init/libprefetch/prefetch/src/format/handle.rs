@@ -0,0 +1,246 @@
+// Copyright (C) 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Opens files by file handle.
+//!
+//! A file handle names a file within its filesystem regardless of its paths. Opening a file by
+//! handle skips the path walk and still works after the file is renamed. Handles are only
+//! meaningful along with the filesystem they were taken on, which is named by a mount id.
+//! Mount ids are not stable across boots, so the mount point of each mount id is recorded too.
+
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+use crate::format::PathString;
+
+const MOUNTINFO_PATH: &str = "/proc/self/mountinfo";
+
+// See include/uapi/linux/fcntl.h
+const MAX_HANDLE_SZ: usize = 128;
+
+/// Identifies a mount as reported by name_to_handle_at(2) and /proc/self/mountinfo.
+pub(crate) type MountId = i32;
+
+/// File handle of a file as returned by name_to_handle_at(2).
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub(crate) struct FileHandle {
+    // Filesystem specific type of the handle.
+    pub(crate) handle_type: i32,
+
+    // Opaque handle bytes.
+    pub(crate) bytes: Vec<u8>,
+
+    // Mount the handle was taken on. Only valid for the boot the records file was recorded in.
+    pub(crate) mount_id: MountId,
+}
+
+#[repr(C)]
+struct RawFileHandle {
+    handle_bytes: u32,
+    handle_type: i32,
+    f_handle: [u8; MAX_HANDLE_SZ],
+}
+
+/// Returns the file handle of `path`.
+pub(crate) fn name_to_handle(path: &str) -> Result<FileHandle, Error> {
+    let c_path = CString::new(path)
+        .map_err(|e| Error::Custom { error: format!("invalid path {path}: {e}") })?;
+    let mut raw = RawFileHandle {
+        handle_bytes: MAX_HANDLE_SZ as u32,
+        handle_type: 0,
+        f_handle: [0; MAX_HANDLE_SZ],
+    };
+    let mut mount_id: libc::c_int = 0;
+    // SAFETY: This is safe because c_path is nul terminated and raw has room for handle_bytes
+    // bytes of handle.
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_name_to_handle_at,
+            libc::AT_FDCWD,
+            c_path.as_ptr(),
+            &mut raw as *mut RawFileHandle,
+            &mut mount_id as *mut libc::c_int,
+            0,
+        )
+    };
+    if ret < 0 {
+        return Err(Error::Custom {
+            error: format!("name_to_handle_at {path} failed: {}", io::Error::last_os_error()),
+        });
+    }
+    Ok(FileHandle {
+        handle_type: raw.handle_type,
+        bytes: raw.f_handle[..raw.handle_bytes as usize].to_vec(),
+        mount_id,
+    })
+}
+
+/// Opens the file of `handle` read only. `mount` is any file on the filesystem of the handle.
+pub(crate) fn open_by_handle(mount: &File, handle: &FileHandle) -> io::Result<File> {
+    if handle.bytes.len() > MAX_HANDLE_SZ {
+        return Err(io::Error::from_raw_os_error(libc::EINVAL));
+    }
+    let mut raw = RawFileHandle {
+        handle_bytes: handle.bytes.len() as u32,
+        handle_type: handle.handle_type,
+        f_handle: [0; MAX_HANDLE_SZ],
+    };
+    raw.f_handle[..handle.bytes.len()].copy_from_slice(&handle.bytes);
+    // SAFETY: This is safe because raw holds handle_bytes bytes of handle and the returned fd,
+    // if any, is owned by nothing else.
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_open_by_handle_at,
+            mount.as_raw_fd(),
+            &mut raw as *mut RawFileHandle,
+            libc::O_RDONLY | libc::O_CLOEXEC,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: fd was just opened and is owned by nothing else.
+    Ok(unsafe { File::from_raw_fd(fd as libc::c_int) })
+}
+
+// Undoes the octal escaping of spaces, tabs, newlines and backslashes in mountinfo paths.
+fn unescape_mountinfo_path(path: &str) -> String {
+    let mut unescaped = String::with_capacity(path.len());
+    let mut rest = path;
+    while let Some(pos) = rest.find('\\') {
+        unescaped.push_str(&rest[..pos]);
+        let escaped =
+            rest.get(pos + 1..pos + 4).and_then(|octal| u8::from_str_radix(octal, 8).ok());
+        match escaped {
+            Some(byte) => {
+                unescaped.push(byte as char);
+                rest = &rest[pos + 4..];
+            }
+            None => {
+                unescaped.push('\\');
+                rest = &rest[pos + 1..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
+
+// Returns the mount point of each mount id in the contents of a mountinfo file.
+fn parse_mount_points(contents: &str) -> HashMap<MountId, PathString> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let mount_id = fields.next()?.parse().ok()?;
+            let mount_point = fields.nth(3)?;
+            Some((mount_id, unescape_mountinfo_path(mount_point)))
+        })
+        .collect()
+}
+
+/// Returns the mount point of each mount id of the current mount namespace.
+pub(crate) fn mount_points() -> Result<HashMap<MountId, PathString>, Error> {
+    let contents = fs::read_to_string(MOUNTINFO_PATH)
+        .map_err(|source| Error::Open { source, path: MOUNTINFO_PATH.to_owned() })?;
+    Ok(parse_mount_points(&contents))
+}
+
+// Set once opening by handle fails for lack of privileges or kernel support so that replay
+// does not pay for a failing syscall per file.
+static HANDLES_UNAVAILABLE: AtomicBool = AtomicBool::new(false);
+
+// Mount points opened so far, or None if they failed to open.
+static MOUNT_FDS: OnceLock<Mutex<HashMap<PathString, Option<Arc<File>>>>> = OnceLock::new();
+
+/// Opens the file of `handle` through the mount at `mount_point`. Mount points are opened once
+/// per process.
+pub(crate) fn open_by_handle_at_mount(mount_point: &str, handle: &FileHandle) -> io::Result<File> {
+    if HANDLES_UNAVAILABLE.load(Ordering::Relaxed) {
+        return Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP));
+    }
+    let mount = MOUNT_FDS
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap()
+        .entry(mount_point.to_owned())
+        .or_insert_with(|| File::open(mount_point).ok().map(Arc::new))
+        .clone()
+        .ok_or_else(|| io::Error::from_raw_os_error(libc::ENOENT))?;
+    let result = open_by_handle(&mount, handle);
+    if let Err(e) = &result {
+        if matches!(e.raw_os_error(), Some(libc::EPERM) | Some(libc::ENOSYS)) {
+            HANDLES_UNAVAILABLE.store(true, Ordering::Relaxed);
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tracer::tests::setup_test_dir;
+    use std::io::Read;
+
+    #[test]
+    fn test_parse_mount_points() {
+        let contents = "22 1 253:0 / / rw,relatime shared:1 - ext4 /dev/dm-0 rw\n\
+                        35 22 253:1 / /data\\040dir rw shared:2 - f2fs /dev/dm-1 rw\n\
+                        garbage\n";
+        let mount_points = parse_mount_points(contents);
+        assert_eq!(mount_points.len(), 2);
+        assert_eq!(mount_points[&22], "/");
+        assert_eq!(mount_points[&35], "/data dir");
+    }
+
+    #[test]
+    fn test_unescape_mountinfo_path() {
+        assert_eq!(unescape_mountinfo_path("/a\\011b\\134c"), "/a\tb\\c");
+        assert_eq!(unescape_mountinfo_path("/trailing\\"), "/trailing\\");
+        assert_eq!(unescape_mountinfo_path("/plain"), "/plain");
+    }
+
+    #[test]
+    fn test_open_by_handle_after_rename() {
+        let dir = setup_test_dir();
+        let path = dir.join("handle");
+        fs::write(&path, b"hello").unwrap();
+        let handle = match name_to_handle(path.to_str().unwrap()) {
+            Ok(handle) => handle,
+            // Not all filesystems support file handles.
+            Err(_) => return,
+        };
+        let renamed = dir.join("renamed");
+        fs::rename(&path, &renamed).unwrap();
+
+        let mount = File::open(&dir).unwrap();
+        match open_by_handle(&mount, &handle) {
+            Ok(mut file) => {
+                let mut contents = String::new();
+                file.read_to_string(&mut contents).unwrap();
+                assert_eq!(contents, "hello");
+            }
+            // Opening by handle needs CAP_DAC_READ_SEARCH.
+            Err(e) => assert_eq!(e.raw_os_error(), Some(libc::EPERM), "{:?}", e),
+        }
+    }
+}
@@ -0,0 +1,99 @@
+// Copyright (C) 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Digests that authenticate records files.
+//!
+//! The CRC32 of the header catches corruption only. Replay runs as root and reads whatever
+//! paths a records file names, so a records file planted in its place must not replay. A
+//! SHA-256 digest catches corruption that a CRC32 may miss. An HMAC-SHA256 keyed with a device
+//! specific secret catches records files written by anyone without the secret.
+
+use std::fs;
+use std::path::Path;
+
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::error::Error;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Digest that covers a records file on top of its CRC32.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DigestAlgorithm {
+    /// The CRC32 only.
+    #[default]
+    Crc32,
+    /// SHA-256 of the records file.
+    Sha256,
+    /// HMAC-SHA256 of the records file keyed with a device specific secret.
+    HmacSha256,
+}
+
+impl DigestAlgorithm {
+    pub(crate) fn is_crc32(&self) -> bool {
+        *self == Self::Crc32
+    }
+}
+
+/// Returns the SHA-256 of `bytes`.
+pub(crate) fn sha256(bytes: &[u8]) -> Vec<u8> {
+    Sha256::digest(bytes).to_vec()
+}
+
+fn new_mac(key: &[u8]) -> Result<HmacSha256, Error> {
+    HmacSha256::new_from_slice(key).map_err(|e| Error::Integrity { error: e.to_string() })
+}
+
+/// Returns the HMAC-SHA256 of `bytes` keyed with `key`.
+pub(crate) fn hmac_sha256(key: &[u8], bytes: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut mac = new_mac(key)?;
+    mac.update(bytes);
+    Ok(mac.finalize().into_bytes().to_vec())
+}
+
+/// Checks, in constant time, that `expected` is the HMAC-SHA256 of `bytes` keyed with `key`.
+pub(crate) fn verify_hmac_sha256(key: &[u8], bytes: &[u8], expected: &[u8]) -> Result<(), Error> {
+    let mut mac = new_mac(key)?;
+    mac.update(bytes);
+    mac.verify_slice(expected)
+        .map_err(|_| Error::Integrity { error: "MAC does not match".to_owned() })
+}
+
+/// Reads the secret that keys the MAC of records files from `path`.
+pub fn read_mac_key(path: &Path) -> Result<Vec<u8>, Error> {
+    let key = fs::read(path)
+        .map_err(|source| Error::Open { source, path: path.to_str().unwrap().to_owned() })?;
+    if key.is_empty() {
+        return Err(Error::Integrity { error: format!("MAC key {} is empty", path.display()) });
+    }
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hmac_sha256() {
+        let mac = hmac_sha256(b"key", b"records").unwrap();
+        assert_eq!(mac.len(), 32);
+        verify_hmac_sha256(b"key", b"records", &mac).unwrap();
+        assert!(verify_hmac_sha256(b"other key", b"records", &mac).is_err());
+        assert!(verify_hmac_sha256(b"key", b"other records", &mac).is_err());
+        assert!(verify_hmac_sha256(b"key", b"records", &mac[..16]).is_err());
+    }
+}
@@ -0,0 +1,246 @@
+// Copyright (C) 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Compressed encoding of records files.
+//!
+//! Records files live in /metadata, which is small, and most of a records file is paths that
+//! share a few directory prefixes. Compressed records files store each directory prefix once,
+//! refer to it by index from the paths, and compress the result.
+//!
+//! Paths are only interned in compressed records files. Plain records files keep the layout
+//! that readers of older minor versions expect.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use serde::de::{self, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::error::Error;
+use crate::format::{InodeInfo, PathString, RecordsFileInner};
+
+// Records files are written once at record time and read at every boot, so compression favors
+// size over speed.
+const ZSTD_LEVEL: i32 = 19;
+
+// Upper bound of the size of decompressed records. Records files of a full boot decompress to a
+// few MiB; anything larger is corrupt or malicious and is not allowed to exhaust memory.
+const MAX_DECOMPRESSED_SIZE: usize = 64 << 20;
+
+/// Compression of the records of a records file.
+///
+/// Only zstd is offered. lz4 decompresses faster, but records files are read once per boot
+/// and zstd makes them much smaller, which matters more in /metadata.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    /// Records are stored as is.
+    #[default]
+    None,
+    /// Path prefixes are interned and records are compressed with zstd.
+    Zstd,
+}
+
+impl Compression {
+    pub(crate) fn is_none(&self) -> bool {
+        *self == Self::None
+    }
+}
+
+/// Bytes serialized as a byte string rather than as a sequence of integers.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub(crate) struct Bytes(pub(crate) Vec<u8>);
+
+impl Serialize for Bytes {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_bytes(&self.0)
+    }
+}
+
+struct BytesVisitor;
+
+impl<'de> Visitor<'de> for BytesVisitor {
+    type Value = Bytes;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter.write_str("bytes")
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Bytes, E> {
+        Ok(Bytes(v.to_vec()))
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Bytes, E> {
+        Ok(Bytes(v))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Bytes, A::Error> {
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(byte) = seq.next_element()? {
+            bytes.push(byte);
+        }
+        Ok(Bytes(bytes))
+    }
+}
+
+impl<'de> Deserialize<'de> for Bytes {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_byte_buf(BytesVisitor)
+    }
+}
+
+// Records with each path stored as `<prefix index>:<file name>`.
+#[derive(Debug, Deserialize, Serialize)]
+struct InternedInner {
+    path_prefixes: Vec<PathString>,
+    inner: RecordsFileInner,
+}
+
+// Splits `path` into its directory, with the trailing slash, and its file name.
+fn split_path(path: &str) -> (&str, &str) {
+    match path.rfind('/') {
+        Some(pos) => path.split_at(pos + 1),
+        None => ("", path),
+    }
+}
+
+fn intern_paths(inner: &RecordsFileInner) -> InternedInner {
+    let mut inner = inner.clone();
+    let mut path_prefixes = vec![];
+    let mut indexes: HashMap<String, usize> = HashMap::new();
+    // Files are visited in id order so that the same records always encode the same.
+    let mut infos: Vec<(u64, &mut InodeInfo)> = inner
+        .inode_map
+        .map
+        .iter_mut()
+        .map(|(id, info)| (id.0, info))
+        .chain(inner.directories.map.iter_mut().map(|(id, info)| (id.0, info)))
+        .collect();
+    infos.sort_by_key(|(id, _)| *id);
+    for (_, info) in infos {
+        for path in &mut info.paths {
+            let (prefix, name) = split_path(path);
+            let index = *indexes.entry(prefix.to_owned()).or_insert_with(|| {
+                path_prefixes.push(prefix.to_owned());
+                path_prefixes.len() - 1
+            });
+            *path = format!("{index}:{name}");
+        }
+    }
+    InternedInner { path_prefixes, inner }
+}
+
+fn expand_info_paths(info: &mut InodeInfo, path_prefixes: &[PathString]) -> Result<(), String> {
+    for path in &mut info.paths {
+        let (index, name) =
+            path.split_once(':').ok_or_else(|| format!("malformed interned path {path}"))?;
+        let prefix = index
+            .parse::<usize>()
+            .ok()
+            .and_then(|index| path_prefixes.get(index))
+            .ok_or_else(|| format!("unknown path prefix in {path}"))?;
+        *path = format!("{prefix}{name}");
+    }
+    Ok(())
+}
+
+fn expand_paths(interned: InternedInner) -> Result<RecordsFileInner, String> {
+    let InternedInner { path_prefixes, mut inner } = interned;
+    let infos = inner.inode_map.map.values_mut().chain(inner.directories.map.values_mut());
+    for info in infos {
+        expand_info_paths(info, &path_prefixes)?;
+    }
+    Ok(inner)
+}
+
+/// Returns `inner` with its paths interned and compressed with zstd.
+pub(crate) fn compress_inner(inner: &RecordsFileInner) -> Result<Vec<u8>, Error> {
+    let serialized = serde_cbor::to_vec(&intern_paths(inner))
+        .map_err(|e| Error::Serialize { error: e.to_string() })?;
+    zstd::encode_all(serialized.as_slice(), ZSTD_LEVEL)
+        .map_err(|e| Error::Serialize { error: format!("zstd compression failed: {e}") })
+}
+
+/// Decodes records encoded by `compress_inner`.
+pub(crate) fn decompress_inner(bytes: &[u8]) -> Result<RecordsFileInner, Error> {
+    let serialized = zstd::bulk::Decompressor::new()
+        .and_then(|mut decompressor| decompressor.decompress(bytes, MAX_DECOMPRESSED_SIZE))
+        .map_err(|e| Error::Deserialize { error: format!("zstd decompression failed: {e}") })?;
+    let interned: InternedInner = serde_cbor::from_slice(&serialized)
+        .map_err(|e| Error::Deserialize { error: e.to_string() })?;
+    expand_paths(interned).map_err(|error| Error::Deserialize { error })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::FileId;
+
+    #[test]
+    fn test_split_path() {
+        assert_eq!(split_path("/system/lib64/libc.so"), ("/system/lib64/", "libc.so"));
+        assert_eq!(split_path("/init"), ("/", "init"));
+        assert_eq!(split_path("relative"), ("", "relative"));
+        assert_eq!(split_path("/system/"), ("/system/", ""));
+    }
+
+    #[test]
+    fn test_intern_paths() {
+        let mut inner = RecordsFileInner::default();
+        inner.inode_map.insert(
+            FileId(0),
+            InodeInfo::new(
+                0,
+                1,
+                vec!["/system/lib/a.so".to_owned(), "/vendor/lib/a.so".to_owned()],
+                2,
+            ),
+        );
+        inner
+            .inode_map
+            .insert(FileId(1), InodeInfo::new(1, 1, vec!["/system/lib/b:c.so".to_owned()], 2));
+        inner
+            .directories
+            .insert(FileId(2), InodeInfo::new(2, 1, vec!["/system/lib".to_owned()], 2));
+
+        let interned = intern_paths(&inner);
+        assert_eq!(interned.path_prefixes.len(), 3);
+        let mut paths: Vec<&String> = interned.inner.inode_map[&FileId(0)].paths.iter().collect();
+        paths.extend(&interned.inner.inode_map[&FileId(1)].paths);
+        assert!(paths.iter().all(|path| !path.contains('/')), "{:?}", paths);
+
+        assert_eq!(expand_paths(interned).unwrap(), inner);
+    }
+
+    #[test]
+    fn test_decompress_too_large() {
+        let bytes = zstd::encode_all(vec![0u8; MAX_DECOMPRESSED_SIZE + 1].as_slice(), 1).unwrap();
+        let e = decompress_inner(&bytes).unwrap_err();
+        assert!(matches!(e, Error::Deserialize { .. }), "{:?}", e);
+    }
+
+    #[test]
+    fn test_expand_malformed_paths() {
+        let mut inner = RecordsFileInner::default();
+        inner.inode_map.insert(FileId(0), InodeInfo::new(0, 1, vec!["7:a.so".to_owned()], 2));
+        let interned = InternedInner { path_prefixes: vec!["/system/".to_owned()], inner };
+        assert_eq!(expand_paths(interned).unwrap_err(), "unknown path prefix in 7:a.so");
+    }
+}
@@ -0,0 +1,150 @@
+// Copyright (C) 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Builds records files from their human readable forms.
+//!
+//! Records files can be edited by hand, generated by other tools or written as test fixtures in
+//! the json printed by `dump`, or as csv with the columns printed by `dump` plus the device and
+//! inode number of each file.
+
+use std::collections::HashMap;
+use std::io::Read;
+
+use serde::Deserialize;
+
+use crate::format::{
+    DeviceNumber, FileId, FsInfo, InodeInfo, InodeNumber, PathString, Record, RecordsFile,
+    RecordsFileInner,
+};
+use crate::tracer::page_size;
+use crate::Error;
+
+// The part of the json printed by `dump` that is imported. The header is left out as the
+// records file gets a new one.
+#[derive(Deserialize)]
+struct DumpedRecordsFile {
+    inner: RecordsFileInner,
+}
+
+// A row of csv.
+#[derive(Deserialize)]
+struct CsvRecord {
+    timestamp: u64,
+    file: PathString,
+    offset: u64,
+    length: u64,
+    file_size: u64,
+    device: DeviceNumber,
+    inode: InodeNumber,
+}
+
+// Orders the records of `inner` by time, checks it and returns it in a records file with a new
+// header.
+fn records_file(mut inner: RecordsFileInner) -> Result<RecordsFile, Error> {
+    inner.records.sort_by_key(|record| record.timestamp);
+    let rf = RecordsFile { inner, ..Default::default() };
+    rf.check()?;
+    Ok(rf)
+}
+
+/// Builds a records file from the json printed by `dump`.
+pub fn import_json(reader: &mut dyn Read) -> Result<RecordsFile, Error> {
+    let dumped: DumpedRecordsFile =
+        serde_json::from_reader(reader).map_err(|e| Error::Deserialize { error: e.to_string() })?;
+    records_file(dumped.inner)
+}
+
+/// Builds a records file from csv with the columns `timestamp`, `file`, `offset`, `length`,
+/// `file_size`, `device` and `inode`, one row per record.
+///
+/// Rows with the same device and inode number are records of the same file, whose paths are
+/// the paths of the rows. Filesystems get the page size as block size.
+pub fn import_csv(reader: &mut dyn Read) -> Result<RecordsFile, Error> {
+    let block_size = page_size()? as u64;
+    let mut inner = RecordsFileInner::default();
+    let mut ids: HashMap<(DeviceNumber, InodeNumber), FileId> = HashMap::new();
+    for row in csv::Reader::from_reader(reader).deserialize() {
+        let row: CsvRecord = row.map_err(|e| Error::Deserialize { error: e.to_string() })?;
+        let next_id = FileId(ids.len() as u64);
+        let id = ids.entry((row.device, row.inode)).or_insert(next_id).clone();
+        match inner.inode_map.get_mut(&id) {
+            Some(info) => {
+                if !info.paths.contains(&row.file) {
+                    info.paths.push(row.file);
+                }
+            }
+            None => {
+                inner.inode_map.insert(
+                    id.clone(),
+                    InodeInfo::new(row.inode, row.file_size, vec![row.file], row.device),
+                );
+            }
+        }
+        inner.filesystems.entry(row.device).or_insert(FsInfo { block_size });
+        inner.records.push(Record {
+            file_id: id,
+            offset: row.offset,
+            length: row.length,
+            timestamp: row.timestamp,
+        });
+    }
+    records_file(inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_import_json() {
+        let mut rf = RecordsFile::default();
+        rf.insert_or_update_inode_info(
+            FileId(3),
+            InodeInfo::new(30, 8192, vec!["/system/a".to_owned(), "/system/b".to_owned()], 7),
+        );
+        rf.inner.filesystems.insert(7, FsInfo { block_size: 4096 });
+        rf.insert_record(Record { file_id: FileId(3), offset: 0, length: 4096, timestamp: 10 });
+        rf.insert_record(Record { file_id: FileId(3), offset: 4096, length: 4096, timestamp: 20 });
+
+        let json = serde_json::to_string_pretty(&rf.plain()).unwrap();
+        let imported = import_json(&mut json.as_bytes()).unwrap();
+        assert_eq!(imported.inner, rf.inner);
+
+        // Records of files that are not in the records file fail the check.
+        let mut broken = rf.clone();
+        broken.inner.records[0].file_id = FileId(4);
+        let json = serde_json::to_string(&broken.plain()).unwrap();
+        assert!(import_json(&mut json.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_import_csv() {
+        let csv = "timestamp,file,offset,length,file_size,device,inode\n\
+                   20,/system/bin/init,4096,4096,8192,1,10\n\
+                   10,/system/bin/init,0,4096,8192,1,10\n\
+                   30,/system/lib/libc.so,0,4096,4096,1,11\n\
+                   40,/system/lib/libc_link.so,0,4096,4096,1,11\n";
+        let rf = import_csv(&mut csv.as_bytes()).unwrap();
+        assert_eq!(rf.inner.inode_map.len(), 2);
+        assert_eq!(rf.inner.records.len(), 4);
+        assert_eq!(rf.inner.records[0].timestamp, 10);
+        let libc = &rf.inner.inode_map[&FileId(1)];
+        assert_eq!(libc.paths, vec!["/system/lib/libc.so", "/system/lib/libc_link.so"]);
+        assert_eq!(libc.inode_number, 11);
+        assert!(rf.inner.filesystems.contains_key(&1));
+
+        let missing_column = "timestamp,file,offset,length,file_size\n10,/init,0,4096,8192\n";
+        assert!(import_csv(&mut missing_column.as_bytes()).is_err());
+    }
+}
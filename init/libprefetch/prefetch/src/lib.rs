@@ -15,11 +15,19 @@
 //! A library to prefetch files on the file system to optimize startup times
 //!
 
+mod analyze;
 mod args;
+mod diff;
 mod error;
+mod filter;
 mod format;
+mod import;
+mod merge;
 mod replay;
+mod timeline;
 mod tracer;
+mod trim;
+mod verify;
 #[cfg(target_os = "android")]
 mod arch {
     pub mod android;
@@ -30,6 +38,7 @@ use std::fs::OpenOptions;
 use std::io;
 use std::io::Write;
 use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
 use std::string::ToString;
 use std::thread;
 use std::time::Duration;
@@ -39,18 +48,45 @@ use log::Level;
 #[cfg(target_os = "linux")]
 use log::LevelFilter;
 
+pub use analyze::{
+    analyze_records_file, AnalyzeReport, Duplicates, FileBytes, Fragmentation, MountBytes,
+    TimeBucket,
+};
 pub use args::args_from_env;
-use args::OutputFormat;
 pub use args::ReplayArgs;
-pub use args::{DumpArgs, MainArgs, RecordArgs, SubCommands};
+pub use args::{AdditionalFilesPosition, ConfigFile, PathPriority};
+pub use args::{
+    AnalyzeArgs, DiffArgs, DumpArgs, ImportArgs, MainArgs, MergeArgs, RecordArgs, SubCommands,
+    TrimArgs, UpgradeArgs, VerifyArgs,
+};
+use args::{AnalyzeFormat, ImportFormat, OutputFormat};
+pub use args::{IoPriorityClass, ReplayEngine, ReplayStrategy};
+pub use diff::{diff_records_files, Change, DiffReport, FileDiff};
 pub use error::Error;
+pub use filter::{
+    file_summaries, write_file_summaries_csv, write_file_summaries_text, DumpFilter, FileSummary,
+};
+use format::read_mac_key;
+pub use format::Compression;
+pub use format::DigestAlgorithm;
 pub use format::FileId;
 pub use format::InodeInfo;
 pub use format::Record;
 pub use format::RecordsFile;
+pub use import::{import_csv, import_json};
 use log::info;
+pub use merge::merge_records_files;
+pub use replay::BenchmarkResult;
 pub use replay::Replay;
+pub use replay::{
+    CancelReason, FileErrors, MetadataReport, ReplayReport, ResidencyReport, StaleFile,
+    WorkerReport,
+};
+pub use replay::{ReplayBuilder, ReplayProgress};
+pub use timeline::{write_chrome_trace, write_perfetto_trace};
 pub use tracer::nanoseconds_since_boot;
+pub use trim::{trim_records_file, TrimPolicy};
+pub use verify::{verify_records_file, FileMismatches, Mismatch, VerifyReport};
 
 #[cfg(target_os = "android")]
 pub use arch::android::*;
@@ -103,6 +139,13 @@ pub fn record(args: &RecordArgs) -> Result<(), Error> {
     thd.join()
         .map_err(|_| Error::ThreadPool { error: "Failed to join timeout thread".to_string() })?;
 
+    if let Some(max_bytes) = args.max_bytes {
+        let removed = trim_records_file(&mut rf, max_bytes, args.trim_policy)?;
+        info!("trimmed {removed} bytes to fit {max_bytes} bytes");
+    }
+    rf.set_compression(args.compression);
+    rf.set_digest_algorithm(args.digest.unwrap_or_default());
+    let serialized = serialize_records_file(&mut rf, args.mac_key_path.as_deref())?;
     let path = args.get_pack_path();
 
     let mut out_file = OpenOptions::new()
@@ -117,7 +160,7 @@ pub fn record(args: &RecordArgs) -> Result<(), Error> {
 
     // Write the record file
     out_file
-        .write_all(&rf.add_checksum_and_serialize()?)
+        .write_all(&serialized)
         .map_err(|source| Error::Write { path: path.to_str().unwrap().to_owned(), source })?;
     out_file.sync_all()?;
 
@@ -136,25 +179,250 @@ pub fn replay(args: &ReplayArgs) -> Result<(), Error> {
         return Ok(());
     }
 
+    if args.benchmark {
+        info!("Starting replay benchmark.");
+        let results = Replay::benchmark(args)?;
+        println!(
+            "{:#}",
+            serde_json::to_string_pretty(&results)
+                .map_err(|e| Error::Serialize { error: e.to_string() })?
+        );
+        return Ok(());
+    }
+
     info!("Starting replay.");
     let replay = Replay::new(args)?;
     replay.replay()
 }
 
+// Reads and checks the records file at `path`.
+fn read_records_file(path: &Path) -> Result<RecordsFile, Error> {
+    let reader = File::open(path)
+        .map_err(|source| Error::Open { source, path: path.to_str().unwrap().to_string() })?;
+    serde_cbor::from_reader(reader).map_err(|e| Error::Deserialize { error: e.to_string() })
+}
+
+// Serializes `rf` with a MAC keyed with the secret at `mac_key_path`, if any.
+fn serialize_records_file(
+    rf: &mut RecordsFile,
+    mac_key_path: Option<&Path>,
+) -> Result<Vec<u8>, Error> {
+    match mac_key_path {
+        Some(key_path) => rf.add_mac_and_serialize(&read_mac_key(key_path)?),
+        None => rf.add_checksum_and_serialize(),
+    }
+}
+
+// Checks the records file at `path` before it is rewritten with a MAC keyed with the secret at
+// `mac_key_path`. A records file with a MAC is only rewritten by holders of the key it was
+// written with, and one without a MAC only gets one if `sign` is set.
+fn check_input_mac(
+    rf: &RecordsFile,
+    path: &Path,
+    mac_key_path: Option<&Path>,
+    sign: bool,
+) -> Result<(), Error> {
+    let key_path = match mac_key_path {
+        Some(key_path) => key_path,
+        None => return Ok(()),
+    };
+    if rf.has_mac() {
+        rf.verify_mac(&read_mac_key(key_path)?)
+    } else if sign {
+        Ok(())
+    } else {
+        Err(Error::Integrity {
+            error: format!("{} has no MAC, pass --sign to add one", path.display()),
+        })
+    }
+}
+
+// Writes `serialized` to `path` through a temporary file so that the records file at `path` is
+// never left half written.
+fn write_records_file(path: &Path, serialized: &[u8]) -> Result<(), Error> {
+    let tmp_path = PathBuf::from(format!("{}.tmp", path.display()));
+    let mut out_file =
+        OpenOptions::new().write(true).create(true).truncate(true).open(&tmp_path).map_err(
+            |source| Error::Create { source, path: tmp_path.to_str().unwrap().to_owned() },
+        )?;
+    std::fs::set_permissions(&tmp_path, std::fs::Permissions::from_mode(0o644))
+        .map_err(|source| Error::Create { source, path: tmp_path.to_str().unwrap().to_owned() })?;
+    out_file
+        .write_all(serialized)
+        .map_err(|source| Error::Write { path: tmp_path.to_str().unwrap().to_owned(), source })?;
+    out_file.sync_all()?;
+    std::fs::rename(&tmp_path, path)
+        .map_err(|source| Error::Write { path: path.to_str().unwrap().to_owned(), source })
+}
+
 /// Dumps prefetch data in the human readable form
 pub fn dump(args: &DumpArgs) -> Result<(), Error> {
-    let reader = File::open(&args.path)
-        .map_err(|source| Error::Open { source, path: args.path.to_str().unwrap().to_string() })?;
-    let rf: RecordsFile =
-        serde_cbor::from_reader(reader).map_err(|e| Error::Deserialize { error: e.to_string() })?;
+    let rf = DumpFilter::from_args(args)?.apply(&read_records_file(&args.path)?);
+    if args.files_only {
+        let summaries = file_summaries(&rf);
+        match args.format {
+            OutputFormat::Json => println!(
+                "{:#}",
+                serde_json::to_string_pretty(&summaries)
+                    .map_err(|e| Error::Serialize { error: e.to_string() })?
+            ),
+            OutputFormat::Csv => write_file_summaries_csv(&summaries, &mut io::stdout())?,
+            // Files are not a timeline, which is refused when args are checked.
+            OutputFormat::Text | OutputFormat::Perfetto | OutputFormat::Chrome => {
+                write_file_summaries_text(&summaries, &mut io::stdout())?
+            }
+        }
+        return Ok(());
+    }
     match args.format {
         OutputFormat::Json => println!(
             "{:#}",
-            serde_json::to_string_pretty(&rf)
+            serde_json::to_string_pretty(&rf.plain())
                 .map_err(|e| Error::Serialize { error: e.to_string() })?
         ),
         OutputFormat::Csv => rf.serialize_records_to_csv(&mut io::stdout())?,
+        OutputFormat::Text => rf.serialize_records_to_text(&mut io::stdout())?,
+        OutputFormat::Perfetto => write_perfetto_trace(&rf, &mut io::stdout())?,
+        OutputFormat::Chrome => write_chrome_trace(&rf, &mut io::stdout())?,
+    }
+    Ok(())
+}
+
+/// Checks the records file against the filesystem and prints the mismatches as json. Returns
+/// `Error::FilesChanged` if any file changed.
+pub fn verify(args: &VerifyArgs) -> Result<(), Error> {
+    let rf = read_records_file(&args.path)?;
+    let report = verify_records_file(&rf)?;
+    println!(
+        "{:#}",
+        serde_json::to_string_pretty(&report)
+            .map_err(|e| Error::Serialize { error: e.to_string() })?
+    );
+    if !report.is_ok() {
+        return Err(Error::FilesChanged {
+            files_checked: report.files_checked,
+            files_mismatched: report.files_mismatched,
+        });
+    }
+    Ok(())
+}
+
+/// Rewrites a records file of an older format version in the current version
+pub fn upgrade(args: &UpgradeArgs) -> Result<(), Error> {
+    let mut rf = read_records_file(&args.path)?;
+    // The MAC covers the header, so it is checked before the header is upgraded.
+    check_input_mac(&rf, &args.path, args.mac_key_path.as_deref(), args.sign)?;
+    let output_path = args.output_path.as_ref().unwrap_or(&args.path);
+    if !rf.needs_upgrade() && output_path == &args.path && args.compression.is_none() {
+        info!("{} is already in the current format", args.path.display());
+        return Ok(());
     }
+    rf.upgrade();
+    if let Some(compression) = args.compression {
+        rf.set_compression(compression);
+    }
+    let serialized = serialize_records_file(&mut rf, args.mac_key_path.as_deref())?;
+    write_records_file(output_path, &serialized)?;
+    info!("upgraded {} to {}", args.path.display(), output_path.display());
+    Ok(())
+}
+
+/// Cuts a records file down to a byte budget
+pub fn trim(args: &TrimArgs) -> Result<(), Error> {
+    let mut rf = read_records_file(&args.path)?;
+    check_input_mac(&rf, &args.path, args.mac_key_path.as_deref(), args.sign)?;
+    let removed = trim_records_file(&mut rf, args.max_bytes, args.policy)?;
+    let output_path = args.output_path.as_ref().unwrap_or(&args.path);
+    let serialized = serialize_records_file(&mut rf, args.mac_key_path.as_deref())?;
+    write_records_file(output_path, &serialized)?;
+    info!(
+        "trimmed {removed} bytes from {} into {} with {} files",
+        args.path.display(),
+        output_path.display(),
+        rf.inner.inode_map.len()
+    );
+    Ok(())
+}
+
+/// Merges the records files of several boots into one
+pub fn merge(args: &MergeArgs) -> Result<(), Error> {
+    let key = args.mac_key_path.as_deref().map(read_mac_key).transpose()?;
+    let mut records_files = vec![];
+    for path in &args.path {
+        let rf = read_records_file(path)?;
+        if let Some(key) = &key {
+            rf.verify_mac(key)?;
+        }
+        records_files.push(rf);
+    }
+    let mut rf = merge_records_files(&records_files, args.get_min_count())?;
+    rf.set_compression(args.compression);
+    let serialized = serialize_records_file(&mut rf, args.mac_key_path.as_deref())?;
+    write_records_file(&args.output_path, &serialized)?;
+    info!(
+        "merged {} records files into {} with {} files",
+        records_files.len(),
+        args.output_path.display(),
+        rf.inner.inode_map.len()
+    );
+    Ok(())
+}
+
+/// Compares two records files and prints the differences in the human readable form
+pub fn diff(args: &DiffArgs) -> Result<(), Error> {
+    let old = read_records_file(&args.old_path)?;
+    let new = read_records_file(&args.new_path)?;
+    let report = diff_records_files(&old, &new);
+    match args.format {
+        OutputFormat::Json => println!(
+            "{:#}",
+            serde_json::to_string_pretty(&report)
+                .map_err(|e| Error::Serialize { error: e.to_string() })?
+        ),
+        OutputFormat::Csv => report.write_csv(&mut io::stdout())?,
+        OutputFormat::Text | OutputFormat::Perfetto | OutputFormat::Chrome => {
+            print!("{report}")
+        }
+    }
+    info!(
+        "{} files added, {} files removed, {} bytes added, {} bytes removed",
+        report.files_added, report.files_removed, report.bytes_added, report.bytes_removed
+    );
+    Ok(())
+}
+
+/// Prints statistics of a records file
+pub fn analyze(args: &AnalyzeArgs) -> Result<(), Error> {
+    let rf = read_records_file(&args.path)?;
+    let report = analyze_records_file(&rf, args.top, args.bucket_ms);
+    match args.format {
+        AnalyzeFormat::Json => println!(
+            "{:#}",
+            serde_json::to_string_pretty(&report)
+                .map_err(|e| Error::Serialize { error: e.to_string() })?
+        ),
+        AnalyzeFormat::Text => print!("{report}"),
+    }
+    Ok(())
+}
+
+/// Builds a records file from its human readable form and writes it with a new checksum
+pub fn import(args: &ImportArgs) -> Result<(), Error> {
+    let mut reader = File::open(&args.path)
+        .map_err(|source| Error::Open { source, path: args.path.to_str().unwrap().to_string() })?;
+    let mut rf = match args.format {
+        ImportFormat::Json => import_json(&mut reader)?,
+        ImportFormat::Csv => import_csv(&mut reader)?,
+    };
+    rf.set_compression(args.compression);
+    let serialized = serialize_records_file(&mut rf, args.mac_key_path.as_deref())?;
+    write_records_file(&args.output_path, &serialized)?;
+    info!(
+        "imported {} files and {} records into {}",
+        rf.inner.inode_map.len(),
+        rf.inner.records.len(),
+        args.output_path.display()
+    );
     Ok(())
 }
 
@@ -17,11 +17,18 @@
 
 use log::error;
 
+use prefetch_rs::analyze;
 use prefetch_rs::args_from_env;
+use prefetch_rs::diff;
 use prefetch_rs::dump;
+use prefetch_rs::import;
 use prefetch_rs::init_logging;
+use prefetch_rs::merge;
 use prefetch_rs::record;
 use prefetch_rs::replay;
+use prefetch_rs::trim;
+use prefetch_rs::upgrade;
+use prefetch_rs::verify;
 use prefetch_rs::LogLevel;
 use prefetch_rs::MainArgs;
 use prefetch_rs::SubCommands;
@@ -33,6 +40,13 @@ fn main() {
         SubCommands::Record(args) => record(args),
         SubCommands::Replay(args) => replay(args),
         SubCommands::Dump(args) => dump(args),
+        SubCommands::Verify(args) => verify(args),
+        SubCommands::Upgrade(args) => upgrade(args),
+        SubCommands::Merge(args) => merge(args),
+        SubCommands::Diff(args) => diff(args),
+        SubCommands::Analyze(args) => analyze(args),
+        SubCommands::Import(args) => import(args),
+        SubCommands::Trim(args) => trim(args),
     };
 
     if let Err(err) = ret {
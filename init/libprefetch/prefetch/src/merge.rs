@@ -0,0 +1,345 @@
+// Copyright (C) 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Merges the records files of several boots into one.
+//!
+//! The trace of a single boot has reads that only happen on that boot, like first boot setup or
+//! crash dumps. Keeping only the parts of files that most boots read leaves them out.
+
+use std::collections::HashMap;
+
+use crate::format::{
+    coalesce_records, DeviceNumber, FileId, InodeInfo, InodeNumber, MountId, PathString, Record,
+    RecordsFile, SerializableHashMap,
+};
+use crate::Error;
+
+// A file, or directory, of the merged records file.
+struct MergedFile {
+    // Inode info of the last records file the file is in, with the paths of all of them.
+    info: InodeInfo,
+
+    // Mount point of the file handle of `info`.
+    mount_point: Option<PathString>,
+
+    // Records of the file in each records file.
+    records: Vec<Vec<Record>>,
+}
+
+// Files of several records files, unified by device and inode number or by path.
+struct MergedFiles {
+    files: Vec<MergedFile>,
+    by_inode: HashMap<(DeviceNumber, InodeNumber), usize>,
+    by_path: HashMap<PathString, usize>,
+}
+
+impl MergedFiles {
+    fn new() -> Self {
+        Self { files: vec![], by_inode: HashMap::new(), by_path: HashMap::new() }
+    }
+
+    // Adds `info`, found in one of `inputs` records files, and returns the index of its file.
+    fn add(&mut self, info: &InodeInfo, mount_point: Option<&PathString>, inputs: usize) -> usize {
+        let found = self
+            .by_inode
+            .get(&(info.device_number, info.inode_number))
+            .or_else(|| info.paths.iter().find_map(|path| self.by_path.get(path)))
+            .copied();
+        let index = match found {
+            Some(index) => {
+                let file = &mut self.files[index];
+                let mut paths = info.paths.clone();
+                for path in &file.info.paths {
+                    if !paths.contains(path) {
+                        paths.push(path.clone());
+                    }
+                }
+                file.info = InodeInfo { paths, ..info.clone() };
+                file.mount_point = mount_point.cloned();
+                index
+            }
+            None => {
+                self.files.push(MergedFile {
+                    info: info.clone(),
+                    mount_point: mount_point.cloned(),
+                    records: vec![vec![]; inputs],
+                });
+                self.files.len() - 1
+            }
+        };
+        self.by_inode.insert((info.device_number, info.inode_number), index);
+        for path in &info.paths {
+            self.by_path.entry(path.clone()).or_insert(index);
+        }
+        index
+    }
+}
+
+// Returns the least of the middle two of `timestamps`.
+fn median(mut timestamps: Vec<u64>) -> u64 {
+    timestamps.sort_unstable();
+    timestamps[(timestamps.len() - 1) / 2]
+}
+
+// Returns the ranges of a file read in at least `min_count` of `inputs`, the records of the file
+// in each records file. Each range has the median of the timestamps of the records files that
+// read it. Adjacent ranges are coalesced and keep the least timestamp.
+fn consensus_records(inputs: &[Vec<Record>], min_count: usize) -> Vec<Record> {
+    let inputs: Vec<Vec<Record>> = inputs
+        .iter()
+        .map(|records| {
+            let mut records = records.clone();
+            records.sort_by_key(|r| r.offset);
+            coalesce_records(records, false)
+        })
+        .collect();
+    let mut boundaries: Vec<u64> =
+        inputs.iter().flatten().flat_map(|r| [r.offset, r.offset + r.length]).collect();
+    boundaries.sort_unstable();
+    boundaries.dedup();
+
+    let mut next = vec![0; inputs.len()];
+    let mut consensus: Vec<Record> = vec![];
+    for range in boundaries.windows(2) {
+        let (start, end) = (range[0], range[1]);
+        let mut timestamps = vec![];
+        for (records, next) in inputs.iter().zip(next.iter_mut()) {
+            while *next < records.len() && records[*next].offset + records[*next].length <= start {
+                *next += 1;
+            }
+            if let Some(record) = records.get(*next) {
+                if record.offset <= start {
+                    timestamps.push(record.timestamp);
+                }
+            }
+        }
+        if timestamps.len() < min_count {
+            continue;
+        }
+        let timestamp = median(timestamps);
+        match consensus.last_mut() {
+            Some(last) if last.offset + last.length == start => {
+                last.length = end - last.offset;
+                last.timestamp = last.timestamp.min(timestamp);
+            }
+            _ => consensus.push(Record {
+                file_id: FileId(0),
+                offset: start,
+                length: end - start,
+                timestamp,
+            }),
+        }
+    }
+    consensus
+}
+
+// Moves the files of `merged` that have consensus records into `files`, with ids from
+// `next_id`, and the mount points of their handles into `mounts`. Returns the consensus records
+// in chronological order.
+fn take_consensus(
+    merged: MergedFiles,
+    min_count: usize,
+    next_id: &mut u64,
+    files: &mut SerializableHashMap<FileId, InodeInfo>,
+    mounts: &mut SerializableHashMap<MountId, PathString>,
+) -> Vec<Record> {
+    let mut records = vec![];
+    for file in merged.files {
+        let consensus = consensus_records(&file.records, min_count);
+        if consensus.is_empty() {
+            continue;
+        }
+        let id = FileId(*next_id);
+        *next_id += 1;
+        let mut info = file.info;
+        // Mount ids of different boots may name different mounts.
+        if let (Some(handle), Some(mount_point)) = (&info.handle, file.mount_point) {
+            if mounts.get(&handle.mount_id).is_none_or(|point| *point == mount_point) {
+                mounts.insert(handle.mount_id, mount_point);
+            } else {
+                info.handle = None;
+            }
+        } else {
+            info.handle = None;
+        }
+        files.insert(id.clone(), info);
+        records.extend(consensus.into_iter().map(|r| Record { file_id: id.clone(), ..r }));
+    }
+    records.sort_by(|a, b| {
+        (a.timestamp, &a.file_id, a.offset).cmp(&(b.timestamp, &b.file_id, b.offset))
+    });
+    records
+}
+
+/// Merges `records_files`, recorded on different boots, into one that keeps the parts of files
+/// read in at least `min_count` of them.
+///
+/// Files are the same file if they have the same device and inode number or share a path. The
+/// merged file has the inode info of the last records file it is in, with all of its paths.
+/// Each kept range has the median of the timestamps it was read at.
+pub fn merge_records_files(
+    records_files: &[RecordsFile],
+    min_count: usize,
+) -> Result<RecordsFile, Error> {
+    if min_count == 0 || min_count > records_files.len() {
+        return Err(Error::InvalidArgs {
+            arg_name: "min_count".to_owned(),
+            arg_value: min_count.to_string(),
+            error: format!("must be between 1 and {}", records_files.len()),
+        });
+    }
+
+    let inputs = records_files.len();
+    let mut files = MergedFiles::new();
+    let mut directories = MergedFiles::new();
+    let mut rf = RecordsFile::default();
+    for (input, input_rf) in records_files.iter().enumerate() {
+        let inner = &input_rf.inner;
+        for (device, fs_info) in inner.filesystems.iter() {
+            rf.inner.filesystems.insert(*device, fs_info.clone());
+        }
+        // Files are added in id order so that merged ids do not depend on hash map order.
+        let mut inode_map: Vec<_> = inner.inode_map.iter().collect();
+        inode_map.sort_by_key(|(id, _)| *id);
+        let mut indexes = HashMap::new();
+        for (id, info) in inode_map {
+            let mount_point =
+                info.handle.as_ref().and_then(|handle| inner.mounts.get(&handle.mount_id));
+            indexes.insert(id, files.add(info, mount_point, inputs));
+        }
+        for record in &inner.records {
+            if let Some(index) = indexes.get(&record.file_id) {
+                files.files[*index].records[input].push(record.clone());
+            }
+        }
+
+        let mut directories_by_id: Vec<_> = inner.directories.iter().collect();
+        directories_by_id.sort_by_key(|(id, _)| *id);
+        let mut indexes = HashMap::new();
+        for (id, info) in directories_by_id {
+            indexes.insert(id, directories.add(info, None, inputs));
+        }
+        for record in &inner.directory_records {
+            if let Some(index) = indexes.get(&record.file_id) {
+                directories.files[*index].records[input].push(record.clone());
+            }
+        }
+    }
+
+    let mut next_id = 0;
+    let inner = &mut rf.inner;
+    inner.records =
+        take_consensus(files, min_count, &mut next_id, &mut inner.inode_map, &mut inner.mounts);
+    inner.directory_records = take_consensus(
+        directories,
+        min_count,
+        &mut next_id,
+        &mut inner.directories,
+        &mut inner.mounts,
+    );
+    rf.check()?;
+    Ok(rf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(file_id: u64, offset: u64, length: u64, timestamp: u64) -> Record {
+        Record { file_id: FileId(file_id), offset, length, timestamp }
+    }
+
+    fn records_file(files: &[(u64, u64, &str)], records: &[Record]) -> RecordsFile {
+        let mut rf = RecordsFile::default();
+        for (id, inode_number, path) in files {
+            rf.insert_or_update_inode_info(
+                FileId(*id),
+                InodeInfo::new(*inode_number, 1 << 20, vec![path.to_string()], 1),
+            );
+        }
+        for record in records {
+            rf.insert_record(record.clone());
+        }
+        rf
+    }
+
+    #[test]
+    fn test_consensus_records() {
+        let inputs = vec![
+            vec![record(0, 0, 8192, 10), record(0, 16384, 4096, 50)],
+            vec![record(0, 4096, 8192, 30)],
+            vec![record(0, 0, 4096, 20), record(0, 65536, 4096, 5)],
+        ];
+        // [0, 4096) is read by the first and the last, [4096, 8192) by the first two.
+        assert_eq!(consensus_records(&inputs, 2), vec![record(0, 0, 8192, 10)]);
+        assert_eq!(consensus_records(&inputs, 3), vec![]);
+        assert_eq!(
+            consensus_records(&inputs, 1),
+            vec![record(0, 0, 12288, 10), record(0, 16384, 4096, 50), record(0, 65536, 4096, 5)]
+        );
+    }
+
+    #[test]
+    fn test_median() {
+        assert_eq!(median(vec![30]), 30);
+        assert_eq!(median(vec![30, 10]), 10);
+        assert_eq!(median(vec![30, 10, 20]), 20);
+    }
+
+    #[test]
+    fn test_merge_records_files() {
+        // The same library under different file ids, a renamed binary, and files read once.
+        let boots = vec![
+            records_file(
+                &[
+                    (0, 100, "/system/lib/libc.so"),
+                    (1, 200, "/system/bin/init"),
+                    (2, 300, "/data/crash"),
+                ],
+                &[record(0, 0, 4096, 10), record(1, 0, 4096, 20), record(2, 0, 4096, 30)],
+            ),
+            records_file(
+                &[(5, 200, "/system/bin/init.new"), (7, 100, "/system/lib/libc.so")],
+                &[record(5, 0, 4096, 40), record(7, 0, 4096, 30)],
+            ),
+            records_file(
+                &[(3, 999, "/system/lib/libc.so"), (4, 400, "/data/setup")],
+                &[record(3, 0, 4096, 20), record(4, 0, 4096, 1)],
+            ),
+        ];
+        let merged = merge_records_files(&boots, 2).unwrap();
+        merged.check().unwrap();
+
+        let paths: Vec<&Vec<String>> = merged
+            .inner
+            .records
+            .iter()
+            .map(|r| &merged.inner.inode_map[&r.file_id].paths)
+            .collect();
+        assert_eq!(
+            paths,
+            vec![
+                &vec!["/system/lib/libc.so".to_owned()],
+                &vec!["/system/bin/init.new".to_owned(), "/system/bin/init".to_owned()],
+            ]
+        );
+        assert_eq!(merged.inner.records[0].timestamp, 20);
+        assert_eq!(merged.inner.records[1].timestamp, 20);
+        // The last boot has another inode for the library.
+        assert_eq!(merged.inner.inode_map[&merged.inner.records[0].file_id].inode_number, 999);
+
+        assert!(merge_records_files(&boots, 0).is_err());
+        assert!(merge_records_files(&boots, 4).is_err());
+    }
+}
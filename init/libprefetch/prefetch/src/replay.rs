@@ -16,30 +16,68 @@ use std::clone::Clone;
 use std::convert::TryInto;
 use std::fmt::Display;
 use std::mem::replace;
+use std::num::NonZeroUsize;
+use std::os::fd::AsFd;
 use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::sync::RwLock;
 use std::thread;
+use std::time::{Duration, Instant};
 
 use log::debug;
 use log::error;
+use log::info;
 use log::warn;
 use lru_cache::LruCache;
 use nix::errno::Errno;
 use nix::fcntl::posix_fadvise;
+use nix::sys::mman::{mmap, munmap, MapFlags, ProtFlags};
 use regex::Regex;
-
-use crate::args::ConfigFile;
+use serde::Serialize;
+
+mod additional_files;
+mod builder;
+mod cancel;
+mod config;
+mod extents;
+mod metadata;
+mod pace;
+mod progress;
+mod report;
+mod residency;
+mod sched;
+mod uring;
+
+use crate::args::ReplayEngine;
+use crate::args::ReplayStrategy;
 use crate::format::Record;
 use crate::format::{FileId, RecordsFile};
+use crate::tracer::page_size;
 use crate::Error;
 use crate::ReplayArgs;
+use cancel::{CancelConditions, CancelToken};
 use libc::{c_void, off64_t, pread64};
+use pace::{MonotonicClock, PaceLimits, Pacer};
+use report::ReplayStats;
+use sched::WorkerScheduling;
+
+pub(crate) use builder::validate_settings;
+pub use builder::ReplayBuilder;
+pub use cancel::CancelReason;
+pub use metadata::MetadataReport;
+pub use progress::ReplayProgress;
+pub use report::{FileErrors, ReplayReport, ResidencyReport, StaleFile, WorkerReport};
 use std::fs::File;
 
 const READ_SZ: usize = 1024 * 1024;
 
+// Interval and maximum number of polls of the page cache while waiting for it to settle after
+// a benchmark run.
+const BENCHMARK_SETTLE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+const BENCHMARK_MAX_SETTLE_POLLS: usize = 50;
+
 struct ScopedLog<T: Display + Sized> {
     msg: T,
     thd_id: usize,
@@ -57,62 +95,284 @@ impl<T: Display> Drop for ScopedLog<T> {
     }
 }
 
-fn readahead(
-    id: usize,
-    file: Arc<File>,
-    record: &Record,
-    buffer: &mut [u8; READ_SZ],
-) -> Result<(), Error> {
-    debug!("readahead {record:?}");
-    let _dbg = scoped_log(id, "readahead");
+/// Brings the data of a record into the page cache.
+trait ReadaheadStrategy {
+    /// Brings the range of `file` described by `record` into the page cache. `buffer` is a
+    /// scratch buffer that strategies may use to read data into.
+    fn readahead(
+        &self,
+        file: &File,
+        record: &Record,
+        buffer: &mut [u8; READ_SZ],
+    ) -> Result<(), Error>;
+}
 
-    let mut current_offset: off64_t = record
+fn record_offset_and_length(record: &Record) -> Result<(off64_t, usize), Error> {
+    let offset: off64_t = record
         .offset
         .try_into()
         .map_err(|_| Error::Read { error: "Failed to convert offset".to_string() })?;
-    let mut remaining_data: usize = record
+    let length: usize = record
         .length
         .try_into()
         .map_err(|_| Error::Read { error: "Failed to convert length".to_string() })?;
+    Ok((offset, length))
+}
 
-    while remaining_data > 0 {
-        let read_size = std::cmp::min(READ_SZ, remaining_data);
+// Reads data with pread64 into a bounce buffer that is thrown away.
+struct PreadStrategy;
 
-        // SAFETY: This is safe because
-        // - the file is known to exist and opened
-        // - buffer is allocated upfront and is guaranteed by the fact it comes from a mutable slice reference.
-        // - read_size is guaranteed not to exceed length of the buffer.
-        let bytes_read = unsafe {
-            pread64(file.as_raw_fd(), buffer.as_mut_ptr() as *mut c_void, read_size, current_offset)
-        };
+impl ReadaheadStrategy for PreadStrategy {
+    fn readahead(
+        &self,
+        file: &File,
+        record: &Record,
+        buffer: &mut [u8; READ_SZ],
+    ) -> Result<(), Error> {
+        let (mut current_offset, mut remaining_data) = record_offset_and_length(record)?;
+
+        while remaining_data > 0 {
+            let read_size = std::cmp::min(READ_SZ, remaining_data);
+
+            // SAFETY: This is safe because
+            // - the file is known to exist and opened
+            // - buffer is allocated upfront and is guaranteed by the fact it comes from a mutable slice reference.
+            // - read_size is guaranteed not to exceed length of the buffer.
+            let bytes_read = unsafe {
+                pread64(
+                    file.as_raw_fd(),
+                    buffer.as_mut_ptr() as *mut c_void,
+                    read_size,
+                    current_offset,
+                )
+            };
+
+            if bytes_read == -1 {
+                return Err(Error::Read {
+                    error: format!("readahead failed: {}", Errno::last_raw()),
+                });
+            }
+
+            if bytes_read == 0 {
+                break; // End of file reached
+            }
+
+            current_offset += bytes_read as off64_t;
+            remaining_data -= bytes_read as usize;
+        }
+        Ok(())
+    }
+}
 
-        if bytes_read == -1 {
+// Uses readahead(2). Data is read into the page cache without copying it to user space. The
+// syscall may return before all of the data is read.
+struct ReadaheadSyscallStrategy;
+
+impl ReadaheadStrategy for ReadaheadSyscallStrategy {
+    fn readahead(
+        &self,
+        file: &File,
+        record: &Record,
+        _buffer: &mut [u8; READ_SZ],
+    ) -> Result<(), Error> {
+        let (offset, length) = record_offset_and_length(record)?;
+        // SAFETY: This is safe because the file is known to exist and opened.
+        if unsafe { libc::readahead(file.as_raw_fd(), offset, length) } == -1 {
             return Err(Error::Read { error: format!("readahead failed: {}", Errno::last_raw()) });
         }
+        Ok(())
+    }
+}
+
+// Uses posix_fadvise(POSIX_FADV_WILLNEED). The kernel initiates reads but does not wait for
+// them to complete.
+struct FadviseStrategy;
+
+impl ReadaheadStrategy for FadviseStrategy {
+    fn readahead(
+        &self,
+        file: &File,
+        record: &Record,
+        _buffer: &mut [u8; READ_SZ],
+    ) -> Result<(), Error> {
+        let (offset, length) = record_offset_and_length(record)?;
+        posix_fadvise(
+            file.as_raw_fd(),
+            offset,
+            length as off64_t,
+            nix::fcntl::PosixFadviseAdvice::POSIX_FADV_WILLNEED,
+        )
+        .map_err(|e| Error::Read { error: format!("fadvise failed: {e}") })
+    }
+}
 
-        if bytes_read == 0 {
-            break; // End of file reached
+// Maps the range with MAP_POPULATE, which faults in all the pages, and unmaps it.
+//
+// Page faults go through mmap readaround, so the kernel may bring in more data than the range.
+struct MmapStrategy;
+
+impl ReadaheadStrategy for MmapStrategy {
+    fn readahead(
+        &self,
+        file: &File,
+        record: &Record,
+        _buffer: &mut [u8; READ_SZ],
+    ) -> Result<(), Error> {
+        let page_size = page_size()? as u64;
+        let file_size = file
+            .metadata()
+            .map_err(|e| Error::Read { error: format!("failed to stat file: {e}") })?
+            .len();
+        let start = record.offset - (record.offset % page_size);
+        let end = std::cmp::min(record.offset.saturating_add(record.length), file_size);
+        if end <= start {
+            return Ok(());
         }
+        let length = NonZeroUsize::new((end - start) as usize).unwrap();
+
+        // SAFETY: This is safe because
+        // - the length is checked for zero
+        // - the offset is page aligned
+        // - the mapping is never accessed and is unmapped below
+        let addr = unsafe {
+            mmap(
+                None,
+                length,
+                ProtFlags::PROT_READ,
+                MapFlags::MAP_SHARED | MapFlags::MAP_POPULATE,
+                file.as_fd(),
+                start as libc::off_t,
+            )
+        }
+        .map_err(|e| Error::Read { error: format!("mmap failed: {e}") })?;
+
+        // SAFETY: This is safe because the address was returned by mmap above with the same length.
+        unsafe { munmap(addr, length.get()) }
+            .map_err(|e| Error::Munmap { error: e.to_string(), length: length.get() })
+    }
+}
 
-        current_offset += bytes_read as off64_t;
-        remaining_data -= bytes_read as usize;
+fn new_strategy(strategy: ReplayStrategy) -> Box<dyn ReadaheadStrategy> {
+    match strategy {
+        ReplayStrategy::Pread => Box::new(PreadStrategy),
+        ReplayStrategy::Readahead => Box::new(ReadaheadSyscallStrategy),
+        ReplayStrategy::Fadvise => Box::new(FadviseStrategy),
+        ReplayStrategy::Mmap => Box::new(MmapStrategy),
     }
+}
 
-    // TODO: Try readahead() syscall or async I/O
-    Ok(())
+// Returns the parts of `record` that are not in the page cache along with the number of
+// bytes of `record` that are. The whole record is returned if residency cannot be determined.
+fn non_resident_parts(file: &File, record: &Record) -> (Vec<Record>, u64) {
+    match residency::page_residency(file, record.offset, record.length) {
+        Ok(residency) => {
+            let parts: Vec<Record> = residency
+                .non_resident_ranges()
+                .into_iter()
+                .map(|range| Record {
+                    offset: range.start,
+                    length: range.end - range.start,
+                    ..record.clone()
+                })
+                .collect();
+            let non_resident: u64 = parts.iter().map(|part| part.length).sum();
+            let requested = residency.range.end.saturating_sub(residency.range.start);
+            (parts, requested - non_resident)
+        }
+        Err(e) => {
+            debug!("failed to get residency of {record:?}: {e}");
+            (vec![record.clone()], 0)
+        }
+    }
 }
 
-fn worker_internal(
-    id: usize,
+// Returns the open file for `record` from the fd cache. On cache miss, the file is opened,
+// filesystem readahead is turned off for it and it is added to the cache. Opens, and failures
+// to open, are reported to `progress`.
+fn get_or_open_file(
+    state: &Mutex<SharedState>,
+    records_file: &RwLock<RecordsFile>,
+    record: &Record,
+    exclude_files_regex: &[Regex],
+    progress: &dyn ReplayProgress,
+) -> Result<Arc<File>, Error> {
+    {
+        let mut state = state.lock().unwrap();
+        if let Some(file) = state.fds.get_mut(&record.file_id) {
+            return Ok(file.clone());
+        }
+        // Stale files are checked once.
+        if let Some(e) = state.stats.stale_error(&record.file_id) {
+            return Err(e);
+        }
+    }
+
+    let opened =
+        records_file.read().unwrap().open_file(record.file_id.clone(), exclude_files_regex);
+    progress.file_opened(&record.file_id, opened.as_ref().map(|_| ()));
+    let file = opened?;
+
+    // We do not want the filesystem be intelligent and prefetch more than what this
+    // code is reading. So turn off prefetch.
+    if let Err(e) =
+        posix_fadvise(file.as_raw_fd(), 0, 0, nix::fcntl::PosixFadviseAdvice::POSIX_FADV_RANDOM)
+    {
+        warn!(
+            "Failed to turn off filesystem read ahead for file id: {} with {}",
+            record.file_id.clone(),
+            e
+        );
+    }
+
+    let file = Arc::new(file);
+    let mut state = state.lock().unwrap();
+    state.fds.insert(record.file_id.clone(), file.clone());
+    state.stats.add_opened();
+    Ok(file)
+}
+
+// Logs a failure to open the file of `file_id`. Skipped and stale files are not treated as
+// errors.
+fn log_open_error(file_id: &FileId, e: Error) {
+    match e {
+        Error::SkipPrefetch { path } => {
+            debug!("Skipping file during replay: {path}");
+        }
+        Error::StaleFile { path, reason } => {
+            debug!("Skipping stale file during replay: {path}: {reason}");
+        }
+        _ => error!("Failed to open file id: {} with {}", file_id, e),
+    }
+}
+
+// State and settings shared by all the replay worker threads.
+#[derive(Clone)]
+struct WorkerContext {
     state: Arc<Mutex<SharedState>>,
     records_file: Arc<RwLock<RecordsFile>>,
     exit_on_error: bool,
     exclude_files_regex: Vec<Regex>,
+    strategy: ReplayStrategy,
+    pacer: Option<Arc<Pacer>>,
+    skip_resident: bool,
+    scheduling: WorkerScheduling,
+    cancel: Arc<CancelToken>,
+    progress: Arc<dyn ReplayProgress>,
+}
+
+fn worker_internal(
+    id: usize,
+    ctx: &WorkerContext,
     buffer: &mut [u8],
+    records_replayed: &mut u64,
 ) -> Result<(), Error> {
+    let strategy = new_strategy(ctx.strategy);
     loop {
+        if ctx.cancel.is_cancelled() {
+            return Ok(());
+        }
         let index = {
-            let mut state = state.lock().unwrap();
+            let mut state = ctx.state.lock().unwrap();
             if state.result.is_err() {
                 return Ok(());
             }
@@ -120,97 +380,87 @@ fn worker_internal(
         };
 
         let record = {
-            let rf = records_file.read().unwrap();
+            let rf = ctx.records_file.read().unwrap();
             if index >= rf.inner.records.len() {
                 return Ok(());
             }
             rf.inner.records.get(index).unwrap().clone()
         };
 
-        let _dbg = scoped_log(id, "record_replay");
+        if let Some(pacer) = &ctx.pacer {
+            let _dbg = scoped_log(id, "pace");
+            if !pacer.wait(&record, &ctx.cancel) {
+                return Ok(());
+            }
+        }
 
-        let file = state.lock().unwrap().fds.get_mut(&record.file_id).map(|f| f.clone());
-
-        let file = match file {
-            Some(file) => file,
-            None => {
-                let file = Arc::new({
-                    let file = records_file
-                        .read()
-                        .unwrap()
-                        .open_file(record.file_id.clone(), &exclude_files_regex);
-                    if let Err(e) = file {
-                        if exit_on_error {
-                            return Err(e);
-                        } else {
-                            match e {
-                                Error::SkipPrefetch { path } => {
-                                    debug!("Skipping file during replay: {path}");
-                                }
-                                _ => error!(
-                                    "Failed to open file id: {} with {}",
-                                    record.file_id.clone(),
-                                    e
-                                ),
-                            }
-                            continue;
-                        }
-                    }
+        let _dbg = scoped_log(id, "record_replay");
 
-                    let file = file.unwrap();
-                    // We do not want the filesystem be intelligent and prefetch more than what this
-                    // code is reading. So turn off prefetch.
-
-                    if let Err(e) = posix_fadvise(
-                        file.as_raw_fd(),
-                        0,
-                        0,
-                        nix::fcntl::PosixFadviseAdvice::POSIX_FADV_RANDOM,
-                    ) {
-                        warn!(
-                            "Failed to turn off filesystem read ahead for file id: {} with {}",
-                            record.file_id.clone(),
-                            e
-                        );
-                    }
-                    file
-                });
-                let cache_file = file.clone();
-                state.lock().unwrap().fds.insert(record.file_id.clone(), cache_file);
-                file
+        let file = match get_or_open_file(
+            &ctx.state,
+            &ctx.records_file,
+            &record,
+            &ctx.exclude_files_regex,
+            ctx.progress.as_ref(),
+        ) {
+            Ok(file) => file,
+            Err(e) => {
+                ctx.progress.record_replayed(&record, Err(&e));
+                ctx.state.lock().unwrap().stats.add_open_error(&record.file_id, &e);
+                // Files that changed since recording are expected after updates.
+                if ctx.exit_on_error && !matches!(e, Error::StaleFile { .. }) {
+                    return Err(e);
+                }
+                log_open_error(&record.file_id, e);
+                continue;
             }
         };
-        if let Err(e) = readahead(id, file, &record, buffer.try_into().unwrap()) {
-            if exit_on_error {
+        let parts = if ctx.skip_resident {
+            let (parts, resident_bytes) = non_resident_parts(&file, &record);
+            ctx.state.lock().unwrap().stats.add_skipped_resident(resident_bytes);
+            parts
+        } else {
+            vec![record.clone()]
+        };
+
+        debug!("readahead {record:?}");
+        let readahead_result = {
+            let _dbg = scoped_log(id, "readahead");
+            parts
+                .iter()
+                .try_for_each(|part| strategy.readahead(&file, part, buffer.try_into().unwrap()))
+        };
+        if let Err(e) = readahead_result {
+            ctx.progress.record_replayed(&record, Err(&e));
+            ctx.state.lock().unwrap().stats.add_read_error(&record.file_id, &e);
+            if ctx.exit_on_error {
                 return Err(e);
             } else {
                 error!("readahead failed on file id: {} with: {}", record.file_id.clone(), e);
                 continue;
             }
         }
+        let bytes_read = parts.iter().map(|part| part.length).sum();
+        ctx.progress.record_replayed(&record, Ok(bytes_read));
+        ctx.state.lock().unwrap().stats.add_replayed(1, bytes_read);
+        *records_replayed += 1;
     }
 }
 
-fn worker(
-    id: usize,
-    state: Arc<Mutex<SharedState>>,
-    records_file: Arc<RwLock<RecordsFile>>,
-    exit_on_error: bool,
-    exclude_files_regex: Vec<Regex>,
-    buffer: &mut [u8],
-) {
+fn worker(id: usize, ctx: WorkerContext, buffer: &mut [u8]) {
     let _dbg = scoped_log(id, "read_loop");
-    let result = worker_internal(
+    ctx.scheduling.apply();
+    let start = Instant::now();
+    let mut records_replayed = 0;
+    let result = worker_internal(id, &ctx, buffer, &mut records_replayed);
+    let mut state = ctx.state.lock().unwrap();
+    state.stats.add_worker(WorkerReport {
         id,
-        state.clone(),
-        records_file,
-        exit_on_error,
-        exclude_files_regex,
-        buffer,
-    );
+        records_replayed,
+        wall_time_ms: start.elapsed().as_millis() as u64,
+    });
     if result.is_err() {
         error!("worker failed with {result:?}");
-        let mut state = state.lock().unwrap();
         if state.result.is_ok() {
             state.result = result;
         }
@@ -222,6 +472,7 @@ pub struct SharedState {
     fds: LruCache<FileId, Arc<File>>,
     records_index: usize,
     result: Result<(), Error>,
+    stats: ReplayStats,
 }
 
 impl SharedState {
@@ -232,6 +483,20 @@ impl SharedState {
     }
 }
 
+/// Outcome of replaying a records file with one strategy.
+#[derive(Debug, Serialize)]
+pub struct BenchmarkResult {
+    /// Strategy used for the replay.
+    pub strategy: ReplayStrategy,
+
+    /// Time taken by the replay in milliseconds.
+    pub wall_time_ms: u64,
+
+    /// Number of bytes of the records that were brought into the page cache by the replay,
+    /// measured once the page cache stops changing.
+    pub bytes_cached: u64,
+}
+
 /// Runtime, in-memory, representation of records file structure.
 #[derive(Debug)]
 pub struct Replay {
@@ -240,77 +505,217 @@ pub struct Replay {
     exit_on_error: bool,
     state: Arc<Mutex<SharedState>>,
     exclude_files_regex: Vec<Regex>,
+    engine: ReplayEngine,
+    queue_depth: u16,
+    strategy: ReplayStrategy,
+    pace_limits: PaceLimits,
+    report_path: Option<PathBuf>,
+    skip_resident: bool,
+    warm_metadata: bool,
+    scheduling: WorkerScheduling,
+    cancel_conditions: CancelConditions,
+    progress: Arc<dyn ReplayProgress>,
 }
 
 impl Replay {
     /// Creates Replay from input `args`.
     pub fn new(args: &ReplayArgs) -> Result<Self, Error> {
         let _dbg = scoped_log(1, "new");
-        let reader: File = File::open(&args.path).map_err(|source| Error::Open {
-            source,
-            path: args.path.to_str().unwrap().to_owned(),
-        })?;
-        let rf: RecordsFile = serde_cbor::from_reader(reader)
-            .map_err(|error| Error::Deserialize { error: error.to_string() })?;
-
-        let mut exclude_files_regex: Vec<Regex> = Vec::new();
-        // The path to the configuration file is optional in the command.
-        // If the path is provided, the configuration file will be read.
-        if !&args.config_path.as_os_str().is_empty() {
-            let config_reader = File::open(&args.config_path).map_err(|source| Error::Open {
-                source,
-                path: args.path.to_str().unwrap().to_owned(),
-            })?;
-            let cf: ConfigFile = serde_json::from_reader(config_reader)
-                .map_err(|error| Error::Deserialize { error: error.to_string() })?;
+        ReplayBuilder::from_args(args)?.build()
+    }
 
-            for file_to_exclude in &cf.files_to_exclude_regex {
-                exclude_files_regex.push(Regex::new(file_to_exclude).unwrap());
+    /// Replays the records once with each strategy and returns the time taken and the bytes
+    /// brought into the page cache by each of them.
+    ///
+    /// The data of the files in the records file is dropped from the page cache before each run.
+    pub fn benchmark(args: &ReplayArgs) -> Result<Vec<BenchmarkResult>, Error> {
+        let mut results = vec![];
+        for strategy in ReplayStrategy::ALL {
+            let replay = Replay::new(&ReplayArgs {
+                engine: ReplayEngine::ThreadPool,
+                strategy,
+                benchmark: false,
+                max_ahead_ms: None,
+                max_ahead_mib: None,
+                report_path: None,
+                deadline_ms: None,
+                stop_property: None,
+                max_memory_pressure: None,
+                ..args.clone()
+            })?;
+            let records_file = replay.records_file.clone();
+            let exclude_files_regex = replay.exclude_files_regex.clone();
+
+            residency::drop_cached_pages(&records_file.read().unwrap(), &exclude_files_regex);
+            let before = residency::records_resident_bytes(
+                &records_file.read().unwrap(),
+                &exclude_files_regex,
+            );
+
+            let start = Instant::now();
+            replay.replay()?;
+            let wall_time = start.elapsed();
+
+            // readahead(2) and fadvise return before all the reads complete. Wait for the page
+            // cache to settle so that all the strategies are measured alike.
+            let mut after = 0;
+            for _ in 0..BENCHMARK_MAX_SETTLE_POLLS {
+                thread::sleep(BENCHMARK_SETTLE_POLL_INTERVAL);
+                let resident = residency::records_resident_bytes(
+                    &records_file.read().unwrap(),
+                    &exclude_files_regex,
+                );
+                if resident == after {
+                    break;
+                }
+                after = resident;
             }
+            let result = BenchmarkResult {
+                strategy,
+                wall_time_ms: wall_time.as_millis() as u64,
+                bytes_cached: after.saturating_sub(before),
+            };
+            info!("{result:?}");
+            results.push(result);
         }
-
-        Ok(Self {
-            records_file: Arc::new(RwLock::new(rf)),
-            io_depth: args.io_depth,
-            exit_on_error: args.exit_on_error,
-            state: Arc::new(Mutex::new(SharedState {
-                fds: LruCache::new(args.max_fds.into()),
-                records_index: 0,
-                result: Ok(()),
-            })),
-            exclude_files_regex,
-        })
+        Ok(results)
     }
 
     /// Replay records.
+    ///
+    /// If a report path was given, a json `ReplayReport` is written to it even if the replay
+    /// fails.
     pub fn replay(self) -> Result<(), Error> {
+        let start = Instant::now();
+        let resident_bytes_before = self.report_path.as_ref().map(|_| {
+            residency::records_resident_bytes(
+                &self.records_file.read().unwrap(),
+                &self.exclude_files_regex,
+            )
+        });
+
+        let result = self.replay_records();
+
+        if let Some(path) = &self.report_path {
+            let report = self.report(start.elapsed(), resident_bytes_before.unwrap_or(0));
+            let report_result = write_report(path, &report);
+            if result.is_err() {
+                if let Err(e) = report_result {
+                    error!("failed to write replay report: {e}");
+                }
+            } else {
+                report_result?;
+            }
+        }
+        result
+    }
+
+    // Replays the records with the configured engine until done or cancelled.
+    fn replay_records(&self) -> Result<(), Error> {
+        // The recorded timeline and the deadline start along with the replay.
+        let clock = Arc::new(MonotonicClock::new());
+        let pacer = if self.pace_limits.is_enabled() {
+            Some(Arc::new(Pacer::new(
+                &self.records_file.read().unwrap(),
+                self.pace_limits,
+                clock.clone(),
+            )))
+        } else {
+            None
+        };
+        let cancel = Arc::new(CancelToken::new(self.cancel_conditions.clone(), clock));
+
+        if self.warm_metadata {
+            let report = metadata::warm_metadata(self, &cancel);
+            info!("{report:?}");
+            self.state.lock().unwrap().stats.set_metadata(report);
+        }
+        let result = self.replay_with_engine(pacer, cancel.clone());
+        if let Some(reason) = cancel.reason() {
+            info!("replay cancelled: {reason:?}");
+            self.state.lock().unwrap().stats.set_cancelled(reason);
+        }
+        result
+    }
+
+    fn replay_with_engine(
+        &self,
+        pacer: Option<Arc<Pacer>>,
+        cancel: Arc<CancelToken>,
+    ) -> Result<(), Error> {
+        if self.engine == ReplayEngine::IoUring {
+            match uring::UringReplay::new(self.queue_depth) {
+                // The ring is driven from a thread of its own so that the scheduling settings
+                // do not stick to the caller's thread.
+                Ok(ring) => {
+                    return std::thread::scope(|scope| {
+                        scope
+                            .spawn(|| ring.replay(self, pacer.as_deref(), &cancel))
+                            .join()
+                            .map_err(|_| Error::ThreadPool {
+                                error: "Failed to join io_uring thread".to_string(),
+                            })?
+                    })
+                }
+                Err(e) => warn!("io_uring is not available, falling back to thread pool: {e}"),
+            }
+        }
+        self.replay_with_threads(pacer, cancel)
+    }
+
+    // Replays records using `io_depth` worker threads issuing blocking reads.
+    fn replay_with_threads(
+        &self,
+        pacer: Option<Arc<Pacer>>,
+        cancel: Arc<CancelToken>,
+    ) -> Result<(), Error> {
         let _dbg = scoped_log(1, "replay");
+        let ctx = WorkerContext {
+            state: self.state.clone(),
+            records_file: self.records_file.clone(),
+            exit_on_error: self.exit_on_error,
+            exclude_files_regex: self.exclude_files_regex.clone(),
+            strategy: self.strategy,
+            pacer,
+            skip_resident: self.skip_resident,
+            scheduling: self.scheduling.clone(),
+            cancel,
+            progress: self.progress.clone(),
+        };
         let mut threads = vec![];
         for i in 0..self.io_depth {
             let i_clone = i as usize;
-            let state = self.state.clone();
-            let records_file = self.records_file.clone();
-            let exit_on_error = self.exit_on_error;
-            let exclude_files_regex = self.exclude_files_regex.clone();
+            let ctx = ctx.clone();
 
             let mut buffer = Box::new([0u8; READ_SZ]);
 
-            threads.push(thread::Builder::new().spawn(move || {
-                worker(
-                    i_clone,
-                    state,
-                    records_file,
-                    exit_on_error,
-                    exclude_files_regex,
-                    buffer.as_mut_slice(),
-                )
-            }));
+            threads.push(
+                thread::Builder::new().spawn(move || worker(i_clone, ctx, buffer.as_mut_slice())),
+            );
         }
         for thread in threads {
             thread.unwrap().join().unwrap();
         }
         replace(&mut self.state.lock().unwrap().result, Ok(()))
     }
+
+    // Builds the report of a finished replay.
+    fn report(&self, wall_time: Duration, resident_bytes_before: u64) -> ReplayReport {
+        let rf = self.records_file.read().unwrap();
+        let residency = ResidencyReport {
+            record_bytes: rf.inner.records.iter().map(|record| record.length).sum(),
+            resident_bytes_before,
+            resident_bytes_after: residency::records_resident_bytes(&rf, &self.exclude_files_regex),
+        };
+        self.state.lock().unwrap().stats.report(&rf, wall_time, residency)
+    }
+}
+
+fn write_report(path: &Path, report: &ReplayReport) -> Result<(), Error> {
+    let file = File::create(path)
+        .map_err(|source| Error::Create { source, path: path.display().to_string() })?;
+    serde_json::to_writer_pretty(file, report)
+        .map_err(|e| Error::Serialize { error: e.to_string() })
 }
 
 // WARNING: flaky tests.
@@ -357,6 +762,7 @@ pub mod tests {
     use tempfile::NamedTempFile;
 
     use super::*;
+    use crate::args::ConfigFile;
     use crate::tracer::{
         page_size,
         tests::{copy_uncached_files_and_record_from, setup_test_dir},
@@ -641,6 +1047,8 @@ pub mod tests {
         inject_error: bool,
         exclude_all_files: bool,
         empty_exclude_file_list: bool,
+        engine: ReplayEngine,
+        strategy: ReplayStrategy,
     ) {
         let page_size = page_size().unwrap() as u64;
         let test_base_dir = setup_test_dir();
@@ -691,6 +1099,10 @@ pub mod tests {
             max_fds: 128,
             exit_on_error,
             config_path: config_file.path().to_owned(),
+            engine,
+            queue_depth: 64,
+            strategy,
+            ..Default::default()
         })
         .unwrap();
 
@@ -721,38 +1133,375 @@ pub mod tests {
         }
     }
 
+    // Replays freshly generated files with `args` and returns the records file and the
+    // parsed report. `prepare` may change the records file and the files before the records
+    // file is written.
+    fn replay_with_report(
+        args: ReplayArgs,
+        prepare: impl FnOnce(&mut RecordsFile, &[(NamedTempFile, Vec<Range<u64>>)]),
+    ) -> (RecordsFile, ReplayReport) {
+        let (mut rf, files) = generate_cached_files_and_record(None, false, None);
+        prepare(&mut rf, &files);
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(&rf.add_checksum_and_serialize().unwrap()).unwrap();
+        let report_file = NamedTempFile::new().unwrap();
+
+        Replay::new(&ReplayArgs {
+            path: file.path().to_owned(),
+            report_path: Some(report_file.path().to_owned()),
+            ..args
+        })
+        .unwrap()
+        .replay()
+        .unwrap();
+
+        let report = serde_json::from_reader(File::open(report_file.path()).unwrap()).unwrap();
+        (rf, report)
+    }
+
     #[test]
     fn test_replay() {
-        test_replay_internal(true, false, false, false, false);
+        test_replay_internal(
+            true,
+            false,
+            false,
+            false,
+            false,
+            ReplayEngine::ThreadPool,
+            ReplayStrategy::Pread,
+        );
     }
 
     #[test]
     fn test_replay_strict() {
-        test_replay_internal(true, true, false, false, false);
+        test_replay_internal(
+            true,
+            true,
+            false,
+            false,
+            false,
+            ReplayEngine::ThreadPool,
+            ReplayStrategy::Pread,
+        );
     }
 
     #[test]
     fn test_replay_no_symlink() {
-        test_replay_internal(false, false, false, false, false);
+        test_replay_internal(
+            false,
+            false,
+            false,
+            false,
+            false,
+            ReplayEngine::ThreadPool,
+            ReplayStrategy::Pread,
+        );
     }
 
     #[test]
     fn test_replay_no_symlink_strict() {
-        test_replay_internal(false, true, false, false, false);
+        test_replay_internal(
+            false,
+            true,
+            false,
+            false,
+            false,
+            ReplayEngine::ThreadPool,
+            ReplayStrategy::Pread,
+        );
     }
 
     #[test]
     fn test_replay_fails_on_error() {
-        test_replay_internal(true, true, true, false, false);
+        test_replay_internal(
+            true,
+            true,
+            true,
+            false,
+            false,
+            ReplayEngine::ThreadPool,
+            ReplayStrategy::Pread,
+        );
     }
 
     #[test]
     fn test_replay_exclude_all_files() {
-        test_replay_internal(true, false, false, true, false);
+        test_replay_internal(
+            true,
+            false,
+            false,
+            true,
+            false,
+            ReplayEngine::ThreadPool,
+            ReplayStrategy::Pread,
+        );
     }
 
     #[test]
     fn test_replay_empty_exclude_files_list() {
-        test_replay_internal(true, false, false, false, true);
+        test_replay_internal(
+            true,
+            false,
+            false,
+            false,
+            true,
+            ReplayEngine::ThreadPool,
+            ReplayStrategy::Pread,
+        );
+    }
+
+    #[test]
+    fn test_replay_io_uring() {
+        test_replay_internal(
+            true,
+            false,
+            false,
+            false,
+            false,
+            ReplayEngine::IoUring,
+            ReplayStrategy::Pread,
+        );
+    }
+
+    #[test]
+    fn test_replay_io_uring_fails_on_error() {
+        test_replay_internal(
+            true,
+            true,
+            true,
+            false,
+            false,
+            ReplayEngine::IoUring,
+            ReplayStrategy::Pread,
+        );
+    }
+
+    #[test]
+    fn test_replay_readahead_strategy() {
+        test_replay_internal(
+            true,
+            false,
+            false,
+            false,
+            false,
+            ReplayEngine::ThreadPool,
+            ReplayStrategy::Readahead,
+        );
+    }
+
+    #[test]
+    fn test_replay_report() {
+        let mut config_file = NamedTempFile::new().unwrap();
+        let mut ids = vec![];
+        let (rf, report) = replay_with_report(
+            ReplayArgs {
+                // A single worker so that a file is not opened by more than one worker.
+                io_depth: 1,
+                max_fds: 128,
+                config_path: config_file.path().to_owned(),
+                ..Default::default()
+            },
+            |rf, _| {
+                ids = rf.inner.inode_map.keys().cloned().collect();
+                ids.sort();
+                for path in &mut rf.inner.inode_map.get_mut(&ids[0]).unwrap().paths {
+                    path.push('-');
+                }
+                let excluded_path = rf.inner.inode_map[&ids[1]].paths[0].clone();
+                config_file
+                    .write_all(create_test_config_file(vec![excluded_path]).as_bytes())
+                    .unwrap();
+            },
+        );
+        let (broken_id, replayed_id) = (&ids[0], &ids[2]);
+        // Returns the number of records and bytes of `id`.
+        let records_of = |id: &FileId| {
+            let records = rf.inner.records.iter().filter(|r| &r.file_id == id);
+            (records.clone().count(), records.map(|r| r.length).sum::<u64>())
+        };
+
+        assert_eq!(report.records_replayed, records_of(replayed_id).0 as u64);
+        assert_eq!(report.bytes_read, records_of(replayed_id).1);
+        assert_eq!(report.files_opened, 1);
+        assert_eq!(report.files_skipped, 1);
+
+        assert_eq!(report.file_errors.len(), 1);
+        assert_eq!(&report.file_errors[0].file_id, broken_id);
+        assert_eq!(report.file_errors[0].open_errors, records_of(broken_id).0 as u64);
+        assert_eq!(report.file_errors[0].read_errors, 0);
+
+        assert_eq!(report.workers.len(), 1);
+        assert_eq!(
+            report.residency.record_bytes,
+            rf.inner.records.iter().map(|r| r.length).sum::<u64>()
+        );
+    }
+
+    // Replays with a deadline that has passed before the first record and verifies that
+    // nothing is replayed.
+    fn test_replay_deadline_internal(engine: ReplayEngine) {
+        let (_, report) = replay_with_report(
+            ReplayArgs {
+                io_depth: 2,
+                max_fds: 128,
+                engine,
+                queue_depth: 8,
+                deadline_ms: Some(0),
+                ..Default::default()
+            },
+            |_, _| {},
+        );
+        assert_eq!(report.records_replayed, 0);
+        assert_eq!(report.files_opened, 0);
+        assert_eq!(report.cancelled, Some(CancelReason::Deadline));
+    }
+
+    #[test]
+    fn test_replay_deadline() {
+        test_replay_deadline_internal(ReplayEngine::ThreadPool);
+    }
+
+    #[test]
+    fn test_replay_deadline_io_uring() {
+        test_replay_deadline_internal(ReplayEngine::IoUring);
+    }
+
+    // Replays files whose data is in the page cache and verifies that nothing is read.
+    fn test_replay_skip_resident_internal(engine: ReplayEngine) {
+        let (rf, report) = replay_with_report(
+            ReplayArgs {
+                io_depth: 4,
+                max_fds: 128,
+                exit_on_error: true,
+                engine,
+                queue_depth: 64,
+                skip_resident: true,
+                ..Default::default()
+            },
+            |_, _| {},
+        );
+        assert_eq!(report.records_replayed, rf.inner.records.len() as u64);
+        assert_eq!(report.bytes_read, 0);
+        // Records cover whole pages and may extend beyond the end of the file.
+        let record_bytes: u64 = rf
+            .inner
+            .records
+            .iter()
+            .map(|r| {
+                let file_size = rf.inner.inode_map[&r.file_id].file_size;
+                (r.offset + r.length).min(file_size).saturating_sub(r.offset)
+            })
+            .sum();
+        assert_eq!(report.bytes_skipped_resident, record_bytes);
+    }
+
+    #[test]
+    fn test_replay_skip_resident() {
+        test_replay_skip_resident_internal(ReplayEngine::ThreadPool);
+    }
+
+    #[test]
+    fn test_replay_skip_resident_io_uring() {
+        test_replay_skip_resident_internal(ReplayEngine::IoUring);
+    }
+
+    // Replays a records file one of whose files changed since it was recorded and verifies
+    // that the file is skipped and reported without failing replay.
+    fn test_replay_stale_file_internal(engine: ReplayEngine) {
+        let mut changed = String::new();
+        let (rf, report) = replay_with_report(
+            ReplayArgs {
+                io_depth: 4,
+                max_fds: 128,
+                exit_on_error: true,
+                engine,
+                queue_depth: 64,
+                ..Default::default()
+            },
+            |_, files| {
+                changed = files[0].0.path().to_str().unwrap().to_owned();
+                OpenOptions::new()
+                    .append(true)
+                    .open(&changed)
+                    .unwrap()
+                    .write_all(b"changed")
+                    .unwrap();
+            },
+        );
+        let changed_id =
+            rf.inner.inode_map.iter().find(|(_, info)| info.paths[0] == changed).unwrap().0;
+
+        assert_eq!(report.stale_files.len(), 1);
+        assert_eq!(&report.stale_files[0].file_id, changed_id);
+        assert_eq!(report.stale_files[0].path, changed);
+        assert!(report.stale_files[0].reason.starts_with("file size changed"));
+        let fresh_records =
+            rf.inner.records.iter().filter(|record| &record.file_id != changed_id).count();
+        assert_eq!(report.records_replayed, fresh_records as u64);
+        assert!(report.file_errors.is_empty());
+    }
+
+    #[test]
+    fn test_replay_stale_file() {
+        test_replay_stale_file_internal(ReplayEngine::ThreadPool);
+    }
+
+    #[test]
+    fn test_replay_stale_file_io_uring() {
+        test_replay_stale_file_internal(ReplayEngine::IoUring);
+    }
+
+    #[test]
+    fn test_replay_warm_metadata() {
+        let (rf, report) = replay_with_report(
+            ReplayArgs {
+                io_depth: 4,
+                max_fds: 128,
+                exit_on_error: true,
+                warm_metadata: true,
+                ..Default::default()
+            },
+            |_, _| {},
+        );
+        let files = rf.inner.inode_map.len() as u64;
+        let metadata = report.metadata.unwrap();
+        assert_eq!(metadata.files_opened, files);
+        assert_eq!(metadata.paths_stated, files);
+        // All the files are in the same directory.
+        assert_eq!(metadata.directories_walked, 1);
+        // Files opened while warming stay in the fd cache for data replay.
+        assert_eq!(report.files_opened, files);
+        assert_eq!(report.records_replayed, rf.inner.records.len() as u64);
+    }
+
+    #[test]
+    fn test_replay_refuses_pack_without_valid_mac() {
+        let (mut rf, _files) = generate_cached_files_and_record(None, false, None);
+        let mut key_file = NamedTempFile::new().unwrap();
+        key_file.write_all(b"device secret").unwrap();
+        let mut other_key_file = NamedTempFile::new().unwrap();
+        other_key_file.write_all(b"other secret").unwrap();
+
+        let replay_with_key = |pack: &[u8], key_path: &Path| {
+            let mut file = NamedTempFile::new().unwrap();
+            file.write_all(pack).unwrap();
+            Replay::new(&ReplayArgs {
+                path: file.path().to_owned(),
+                io_depth: 1,
+                max_fds: 128,
+                mac_key_path: Some(key_path.to_owned()),
+                ..Default::default()
+            })
+            .map(|_| ())
+        };
+
+        let plain = rf.add_checksum_and_serialize().unwrap();
+        let err = replay_with_key(&plain, key_file.path()).unwrap_err();
+        assert!(matches!(err, Error::Integrity { .. }), "{:?}", err);
+
+        let mac = rf.add_mac_and_serialize(b"other secret").unwrap();
+        let err = replay_with_key(&mac, key_file.path()).unwrap_err();
+        assert!(matches!(err, Error::Integrity { .. }), "{:?}", err);
+        replay_with_key(&mac, other_key_file.path()).unwrap();
     }
 }
@@ -0,0 +1,205 @@
+// Copyright (C) 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Resolves `additional_replay_files` of the config file into records.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+
+use log::{debug, warn};
+
+use crate::args::AdditionalFilesPosition;
+use crate::format::{FileId, Record, RecordsFile};
+use crate::Error;
+
+// Returns the paths matching `patterns` in the order of the patterns. Paths matched by more
+// than one pattern are returned once.
+fn expand_patterns(patterns: &[String]) -> Result<Vec<PathBuf>, Error> {
+    let mut seen = HashSet::new();
+    let mut paths = vec![];
+    for pattern in patterns {
+        let entries = glob::glob(pattern).map_err(|e| Error::InvalidArgs {
+            arg_name: "additional_replay_files".to_owned(),
+            arg_value: pattern.to_owned(),
+            error: e.to_string(),
+        })?;
+        for entry in entries {
+            match entry {
+                Ok(path) => {
+                    if seen.insert(path.clone()) {
+                        paths.push(path);
+                    }
+                }
+                Err(e) => warn!("failed to expand {pattern}: {e}"),
+            }
+        }
+    }
+    Ok(paths)
+}
+
+/// Adds a record covering the whole file for each regular file that matches one of the glob
+/// `patterns`. The records are added either before or after the existing records as per
+/// `position`.
+///
+/// Files that are already in the records file keep their `FileId`. Files that cannot be found
+/// or that are not regular files are ignored.
+pub(super) fn add_additional_replay_files(
+    rf: &mut RecordsFile,
+    patterns: &[String],
+    position: AdditionalFilesPosition,
+) -> Result<(), Error> {
+    let mut known_inodes: HashMap<(u64, u64), FileId> = rf
+        .inner
+        .inode_map
+        .iter()
+        .map(|(id, info)| ((info.device_number, info.inode_number), id.clone()))
+        .collect();
+    let mut next_id = rf.inner.inode_map.keys().map(|id| id.0 + 1).max().unwrap_or(0);
+
+    // Records of additional files are due along with the first or the last recorded record.
+    let timestamps = rf.inner.records.iter().map(|record| record.timestamp);
+    let timestamp = match position {
+        AdditionalFilesPosition::Before => timestamps.min(),
+        AdditionalFilesPosition::After => timestamps.max(),
+    }
+    .unwrap_or(0);
+
+    let mut added = HashSet::new();
+    let mut records = vec![];
+    for path in expand_patterns(patterns)? {
+        let stat = match fs::metadata(&path) {
+            Ok(stat) => stat,
+            Err(e) => {
+                warn!("failed to stat additional replay file {}: {e}", path.display());
+                continue;
+            }
+        };
+        if !stat.is_file() || stat.len() == 0 {
+            debug!("ignoring additional replay file {}", path.display());
+            continue;
+        }
+        let path_string = match path.to_str() {
+            Some(path_string) => path_string.to_owned(),
+            None => {
+                warn!("ignoring additional replay file with non utf-8 path {}", path.display());
+                continue;
+            }
+        };
+
+        let file_id = known_inodes
+            .entry((stat.dev(), stat.ino()))
+            .or_insert_with(|| {
+                let id = FileId(next_id);
+                next_id += 1;
+                rf.insert_or_update_inode(id.clone(), &stat, path_string);
+                id
+            })
+            .clone();
+        if added.insert(file_id.clone()) {
+            records.push(Record { file_id, offset: 0, length: stat.len(), timestamp });
+        }
+    }
+
+    match position {
+        AdditionalFilesPosition::Before => {
+            records.append(&mut rf.inner.records);
+            rf.inner.records = records;
+        }
+        AdditionalFilesPosition::After => rf.inner.records.append(&mut records),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tracer::tests::setup_test_dir;
+    use std::io::Write;
+    use std::path::Path;
+
+    fn create_file(dir: &Path, name: &str, len: usize) -> String {
+        let path = dir.join(name);
+        fs::File::create(&path).unwrap().write_all(&vec![1u8; len]).unwrap();
+        path.to_str().unwrap().to_owned()
+    }
+
+    fn records_file_with(path: &str) -> RecordsFile {
+        let mut rf = RecordsFile::default();
+        rf.insert_or_update_inode(FileId(7), &fs::metadata(path).unwrap(), path.to_owned());
+        rf.insert_record(Record { file_id: FileId(7), offset: 0, length: 4096, timestamp: 100 });
+        rf.insert_record(Record { file_id: FileId(7), offset: 8192, length: 4096, timestamp: 200 });
+        rf
+    }
+
+    #[test]
+    fn test_additional_files_after() {
+        let dir = setup_test_dir();
+        let recorded = create_file(&dir, "recorded", 16384);
+        let a = create_file(&dir, "a.apk", 10);
+        let b = create_file(&dir, "b.apk", 20);
+        create_file(&dir, "c.odex", 30);
+        create_file(&dir, "empty.apk", 0);
+        fs::create_dir(dir.join("d.apk")).unwrap();
+
+        let mut rf = records_file_with(&recorded);
+        let pattern = format!("{}/*.apk", dir.display());
+        add_additional_replay_files(&mut rf, &[pattern], AdditionalFilesPosition::After).unwrap();
+
+        assert_eq!(rf.inner.records.len(), 4);
+        assert_eq!(rf.inner.records[0].offset, 0);
+        assert_eq!(rf.inner.records[1].offset, 8192);
+        for (record, (path, len)) in rf.inner.records[2..].iter().zip([(a, 10), (b, 20)]) {
+            assert_eq!(rf.inner.inode_map[&record.file_id].paths, vec![path]);
+            assert_eq!((record.offset, record.length, record.timestamp), (0, len, 200));
+        }
+        rf.check().unwrap();
+    }
+
+    #[test]
+    fn test_additional_files_before_reuses_known_file() {
+        let dir = setup_test_dir();
+        let recorded = create_file(&dir, "recorded", 16384);
+        let a = create_file(&dir, "a", 10);
+
+        let mut rf = records_file_with(&recorded);
+        // Overlapping patterns match the same files more than once.
+        let patterns = vec![a.clone(), recorded.clone(), format!("{}/*", dir.display())];
+        add_additional_replay_files(&mut rf, &patterns, AdditionalFilesPosition::Before).unwrap();
+
+        let records = &rf.inner.records;
+        assert_eq!(records.len(), 4);
+        assert_eq!(rf.inner.inode_map.len(), 2);
+        assert_eq!(rf.inner.inode_map[&records[0].file_id].paths, vec![a]);
+        assert_eq!(records[0].file_id, FileId(8));
+        assert_eq!((records[0].length, records[0].timestamp), (10, 100));
+        assert_eq!(records[1].file_id, FileId(7));
+        assert_eq!((records[1].offset, records[1].length), (0, 16384));
+        assert_eq!(records[2].offset, 0);
+        assert_eq!(records[3].offset, 8192);
+    }
+
+    #[test]
+    fn test_additional_files_invalid_pattern() {
+        let mut rf = RecordsFile::default();
+        let err = add_additional_replay_files(
+            &mut rf,
+            &["/tmp/[".to_owned()],
+            AdditionalFilesPosition::After,
+        )
+        .unwrap_err();
+        assert!(matches!(err, Error::InvalidArgs { .. }), "{:?}", err);
+    }
+}
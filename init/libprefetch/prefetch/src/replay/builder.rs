@@ -0,0 +1,604 @@
+// Copyright (C) 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Builds a `Replay` from code rather than from command line arguments.
+
+use std::fs::File;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use lru_cache::LruCache;
+
+use crate::args::{
+    ConfigFile, IoPriorityClass, ReplayEngine, ReplayStrategy, DEFAULT_EXIT_ON_ERROR,
+    DEFAULT_IOPRIO_LEVEL, DEFAULT_IO_DEPTH, DEFAULT_MAX_FDS, DEFAULT_QUEUE_DEPTH,
+};
+use crate::format::{read_mac_key, RecordsFile};
+use crate::replay::cancel::CancelConditions;
+use crate::replay::config::{load_config_file, ReplayConfig};
+use crate::replay::extents;
+use crate::replay::pace::PaceLimits;
+use crate::replay::progress::{NoProgress, ReplayProgress};
+use crate::replay::report::ReplayStats;
+use crate::replay::sched::{self, WorkerScheduling};
+use crate::replay::{Replay, SharedState};
+use crate::Error;
+use crate::ReplayArgs;
+
+/// Builds a `Replay` of an in-memory `RecordsFile`.
+///
+/// Settings left alone keep the defaults of the replay command.
+#[derive(Debug)]
+pub struct ReplayBuilder {
+    records_file: RecordsFile,
+    config: ConfigFile,
+    io_depth: u16,
+    max_fds: u16,
+    exit_on_error: bool,
+    engine: ReplayEngine,
+    queue_depth: u16,
+    strategy: ReplayStrategy,
+    pace_limits: PaceLimits,
+    report_path: Option<PathBuf>,
+    skip_resident: bool,
+    warm_metadata: bool,
+    ioprio_class: Option<IoPriorityClass>,
+    ioprio_level: u8,
+    scheduling: WorkerScheduling,
+    task_profile: Option<String>,
+    cancel_conditions: CancelConditions,
+    extent_sort_window: Option<Duration>,
+    progress: Arc<dyn ReplayProgress>,
+}
+
+impl ReplayBuilder {
+    /// Creates a builder that replays the records of `records_file`.
+    pub fn new(records_file: RecordsFile) -> Self {
+        Self {
+            records_file,
+            config: ConfigFile::default(),
+            io_depth: DEFAULT_IO_DEPTH,
+            max_fds: DEFAULT_MAX_FDS,
+            exit_on_error: DEFAULT_EXIT_ON_ERROR,
+            engine: ReplayEngine::default(),
+            queue_depth: DEFAULT_QUEUE_DEPTH,
+            strategy: ReplayStrategy::default(),
+            pace_limits: PaceLimits::default(),
+            report_path: None,
+            skip_resident: false,
+            warm_metadata: false,
+            ioprio_class: None,
+            ioprio_level: DEFAULT_IOPRIO_LEVEL,
+            scheduling: WorkerScheduling::default(),
+            task_profile: None,
+            cancel_conditions: CancelConditions::default(),
+            extent_sort_window: None,
+            progress: Arc::new(NoProgress),
+        }
+    }
+
+    /// Creates a builder from the arguments of the replay command. The records file and the
+    /// config file are read from the paths in `args`.
+    pub fn from_args(args: &ReplayArgs) -> Result<Self, Error> {
+        let reader: File = File::open(&args.path)
+            .map_err(|source| Error::Open { source, path: args.path.display().to_string() })?;
+        let rf: RecordsFile = serde_cbor::from_reader(reader)
+            .map_err(|error| Error::Deserialize { error: error.to_string() })?;
+        // Replay reads whatever paths the records file names. With a key, only records files
+        // written by a holder of the key are replayed.
+        if let Some(key_path) = &args.mac_key_path {
+            rf.verify_mac(&read_mac_key(key_path)?)?;
+        }
+
+        // The path to the configuration file is optional in the command.
+        // If the path is provided, the configuration file will be read.
+        let mut builder = Self::new(rf).with_args(args);
+        if !args.config_path.as_os_str().is_empty() {
+            builder = builder.config(load_config_file(&args.config_path)?);
+        }
+        Ok(builder)
+    }
+
+    // Takes all the settings of `args` except for the records file and the config file.
+    fn with_args(self, args: &ReplayArgs) -> Self {
+        let mut builder = self
+            .io_depth(args.io_depth)
+            .max_fds(args.max_fds)
+            .exit_on_error(args.exit_on_error)
+            .engine(args.engine)
+            .queue_depth(args.queue_depth)
+            .strategy(args.strategy)
+            .skip_resident(args.skip_resident)
+            .warm_metadata(args.warm_metadata);
+        builder.pace_limits = PaceLimits {
+            max_ahead_time: args.max_ahead_ms.map(Duration::from_millis),
+            max_ahead_bytes: args.max_ahead_mib.map(|mib| mib * 1024 * 1024),
+        };
+        builder.report_path = args.report_path.clone();
+        builder.ioprio_class = args.ioprio_class;
+        builder.ioprio_level = args.ioprio_level;
+        builder.scheduling.nice = args.nice;
+        builder.scheduling.cpu_affinity = args.cpu_affinity;
+        builder.scheduling.cgroup_path = args.cgroup_path.clone();
+        builder.task_profile = args.task_profile.clone();
+        builder.extent_sort_window = args.extent_sort_window_ms.map(Duration::from_millis);
+        builder.cancel_conditions = CancelConditions {
+            deadline: args.deadline_ms.map(Duration::from_millis),
+            stop_property: args.stop_property.clone(),
+            max_memory_pressure: args.max_memory_pressure,
+        };
+        builder
+    }
+
+    /// Applies the rules of a config file: exclusions, inclusions, priorities, byte caps and
+    /// additional files. Replaces any previous config or exclusions.
+    pub fn config(mut self, config: ConfigFile) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Skips files with a path matching any of these regexes.
+    pub fn exclude_files_regex(mut self, regexes: Vec<String>) -> Self {
+        self.config.files_to_exclude_regex = regexes;
+        self
+    }
+
+    /// Number of worker threads of the thread pool engine.
+    pub fn io_depth(mut self, io_depth: u16) -> Self {
+        self.io_depth = io_depth;
+        self
+    }
+
+    /// Maximum number of files kept open at once.
+    pub fn max_fds(mut self, max_fds: u16) -> Self {
+        self.max_fds = max_fds;
+        self
+    }
+
+    /// Stops replay at the first open or read error.
+    pub fn exit_on_error(mut self, exit_on_error: bool) -> Self {
+        self.exit_on_error = exit_on_error;
+        self
+    }
+
+    /// Engine used to issue IO.
+    pub fn engine(mut self, engine: ReplayEngine) -> Self {
+        self.engine = engine;
+        self
+    }
+
+    /// Number of reads kept in flight by the io_uring engine.
+    pub fn queue_depth(mut self, queue_depth: u16) -> Self {
+        self.queue_depth = queue_depth;
+        self
+    }
+
+    /// Syscall used to bring records into the page cache.
+    pub fn strategy(mut self, strategy: ReplayStrategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    /// Holds back a record until replay is at most `ahead` ahead of its recorded time.
+    pub fn max_ahead_time(mut self, ahead: Duration) -> Self {
+        self.pace_limits.max_ahead_time = Some(ahead);
+        self
+    }
+
+    /// Holds back records so that replay reads at most `bytes` ahead of the recorded timeline.
+    pub fn max_ahead_bytes(mut self, bytes: u64) -> Self {
+        self.pace_limits.max_ahead_bytes = Some(bytes);
+        self
+    }
+
+    /// Writes a json `ReplayReport` to `path` once replay is done.
+    pub fn report_path(mut self, path: PathBuf) -> Self {
+        self.report_path = Some(path);
+        self
+    }
+
+    /// Reads only the parts of records that are not already in the page cache.
+    pub fn skip_resident(mut self, skip_resident: bool) -> Self {
+        self.skip_resident = skip_resident;
+        self
+    }
+
+    /// Walks the directories of the records file and opens its files in recorded order before
+    /// reading any data.
+    pub fn warm_metadata(mut self, warm_metadata: bool) -> Self {
+        self.warm_metadata = warm_metadata;
+        self
+    }
+
+    /// IO priority class and level, from 0 to 7, of the replay threads.
+    pub fn ioprio(mut self, class: IoPriorityClass, level: u8) -> Self {
+        self.ioprio_class = Some(class);
+        self.ioprio_level = level;
+        self
+    }
+
+    /// Nice value, from -20 to 19, of the replay threads.
+    pub fn nice(mut self, nice: i32) -> Self {
+        self.scheduling.nice = Some(nice);
+        self
+    }
+
+    /// Bitmask of the cpus the replay threads can run on.
+    pub fn cpu_affinity(mut self, mask: u64) -> Self {
+        self.scheduling.cpu_affinity = Some(mask);
+        self
+    }
+
+    /// cgroup directory the replay threads are moved to.
+    pub fn cgroup_path(mut self, path: PathBuf) -> Self {
+        self.scheduling.cgroup_path = Some(path);
+        self
+    }
+
+    /// Android task profile the replay threads are moved to.
+    pub fn task_profile(mut self, name: String) -> Self {
+        self.task_profile = Some(name);
+        self
+    }
+
+    /// Orders reads by physical location within windows of `window` of recorded time.
+    pub fn extent_sort_window(mut self, window: Duration) -> Self {
+        self.extent_sort_window = Some(window);
+        self
+    }
+
+    /// Stops replay once `deadline` has passed since it started.
+    pub fn deadline(mut self, deadline: Duration) -> Self {
+        self.cancel_conditions.deadline = Some(deadline);
+        self
+    }
+
+    /// Stops replay once the system property `name` is set to true. Only supported on android.
+    pub fn stop_property(mut self, name: String) -> Self {
+        self.cancel_conditions.stop_property = Some(name);
+        self
+    }
+
+    /// Stops replay once memory pressure, in percent, reaches `pressure`.
+    pub fn max_memory_pressure(mut self, pressure: u32) -> Self {
+        self.cancel_conditions.max_memory_pressure = Some(pressure);
+        self
+    }
+
+    /// Receives progress of the replay.
+    pub fn progress(mut self, progress: Arc<dyn ReplayProgress>) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    // Returns an error for settings that replay cannot run with.
+    fn validate(&self) -> Result<(), Error> {
+        validate_settings(
+            self.engine,
+            self.queue_depth,
+            self.strategy,
+            self.ioprio_level,
+            self.scheduling.nice,
+            self.cancel_conditions.max_memory_pressure,
+            self.cancel_conditions.stop_property.as_deref(),
+        )
+    }
+
+    /// Validates the settings and applies the config to the records.
+    pub fn build(self) -> Result<Replay, Error> {
+        self.validate()?;
+        let ioprio_level = self.ioprio_level;
+        let mut scheduling = self.scheduling;
+        scheduling.ioprio = self.ioprio_class.map(|class| (class, ioprio_level));
+        if let Some(name) = &self.task_profile {
+            scheduling.task_profile_cgroups = sched::resolve_task_profile(name)?;
+        }
+
+        let mut rf = self.records_file;
+        let config = ReplayConfig::new(&self.config)?;
+        config.apply(&mut rf)?;
+        let exclude_files_regex = config.exclude_files_regex;
+        if let Some(window) = self.extent_sort_window {
+            extents::sort_by_extents(&mut rf, window, &exclude_files_regex);
+        }
+
+        Ok(Replay {
+            records_file: Arc::new(RwLock::new(rf)),
+            io_depth: self.io_depth,
+            exit_on_error: self.exit_on_error,
+            state: Arc::new(Mutex::new(SharedState {
+                fds: LruCache::new(self.max_fds.into()),
+                records_index: 0,
+                result: Ok(()),
+                stats: ReplayStats::default(),
+            })),
+            exclude_files_regex,
+            engine: self.engine,
+            queue_depth: self.queue_depth,
+            strategy: self.strategy,
+            pace_limits: self.pace_limits,
+            report_path: self.report_path,
+            skip_resident: self.skip_resident,
+            warm_metadata: self.warm_metadata,
+            scheduling,
+            cancel_conditions: self.cancel_conditions,
+            progress: self.progress,
+        })
+    }
+}
+
+/// Returns an error for settings that replay cannot run with.
+///
+/// Shared by `ReplayBuilder` and the checks of the replay command's arguments.
+pub(crate) fn validate_settings(
+    engine: ReplayEngine,
+    queue_depth: u16,
+    strategy: ReplayStrategy,
+    ioprio_level: u8,
+    nice: Option<i32>,
+    max_memory_pressure: Option<u32>,
+    stop_property: Option<&str>,
+) -> Result<(), Error> {
+    let invalid = |arg_name: &str, arg_value: String, error: &str| {
+        Err(Error::InvalidArgs {
+            arg_name: arg_name.to_string(),
+            arg_value,
+            error: error.to_string(),
+        })
+    };
+    if engine == ReplayEngine::IoUring && queue_depth == 0 {
+        return invalid(
+            "queue_depth",
+            queue_depth.to_string(),
+            "queue depth must be greater than zero",
+        );
+    }
+    if engine == ReplayEngine::IoUring && strategy != ReplayStrategy::Pread {
+        return invalid(
+            "strategy",
+            format!("{:?}", strategy),
+            "io_uring engine only supports pread strategy",
+        );
+    }
+    if ioprio_level > 7 {
+        return invalid(
+            "ioprio_level",
+            ioprio_level.to_string(),
+            "io priority level must be between 0 and 7",
+        );
+    }
+    if let Some(nice) = nice {
+        if !(-20..=19).contains(&nice) {
+            return invalid("nice", nice.to_string(), "nice value must be between -20 and 19");
+        }
+    }
+    if let Some(pressure) = max_memory_pressure {
+        if !(1..=100).contains(&pressure) {
+            return invalid(
+                "max_memory_pressure",
+                pressure.to_string(),
+                "memory pressure must be between 1 and 100",
+            );
+        }
+    }
+    #[cfg(target_os = "android")]
+    let _ = stop_property;
+    #[cfg(not(target_os = "android"))]
+    if let Some(property) = stop_property {
+        return invalid(
+            "stop_property",
+            property.to_owned(),
+            "system properties are only supported on android",
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{FileId, InodeInfo, Record};
+    use crate::replay::tests::generate_cached_files_and_record;
+    use crate::replay::READ_SZ;
+    use crate::tracer::tests::setup_test_dir;
+    use std::collections::HashMap;
+
+    // Collects the progress of a replay.
+    #[derive(Default)]
+    struct ProgressRecorder {
+        opened: Mutex<HashMap<FileId, usize>>,
+        failed_files: Mutex<Vec<FileId>>,
+        replayed: Mutex<Vec<(Record, u64)>>,
+        failed_records: Mutex<Vec<Record>>,
+    }
+
+    impl ReplayProgress for ProgressRecorder {
+        fn file_opened(&self, file_id: &FileId, result: Result<(), &Error>) {
+            match result {
+                Ok(()) => *self.opened.lock().unwrap().entry(file_id.clone()).or_default() += 1,
+                Err(_) => self.failed_files.lock().unwrap().push(file_id.clone()),
+            }
+        }
+
+        fn record_replayed(&self, record: &Record, result: Result<u64, &Error>) {
+            match result {
+                Ok(bytes) => self.replayed.lock().unwrap().push((record.clone(), bytes)),
+                Err(_) => self.failed_records.lock().unwrap().push(record.clone()),
+            }
+        }
+    }
+
+    fn test_builder_progress_internal(engine: ReplayEngine) {
+        let (rf, files) = generate_cached_files_and_record(None, false, None);
+        let excluded_path = files[0].0.path().to_str().unwrap().to_owned();
+        let excluded_id = rf
+            .inner
+            .inode_map
+            .iter()
+            .find(|(_, info)| info.paths.contains(&excluded_path))
+            .map(|(id, _)| id.clone())
+            .unwrap();
+        let mut expected = rf.inner.records.clone();
+        expected.retain(|record| record.file_id != excluded_id);
+
+        let progress = Arc::new(ProgressRecorder::default());
+        ReplayBuilder::new(rf.clone())
+            .exclude_files_regex(vec![regex::escape(&excluded_path)])
+            .engine(engine)
+            .io_depth(1)
+            .progress(progress.clone())
+            .build()
+            .unwrap()
+            .replay()
+            .unwrap();
+
+        let mut replayed = progress.replayed.lock().unwrap().clone();
+        replayed.sort_by_key(|(record, _)| (record.file_id.clone(), record.offset));
+        expected.sort_by_key(|record| (record.file_id.clone(), record.offset));
+        assert_eq!(replayed.iter().map(|(record, _)| record.clone()).collect::<Vec<_>>(), expected);
+        // The last page of a file is only partly read. io_uring reports the bytes actually
+        // read.
+        for (record, bytes) in &replayed {
+            let expected = match engine {
+                ReplayEngine::IoUring => {
+                    let size = rf.inner.inode_map[&record.file_id].file_size;
+                    record.length.min(size.saturating_sub(record.offset))
+                }
+                ReplayEngine::ThreadPool => record.length,
+            };
+            assert_eq!(*bytes, expected);
+        }
+
+        // Excluded files fail to open for each of their records.
+        let excluded_records = rf.inner.records.len() - expected.len();
+        assert_eq!(progress.failed_files.lock().unwrap().len(), excluded_records);
+        assert_eq!(progress.failed_records.lock().unwrap().len(), excluded_records);
+        let opened = progress.opened.lock().unwrap();
+        assert_eq!(opened.len(), 2);
+        assert!(opened.values().all(|count| *count == 1));
+    }
+
+    #[test]
+    fn test_builder_progress() {
+        test_builder_progress_internal(ReplayEngine::ThreadPool);
+    }
+
+    #[test]
+    fn test_builder_progress_io_uring() {
+        test_builder_progress_internal(ReplayEngine::IoUring);
+    }
+
+    #[test]
+    fn test_builder_progress_io_uring_read_error() {
+        // Reads of a directory fail, so no chunk of the record gets replayed.
+        let dir = setup_test_dir();
+        let mut rf = RecordsFile::default();
+        rf.insert_or_update_inode_info(
+            FileId(0),
+            InodeInfo::new(0, 0, vec![dir.display().to_string()], 0),
+        );
+        let record =
+            Record { file_id: FileId(0), offset: 0, length: 3 * READ_SZ as u64, timestamp: 0 };
+        rf.insert_record(record.clone());
+
+        let progress = Arc::new(ProgressRecorder::default());
+        ReplayBuilder::new(rf)
+            .engine(ReplayEngine::IoUring)
+            .exit_on_error(false)
+            .progress(progress.clone())
+            .build()
+            .unwrap()
+            .replay()
+            .unwrap();
+        assert!(progress.replayed.lock().unwrap().is_empty());
+        assert_eq!(*progress.failed_records.lock().unwrap(), vec![record]);
+    }
+
+    // Replays a zero length record and a record that reads past the end of its file.
+    fn replay_empty_and_short_records(engine: ReplayEngine) -> Vec<(Record, u64)> {
+        let path = setup_test_dir().join("short");
+        std::fs::write(&path, vec![1u8; 100]).unwrap();
+        let mut rf = RecordsFile::default();
+        rf.insert_or_update_inode_info(
+            FileId(0),
+            InodeInfo::new(0, 100, vec![path.display().to_string()], 0),
+        );
+        rf.insert_record(Record {
+            file_id: FileId(0),
+            offset: 0,
+            length: 2 * READ_SZ as u64,
+            timestamp: 1,
+        });
+
+        let progress = Arc::new(ProgressRecorder::default());
+        let replay = ReplayBuilder::new(rf)
+            .engine(engine)
+            .io_depth(1)
+            .progress(progress.clone())
+            .build()
+            .unwrap();
+        // The config drops zero length records, so the record is added after it is applied.
+        replay
+            .records_file
+            .write()
+            .unwrap()
+            .inner
+            .records
+            .insert(0, Record { file_id: FileId(0), offset: 0, length: 0, timestamp: 0 });
+        replay.replay().unwrap();
+        let mut replayed = progress.replayed.lock().unwrap().clone();
+        replayed.sort_by_key(|(record, _)| record.timestamp);
+        replayed
+    }
+
+    #[test]
+    fn test_builder_progress_empty_and_short_records() {
+        let replayed = replay_empty_and_short_records(ReplayEngine::ThreadPool);
+        assert_eq!(replayed.len(), 2);
+        assert_eq!(replayed[0].1, 0);
+
+        // io_uring reports the bytes actually read.
+        let replayed = replay_empty_and_short_records(ReplayEngine::IoUring);
+        assert_eq!(replayed.len(), 2);
+        assert_eq!(replayed[0].1, 0);
+        assert_eq!(replayed[1].1, 100);
+    }
+
+    #[test]
+    fn test_builder_validation() {
+        let build_error = |builder: ReplayBuilder| match builder.build().unwrap_err() {
+            Error::InvalidArgs { arg_name, .. } => arg_name,
+            e => panic!("unexpected error {:?}", e),
+        };
+        let builder = || ReplayBuilder::new(RecordsFile::default());
+
+        assert_eq!(
+            build_error(builder().engine(ReplayEngine::IoUring).queue_depth(0)),
+            "queue_depth"
+        );
+        assert_eq!(
+            build_error(builder().engine(ReplayEngine::IoUring).strategy(ReplayStrategy::Mmap)),
+            "strategy"
+        );
+        assert_eq!(build_error(builder().ioprio(IoPriorityClass::Idle, 8)), "ioprio_level");
+        assert_eq!(build_error(builder().nice(20)), "nice");
+        assert_eq!(build_error(builder().max_memory_pressure(0)), "max_memory_pressure");
+        assert_eq!(
+            build_error(builder().exclude_files_regex(vec!["(".to_owned()])),
+            "files_to_exclude_regex[0]"
+        );
+        builder().build().unwrap();
+    }
+}
@@ -0,0 +1,249 @@
+// Copyright (C) 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Stops replay early.
+//!
+//! Replay is cancelled once a deadline passes, once a system property is set, or once memory
+//! pressure crosses a threshold. Prefetching while the device is thrashing evicts pages that
+//! are in use and does more harm than good.
+
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::replay::pace::Clock;
+
+const MEMORY_PRESSURE_PATH: &str = "/proc/pressure/memory";
+
+// The stop property and memory pressure are polled at most this often. PSI averages are only
+// updated every two seconds, so polling more often gains little.
+pub(super) const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Why replay was cancelled.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CancelReason {
+    /// Replay ran past its deadline.
+    Deadline,
+    /// The stop property was set.
+    StopProperty,
+    /// Memory pressure crossed the threshold.
+    MemoryPressure,
+}
+
+/// Conditions under which replay is cancelled.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub(crate) struct CancelConditions {
+    /// Replay is cancelled once this much time has passed since it started.
+    pub deadline: Option<Duration>,
+    /// Replay is cancelled once this system property is set to true.
+    pub stop_property: Option<String>,
+    /// Replay is cancelled once the share of time, in percent, some tasks were stalled on
+    /// memory over the last 10 seconds reaches this value.
+    pub max_memory_pressure: Option<u32>,
+}
+
+impl CancelConditions {
+    pub fn is_enabled(&self) -> bool {
+        self.deadline.is_some()
+            || self.stop_property.is_some()
+            || self.max_memory_pressure.is_some()
+    }
+}
+
+// Returns the `some avg10` value of the contents of a PSI file.
+fn parse_memory_pressure(contents: &str) -> Option<f64> {
+    let line = contents.lines().find(|line| line.starts_with("some "))?;
+    line.split_whitespace().find_map(|field| field.strip_prefix("avg10=")?.parse().ok())
+}
+
+#[cfg(target_os = "android")]
+fn is_property_set(name: &str) -> bool {
+    rustutils::system_properties::read_bool(name, false).unwrap_or_else(|e| {
+        warn!("failed to read {name}: {e}");
+        false
+    })
+}
+
+// System properties only exist on android. Args verification rejects a stop property
+// elsewhere.
+#[cfg(not(target_os = "android"))]
+fn is_property_set(_name: &str) -> bool {
+    false
+}
+
+#[derive(Debug, Default)]
+struct CancelState {
+    next_poll: Duration,
+    memory_pressure_unavailable: bool,
+    reason: Option<CancelReason>,
+}
+
+/// Tells replay workers when to stop. Once cancelled, it stays cancelled.
+pub(crate) struct CancelToken {
+    conditions: CancelConditions,
+    clock: Arc<dyn Clock>,
+    memory_pressure_path: PathBuf,
+    state: Mutex<CancelState>,
+}
+
+impl CancelToken {
+    /// Creates a token for `conditions`. The deadline is measured by `clock`.
+    pub fn new(conditions: CancelConditions, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            conditions,
+            clock,
+            memory_pressure_path: PathBuf::from(MEMORY_PRESSURE_PATH),
+            state: Mutex::new(CancelState::default()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.conditions.is_enabled()
+    }
+
+    // Returns true if memory pressure is at or above `threshold`. Kernels without PSI disable
+    // the check.
+    fn memory_pressure_exceeds(&self, state: &mut CancelState, threshold: u32) -> bool {
+        if state.memory_pressure_unavailable {
+            return false;
+        }
+        let pressure = fs::read_to_string(&self.memory_pressure_path)
+            .ok()
+            .and_then(|contents| parse_memory_pressure(&contents));
+        match pressure {
+            Some(pressure) => pressure >= threshold as f64,
+            None => {
+                warn!(
+                    "memory pressure is not available from {}",
+                    self.memory_pressure_path.display()
+                );
+                state.memory_pressure_unavailable = true;
+                false
+            }
+        }
+    }
+
+    /// Checks the conditions and returns true if replay should stop.
+    pub fn is_cancelled(&self) -> bool {
+        if !self.is_enabled() {
+            return false;
+        }
+        let mut state = self.state.lock().unwrap();
+        if state.reason.is_some() {
+            return true;
+        }
+
+        let elapsed = self.clock.elapsed();
+        if self.conditions.deadline.is_some_and(|deadline| elapsed >= deadline) {
+            state.reason = Some(CancelReason::Deadline);
+        } else if elapsed >= state.next_poll {
+            state.next_poll = elapsed + CANCEL_POLL_INTERVAL;
+            if self.conditions.stop_property.as_deref().is_some_and(is_property_set) {
+                state.reason = Some(CancelReason::StopProperty);
+            } else if let Some(threshold) = self.conditions.max_memory_pressure {
+                if self.memory_pressure_exceeds(&mut state, threshold) {
+                    state.reason = Some(CancelReason::MemoryPressure);
+                }
+            }
+        }
+        state.reason.is_some()
+    }
+
+    /// Returns why replay was cancelled, if it was.
+    pub fn reason(&self) -> Option<CancelReason> {
+        self.state.lock().unwrap().reason
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::replay::pace::tests::FakeClock;
+    use crate::tracer::tests::setup_test_dir;
+
+    const PSI: &str = "some avg10=12.50 avg60=3.00 avg300=1.00 total=123456\n\
+                       full avg10=2.00 avg60=0.50 avg300=0.10 total=2345\n";
+
+    #[test]
+    fn test_parse_memory_pressure() {
+        assert_eq!(parse_memory_pressure(PSI), Some(12.5));
+        assert_eq!(parse_memory_pressure("full avg10=2.00 avg60=0.50"), None);
+        assert_eq!(parse_memory_pressure(""), None);
+    }
+
+    #[test]
+    fn test_no_conditions() {
+        let clock = Arc::new(FakeClock::default());
+        let token = CancelToken::new(CancelConditions::default(), clock.clone());
+        clock.sleep(Duration::from_secs(3600));
+        assert!(!token.is_cancelled());
+        assert_eq!(token.reason(), None);
+    }
+
+    #[test]
+    fn test_deadline() {
+        let clock = Arc::new(FakeClock::default());
+        let conditions =
+            CancelConditions { deadline: Some(Duration::from_millis(50)), ..Default::default() };
+        let token = CancelToken::new(conditions, clock.clone());
+        assert!(!token.is_cancelled());
+        clock.sleep(Duration::from_millis(50));
+        assert!(token.is_cancelled());
+        assert_eq!(token.reason(), Some(CancelReason::Deadline));
+    }
+
+    #[test]
+    fn test_memory_pressure() {
+        let psi = setup_test_dir().join("memory");
+        fs::write(&psi, PSI.replace("12.50", "5.00")).unwrap();
+        let clock = Arc::new(FakeClock::default());
+        let conditions = CancelConditions { max_memory_pressure: Some(10), ..Default::default() };
+        let token = CancelToken {
+            memory_pressure_path: psi.clone(),
+            ..CancelToken::new(conditions, clock.clone())
+        };
+        assert!(!token.is_cancelled());
+
+        // Pressure is not polled again until the poll interval passes.
+        fs::write(&psi, PSI).unwrap();
+        assert!(!token.is_cancelled());
+        clock.sleep(CANCEL_POLL_INTERVAL);
+        assert!(token.is_cancelled());
+        assert_eq!(token.reason(), Some(CancelReason::MemoryPressure));
+
+        // Cancellation sticks.
+        fs::write(&psi, PSI.replace("12.50", "0.00")).unwrap();
+        clock.sleep(CANCEL_POLL_INTERVAL);
+        assert!(token.is_cancelled());
+    }
+
+    #[test]
+    fn test_memory_pressure_unavailable() {
+        let clock = Arc::new(FakeClock::default());
+        let conditions = CancelConditions { max_memory_pressure: Some(10), ..Default::default() };
+        let token = CancelToken {
+            memory_pressure_path: setup_test_dir().join("missing"),
+            ..CancelToken::new(conditions, clock.clone())
+        };
+        assert!(!token.is_cancelled());
+        clock.sleep(CANCEL_POLL_INTERVAL);
+        assert!(!token.is_cancelled());
+        assert!(token.state.lock().unwrap().memory_pressure_unavailable);
+    }
+}
@@ -0,0 +1,316 @@
+// Copyright (C) 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Validated form of the replay config file and the changes it makes to the records to replay.
+
+use std::cmp::{min, Reverse};
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
+
+use regex::Regex;
+
+use crate::args::{AdditionalFilesPosition, ConfigFile, CONFIG_VERSION};
+use crate::format::{FileId, RecordsFile};
+use crate::replay::additional_files::add_additional_replay_files;
+use crate::Error;
+
+#[derive(Debug, Default)]
+pub(super) struct ReplayConfig {
+    pub exclude_files_regex: Vec<Regex>,
+    include_files_regex: Vec<Regex>,
+    priorities: Vec<(Regex, i32)>,
+    max_bytes_per_file: Option<u64>,
+    max_total_bytes: Option<u64>,
+    additional_replay_files: Vec<String>,
+    additional_replay_files_position: AdditionalFilesPosition,
+}
+
+// Compiles `patterns` of the config file field `field`. Errors name the offending entry.
+fn compile_regexes<'a>(
+    field: &str,
+    patterns: impl Iterator<Item = &'a String>,
+) -> Result<Vec<Regex>, Error> {
+    patterns
+        .enumerate()
+        .map(|(i, pattern)| {
+            Regex::new(pattern).map_err(|e| Error::InvalidArgs {
+                arg_name: format!("{field}[{i}]"),
+                arg_value: pattern.to_owned(),
+                error: e.to_string(),
+            })
+        })
+        .collect()
+}
+
+fn check_cap(field: &str, cap: Option<u64>) -> Result<Option<u64>, Error> {
+    if cap == Some(0) {
+        return Err(Error::InvalidArgs {
+            arg_name: field.to_owned(),
+            arg_value: "0".to_owned(),
+            error: "must be greater than zero".to_owned(),
+        });
+    }
+    Ok(cap)
+}
+
+/// Reads the config file at `path`.
+pub(super) fn load_config_file(path: &Path) -> Result<ConfigFile, Error> {
+    let reader = File::open(path)
+        .map_err(|source| Error::Open { source, path: path.display().to_string() })?;
+    serde_json::from_reader(reader).map_err(|error| Error::Deserialize { error: error.to_string() })
+}
+
+impl ReplayConfig {
+    /// Validates `cf`.
+    pub fn new(cf: &ConfigFile) -> Result<Self, Error> {
+        if cf.version == 0 || cf.version > CONFIG_VERSION {
+            return Err(Error::InvalidArgs {
+                arg_name: "version".to_owned(),
+                arg_value: cf.version.to_string(),
+                error: format!("supported versions are 1 to {CONFIG_VERSION}"),
+            });
+        }
+
+        let priority_regexes =
+            compile_regexes("priorities", cf.priorities.iter().map(|p| &p.regex))?;
+        Ok(Self {
+            exclude_files_regex: compile_regexes(
+                "files_to_exclude_regex",
+                cf.files_to_exclude_regex.iter(),
+            )?,
+            include_files_regex: compile_regexes(
+                "files_to_include_regex",
+                cf.files_to_include_regex.iter(),
+            )?,
+            priorities: priority_regexes
+                .into_iter()
+                .zip(cf.priorities.iter().map(|p| p.priority))
+                .collect(),
+            max_bytes_per_file: check_cap("max_bytes_per_file", cf.max_bytes_per_file)?,
+            max_total_bytes: check_cap("max_total_bytes", cf.max_total_bytes)?,
+            additional_replay_files: cf.additional_replay_files.clone(),
+            additional_replay_files_position: cf.additional_replay_files_position,
+        })
+    }
+
+    /// Applies the config to the records to replay.
+    ///
+    /// Additional replay files are added, records of files that are not included are dropped,
+    /// records are ordered by priority and then trimmed to fit the byte caps. Records of
+    /// excluded files are left in place so that replay reports them as skipped; they do not
+    /// count towards the caps.
+    pub fn apply(&self, rf: &mut RecordsFile) -> Result<(), Error> {
+        add_additional_replay_files(
+            rf,
+            &self.additional_replay_files,
+            self.additional_replay_files_position,
+        )?;
+
+        let matches = |regexes: &[Regex], id: &FileId| {
+            rf.inner.inode_map.get(id).is_some_and(|info| {
+                info.paths.iter().any(|path| regexes.iter().any(|regex| regex.is_match(path)))
+            })
+        };
+
+        let mut included: HashMap<FileId, bool> = HashMap::new();
+        let mut priorities: HashMap<FileId, i32> = HashMap::new();
+        let mut excluded: HashMap<FileId, bool> = HashMap::new();
+        for id in rf.inner.inode_map.keys() {
+            included.insert(
+                id.clone(),
+                self.include_files_regex.is_empty() || matches(&self.include_files_regex, id),
+            );
+            let priority = self
+                .priorities
+                .iter()
+                .find(|(regex, _)| matches(std::slice::from_ref(regex), id))
+                .map_or(0, |(_, priority)| *priority);
+            priorities.insert(id.clone(), priority);
+            // Mirrors `RecordsFile::open_file` which only looks at the first path.
+            let first_path_excluded = rf.inner.inode_map[id].paths.first().is_some_and(|path| {
+                self.exclude_files_regex.iter().any(|regex| regex.is_match(path))
+            });
+            excluded.insert(id.clone(), first_path_excluded);
+        }
+
+        let mut records = std::mem::take(&mut rf.inner.records);
+        records.retain(|record| included.get(&record.file_id).copied().unwrap_or(true));
+        records.sort_by_key(|record| {
+            Reverse(priorities.get(&record.file_id).copied().unwrap_or_default())
+        });
+
+        let mut file_bytes: HashMap<FileId, u64> = HashMap::new();
+        let mut total_bytes = 0;
+        records.retain_mut(|record| {
+            if excluded.get(&record.file_id).copied().unwrap_or(false) {
+                return true;
+            }
+            let used = file_bytes.entry(record.file_id.clone()).or_default();
+            let mut allowed = record.length;
+            if let Some(cap) = self.max_bytes_per_file {
+                allowed = min(allowed, cap.saturating_sub(*used));
+            }
+            if let Some(cap) = self.max_total_bytes {
+                allowed = min(allowed, cap.saturating_sub(total_bytes));
+            }
+            *used += allowed;
+            total_bytes += allowed;
+            record.length = allowed;
+            allowed > 0
+        });
+
+        rf.inner.records = records;
+        rf.remove_orphans();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::args::PathPriority;
+    use crate::format::{InodeInfo, Record};
+
+    // Builds a records file with a file per path. Each file has two records of `length` bytes.
+    fn records_file(paths: &[&str], length: u64) -> RecordsFile {
+        let mut rf = RecordsFile::default();
+        for (i, path) in paths.iter().enumerate() {
+            let id = FileId(i as u64);
+            rf.insert_or_update_inode_info(
+                id.clone(),
+                InodeInfo::new(i as u64, 2 * length, vec![path.to_string()], 0),
+            );
+            for offset in [0, length] {
+                rf.insert_record(Record {
+                    file_id: id.clone(),
+                    offset,
+                    length,
+                    timestamp: i as u64,
+                });
+            }
+        }
+        rf
+    }
+
+    fn replayed(rf: &RecordsFile) -> Vec<(String, u64, u64)> {
+        rf.inner
+            .records
+            .iter()
+            .map(|r| (rf.inner.inode_map[&r.file_id].paths[0].clone(), r.offset, r.length))
+            .collect()
+    }
+
+    fn entry(path: &str, offset: u64, length: u64) -> (String, u64, u64) {
+        (path.to_owned(), offset, length)
+    }
+
+    fn invalid_arg_name(cf: ConfigFile) -> String {
+        match ReplayConfig::new(&cf).unwrap_err() {
+            Error::InvalidArgs { arg_name, .. } => arg_name,
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_invalid_entries_are_named() {
+        let cf = ConfigFile {
+            files_to_exclude_regex: vec!["a".to_owned(), "(".to_owned()],
+            ..Default::default()
+        };
+        assert_eq!(invalid_arg_name(cf), "files_to_exclude_regex[1]");
+
+        let cf = ConfigFile { files_to_include_regex: vec!["[".to_owned()], ..Default::default() };
+        assert_eq!(invalid_arg_name(cf), "files_to_include_regex[0]");
+
+        let cf = ConfigFile {
+            priorities: vec![
+                PathPriority { regex: "a".to_owned(), priority: 1 },
+                PathPriority { regex: "b".to_owned(), priority: 2 },
+                PathPriority { regex: "*".to_owned(), priority: 3 },
+            ],
+            ..Default::default()
+        };
+        assert_eq!(invalid_arg_name(cf), "priorities[2]");
+
+        let cf = ConfigFile { max_total_bytes: Some(0), ..Default::default() };
+        assert_eq!(invalid_arg_name(cf), "max_total_bytes");
+
+        let cf = ConfigFile { version: CONFIG_VERSION + 1, ..Default::default() };
+        assert_eq!(invalid_arg_name(cf), "version");
+    }
+
+    #[test]
+    fn test_unversioned_config_file() {
+        let cf: ConfigFile =
+            serde_json::from_str(r#"{"files_to_exclude_regex":[],"additional_replay_files":[]}"#)
+                .unwrap();
+        assert_eq!(cf.version, 1);
+        ReplayConfig::new(&cf).unwrap();
+    }
+
+    #[test]
+    fn test_include_and_priorities() {
+        let mut rf = records_file(&["/system/a", "/vendor/b", "/system/c", "/data/d"], 10);
+        let cf = ConfigFile {
+            files_to_include_regex: vec!["^/system/".to_owned(), "^/vendor/".to_owned()],
+            priorities: vec![
+                PathPriority { regex: "/c$".to_owned(), priority: 10 },
+                PathPriority { regex: "^/vendor/".to_owned(), priority: 5 },
+                PathPriority { regex: "^/system/".to_owned(), priority: -1 },
+            ],
+            ..Default::default()
+        };
+        ReplayConfig::new(&cf).unwrap().apply(&mut rf).unwrap();
+        assert_eq!(
+            replayed(&rf),
+            vec![
+                entry("/system/c", 0, 10),
+                entry("/system/c", 10, 10),
+                entry("/vendor/b", 0, 10),
+                entry("/vendor/b", 10, 10),
+                entry("/system/a", 0, 10),
+                entry("/system/a", 10, 10),
+            ]
+        );
+        assert_eq!(rf.inner.inode_map.len(), 3);
+        rf.check().unwrap();
+    }
+
+    #[test]
+    fn test_byte_caps() {
+        let mut rf = records_file(&["/a", "/b", "/excluded", "/c"], 10);
+        let cf = ConfigFile {
+            files_to_exclude_regex: vec!["excluded".to_owned()],
+            max_bytes_per_file: Some(15),
+            max_total_bytes: Some(35),
+            ..Default::default()
+        };
+        let config = ReplayConfig::new(&cf).unwrap();
+        config.apply(&mut rf).unwrap();
+        assert_eq!(
+            replayed(&rf),
+            vec![
+                entry("/a", 0, 10),
+                entry("/a", 10, 5),
+                entry("/b", 0, 10),
+                entry("/b", 10, 5),
+                entry("/excluded", 0, 10),
+                entry("/excluded", 10, 10),
+                entry("/c", 0, 5),
+            ]
+        );
+        rf.check().unwrap();
+    }
+}
@@ -0,0 +1,355 @@
+// Copyright (C) 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Reorders records by the physical location of their data.
+//!
+//! Records are mapped to their physical extents with the FIEMAP ioctl. Within a window of
+//! recorded time, the resulting reads are sorted by device and physical block so that the
+//! storage device sees mostly sequential reads.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+
+use log::debug;
+use regex::Regex;
+
+use crate::format::{DeviceNumber, FileId, Record, RecordsFile};
+use crate::Error;
+
+// See include/uapi/linux/fiemap.h
+const FS_IOC_FIEMAP: libc::c_ulong = 0xC020660B;
+const FIEMAP_EXTENT_LAST: u32 = 0x00000001;
+// Extents whose physical location is not known or does not map to device blocks.
+const FIEMAP_EXTENT_UNMAPPED_FLAGS: u32 = 0x00000002 // UNKNOWN
+    | 0x00000004 // DELALLOC
+    | 0x00000008 // ENCODED
+    | 0x00000080 // DATA_ENCRYPTED
+    | 0x00000200 // DATA_INLINE
+    | 0x00000400; // DATA_TAIL
+
+// Number of extents fetched per ioctl.
+const FIEMAP_BATCH: usize = 32;
+
+// Used when the records file does not know the block size of a filesystem.
+const DEFAULT_BLOCK_SIZE: u64 = 4096;
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct FiemapExtent {
+    fe_logical: u64,
+    fe_physical: u64,
+    fe_length: u64,
+    fe_reserved64: [u64; 2],
+    fe_flags: u32,
+    fe_reserved: [u32; 3],
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct Fiemap {
+    fm_start: u64,
+    fm_length: u64,
+    fm_flags: u32,
+    fm_mapped_extents: u32,
+    fm_extent_count: u32,
+    fm_reserved: u32,
+    fm_extents: [FiemapExtent; FIEMAP_BATCH],
+}
+
+/// A logically contiguous range of a file.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(super) struct Extent {
+    /// Offset of the range in the file.
+    pub logical: u64,
+    /// Offset of the range on the device. None if the location is not known.
+    pub physical: Option<u64>,
+    /// Length of the range.
+    pub length: u64,
+}
+
+/// Returns the extents of `file` that overlap `length` bytes starting at `offset`. Holes are
+/// not returned.
+pub(super) fn file_extents(file: &File, offset: u64, length: u64) -> Result<Vec<Extent>, Error> {
+    let end = offset.saturating_add(length);
+    let mut extents = vec![];
+    let mut start = offset;
+    while start < end {
+        let mut fiemap = Fiemap {
+            fm_start: start,
+            fm_length: end - start,
+            fm_extent_count: FIEMAP_BATCH as u32,
+            ..Default::default()
+        };
+        // SAFETY: This is safe because fiemap is laid out as the kernel expects and has room
+        // for fm_extent_count extents.
+        let ret = unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_FIEMAP as _, &mut fiemap) };
+        if ret < 0 {
+            return Err(Error::Custom {
+                error: format!("FIEMAP failed: {}", io::Error::last_os_error()),
+            });
+        }
+
+        let mapped = &fiemap.fm_extents[..fiemap.fm_mapped_extents as usize];
+        for extent in mapped {
+            extents.push(Extent {
+                logical: extent.fe_logical,
+                physical: if extent.fe_flags & FIEMAP_EXTENT_UNMAPPED_FLAGS == 0 {
+                    Some(extent.fe_physical)
+                } else {
+                    None
+                },
+                length: extent.fe_length,
+            });
+        }
+        match mapped.last() {
+            Some(last) if last.fe_flags & FIEMAP_EXTENT_LAST == 0 => {
+                start = last.fe_logical + last.fe_length;
+            }
+            _ => break,
+        }
+    }
+    Ok(extents)
+}
+
+// Splits `record` into a record per extent. Returns the record as is, with an unknown
+// location, if none of its data is mapped.
+fn split_record(record: &Record, extents: &[Extent]) -> Vec<(Record, Option<u64>)> {
+    let end = record.offset + record.length;
+    let pieces: Vec<(Record, Option<u64>)> = extents
+        .iter()
+        .filter_map(|extent| {
+            let start = extent.logical.max(record.offset);
+            let piece_end = (extent.logical + extent.length).min(end);
+            if start >= piece_end {
+                return None;
+            }
+            let piece = Record { offset: start, length: piece_end - start, ..record.clone() };
+            Some((piece, extent.physical.map(|physical| physical + (start - extent.logical))))
+        })
+        .collect();
+    if pieces.is_empty() {
+        vec![(record.clone(), None)]
+    } else {
+        pieces
+    }
+}
+
+// A piece of a record along with where it goes in the replay order.
+struct Piece {
+    window: u64,
+    device: DeviceNumber,
+    block: Option<u64>,
+    physical: Option<u64>,
+    record: Record,
+}
+
+/// Reorders `records` by physical location within windows of `window` of recorded time.
+///
+/// `map` returns the device, block size and extents of a record, or None if the record cannot
+/// be mapped. Records that cannot be mapped keep their order and go after the mapped records
+/// of their window. Pieces of a file that are contiguous both in the file and on the device
+/// are merged.
+pub(super) fn sort_records_by_extents<F>(
+    records: &[Record],
+    window: Duration,
+    mut map: F,
+) -> Vec<Record>
+where
+    F: FnMut(&Record) -> Option<(DeviceNumber, u64, Vec<Extent>)>,
+{
+    let base = records.iter().map(|record| record.timestamp).min().unwrap_or(0);
+    let window = (window.as_nanos() as u64).max(1);
+
+    let mut pieces = vec![];
+    for record in records {
+        let window_index = (record.timestamp - base) / window;
+        match map(record) {
+            Some((device, block_size, extents)) => {
+                for (record, physical) in split_record(record, &extents) {
+                    pieces.push(Piece {
+                        window: window_index,
+                        device,
+                        block: physical.map(|physical| physical / block_size),
+                        physical,
+                        record,
+                    })
+                }
+            }
+            None => pieces.push(Piece {
+                window: window_index,
+                device: 0,
+                block: None,
+                physical: None,
+                record: record.clone(),
+            }),
+        }
+    }
+
+    // Unmapped pieces sort after the mapped ones. The sort is stable, so they keep their
+    // relative order.
+    pieces.sort_by_key(|piece| {
+        (piece.window, piece.block.is_none(), piece.device, piece.block, piece.physical)
+    });
+
+    let mut sorted: Vec<Piece> = Vec::with_capacity(pieces.len());
+    for piece in pieces {
+        if let Some(last) = sorted.last_mut() {
+            let contiguous = last.window == piece.window
+                && last.record.file_id == piece.record.file_id
+                && last.record.offset + last.record.length == piece.record.offset
+                && last.physical.is_some()
+                && last.physical.map(|physical| physical + last.record.length) == piece.physical;
+            if contiguous {
+                last.record.length += piece.record.length;
+                last.record.timestamp = last.record.timestamp.min(piece.record.timestamp);
+                continue;
+            }
+        }
+        sorted.push(piece);
+    }
+    sorted.into_iter().map(|piece| piece.record).collect()
+}
+
+/// Reorders the records of `rf` by physical location within windows of `window` of recorded
+/// time. Files that cannot be opened or mapped keep their records unchanged.
+///
+/// Files are opened and mapped one at a time, so only their extents are held at once.
+pub(super) fn sort_by_extents(rf: &mut RecordsFile, window: Duration, exclude: &[Regex]) {
+    let records = std::mem::take(&mut rf.inner.records);
+    let mut file_records: HashMap<&FileId, Vec<&Record>> = HashMap::new();
+    for record in &records {
+        file_records.entry(&record.file_id).or_default().push(record);
+    }
+
+    let mut extents: HashMap<(&FileId, u64, u64), Vec<Extent>> = HashMap::new();
+    for (id, file_records) in file_records {
+        let file = match rf.open_file(id.clone(), exclude) {
+            Ok(file) => file,
+            Err(_) => continue,
+        };
+        for record in file_records {
+            match file_extents(&file, record.offset, record.length) {
+                Ok(record_extents) => {
+                    extents.insert((id, record.offset, record.length), record_extents);
+                }
+                Err(e) => debug!("failed to map {record:?}: {e}"),
+            }
+        }
+    }
+
+    let sorted = sort_records_by_extents(&records, window, |record| {
+        let info = rf.inner.inode_map.get(&record.file_id)?;
+        let extents = extents.get(&(&record.file_id, record.offset, record.length))?.clone();
+        let block_size = rf
+            .inner
+            .filesystems
+            .get(&info.device_number)
+            .map_or(DEFAULT_BLOCK_SIZE, |fs| fs.block_size.max(1));
+        Some((info.device_number, block_size, extents))
+    });
+    rf.inner.records = sorted;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tracer::tests::setup_test_dir;
+    use std::io::Write;
+
+    const MS: u64 = 1_000_000;
+
+    fn record(file_id: u64, offset: u64, length: u64, timestamp_ms: u64) -> Record {
+        Record { file_id: FileId(file_id), offset, length, timestamp: timestamp_ms * MS }
+    }
+
+    fn extent(logical: u64, physical: u64, length: u64) -> Extent {
+        Extent { logical, physical: Some(physical), length }
+    }
+
+    #[test]
+    fn test_sort_within_window() {
+        let records = vec![
+            record(0, 0, 4096, 0),
+            record(1, 0, 4096, 5),
+            record(2, 0, 4096, 8),
+            // Falls in the second window.
+            record(3, 0, 4096, 12),
+        ];
+        let physical = [40960, 8192, 0, 4096];
+        let sorted = sort_records_by_extents(&records, Duration::from_millis(10), |r| {
+            Some((1, 4096, vec![extent(0, physical[r.file_id.0 as usize], 4096)]))
+        });
+        let order: Vec<u64> = sorted.iter().map(|r| r.file_id.0).collect();
+        assert_eq!(order, vec![2, 1, 0, 3]);
+    }
+
+    #[test]
+    fn test_split_and_merge() {
+        // File 0 is stored in two extents in reverse order. File 1 sits between them.
+        let records = vec![record(0, 0, 8192, 0), record(1, 0, 4096, 1)];
+        let sorted =
+            sort_records_by_extents(&records, Duration::from_secs(1), |r| match r.file_id.0 {
+                0 => Some((1, 4096, vec![extent(0, 12288, 4096), extent(4096, 0, 4096)])),
+                _ => Some((1, 4096, vec![extent(0, 4096, 4096)])),
+            });
+        assert_eq!(
+            sorted,
+            vec![record(0, 4096, 4096, 0), record(1, 0, 4096, 1), record(0, 0, 4096, 0)]
+        );
+
+        // Physically contiguous extents of a file are merged back.
+        let records = vec![record(0, 0, 4096, 0), record(0, 4096, 4096, 2)];
+        let sorted = sort_records_by_extents(&records, Duration::from_secs(1), |r| {
+            Some((1, 4096, vec![extent(r.offset, 8192 + r.offset, 4096)]))
+        });
+        assert_eq!(sorted, vec![record(0, 0, 8192, 0)]);
+    }
+
+    #[test]
+    fn test_unmapped_records_keep_order() {
+        let records = vec![record(0, 0, 4096, 0), record(1, 0, 4096, 1), record(2, 0, 4096, 2)];
+        let sorted = sort_records_by_extents(&records, Duration::from_secs(1), |r| {
+            if r.file_id.0 == 2 {
+                Some((1, 4096, vec![extent(0, 0, 4096)]))
+            } else {
+                None
+            }
+        });
+        let order: Vec<u64> = sorted.iter().map(|r| r.file_id.0).collect();
+        assert_eq!(order, vec![2, 0, 1]);
+    }
+
+    #[test]
+    fn test_file_extents() {
+        let path = setup_test_dir().join("extents");
+        let mut file = File::create(&path).unwrap();
+        file.write_all(&vec![1u8; 64 * 1024]).unwrap();
+        file.sync_all().unwrap();
+        let file = File::open(&path).unwrap();
+
+        let extents = match file_extents(&file, 4096, 16384) {
+            Ok(extents) => extents,
+            // Not all filesystems support FIEMAP.
+            Err(_) => return,
+        };
+        assert!(!extents.is_empty());
+        let first = extents.first().unwrap();
+        let last = extents.last().unwrap();
+        assert!(first.logical <= 4096);
+        assert!(last.logical + last.length >= 4096 + 16384);
+    }
+}
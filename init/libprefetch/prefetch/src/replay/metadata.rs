@@ -0,0 +1,194 @@
+// Copyright (C) 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Warms the dentry and inode caches ahead of data replay.
+//!
+//! Opening thousands of files at boot is dominated by path lookup rather than by reads. The
+//! directories of the records file are walked and its files are opened in recorded order so
+//! that lookups during data replay, and by the processes that follow, hit the caches.
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::format::{Record, RecordsFile};
+use crate::replay::cancel::CancelToken;
+use crate::replay::{get_or_open_file, Replay};
+
+/// Outcome of the metadata warming phase.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct MetadataReport {
+    /// Number of directories whose entries were read.
+    pub directories_walked: u64,
+
+    /// Number of directory entries read.
+    pub directory_entries: u64,
+
+    /// Number of file paths stat'ed.
+    pub paths_stated: u64,
+
+    /// Number of files opened ahead of data replay.
+    pub files_opened: u64,
+
+    /// Time taken by the phase in milliseconds.
+    pub wall_time_ms: u64,
+}
+
+// Returns the directories to walk ordered by recorded time: recorded directories along with
+// the directories that contain the files of `rf`. A file's directory is due along with the
+// file's first replayed record.
+fn directories_in_order(rf: &RecordsFile) -> Vec<PathBuf> {
+    let mut events: Vec<(u64, PathBuf)> = vec![];
+    let mut seen_ids = HashSet::new();
+    for record in &rf.inner.records {
+        if !seen_ids.insert(&record.file_id) {
+            continue;
+        }
+        let info = match rf.inner.inode_map.get(&record.file_id) {
+            Some(info) => info,
+            None => continue,
+        };
+        for path in &info.paths {
+            if let Some(parent) = Path::new(path).parent() {
+                events.push((record.timestamp, parent.to_owned()));
+            }
+        }
+    }
+    for record in &rf.inner.directory_records {
+        if !seen_ids.insert(&record.file_id) {
+            continue;
+        }
+        if let Some(info) = rf.inner.directories.get(&record.file_id) {
+            events.extend(info.paths.iter().map(|path| (record.timestamp, PathBuf::from(path))));
+        }
+    }
+    events.sort_by_key(|(timestamp, _)| *timestamp);
+
+    let mut seen_paths = HashSet::new();
+    events
+        .into_iter()
+        .filter_map(|(_, path)| if seen_paths.insert(path.clone()) { Some(path) } else { None })
+        .collect()
+}
+
+// Returns the first record and the paths of each file of `rf` in the order the files are first
+// replayed.
+fn files_in_order(rf: &RecordsFile) -> Vec<(Record, Vec<String>)> {
+    let mut seen = HashSet::new();
+    rf.inner
+        .records
+        .iter()
+        .filter(|record| seen.insert(&record.file_id))
+        .filter_map(|record| {
+            let info = rf.inner.inode_map.get(&record.file_id)?;
+            Some((record.clone(), info.paths.clone()))
+        })
+        .collect()
+}
+
+/// Reads the entries of the directories of the records file of `replay`, stats the paths of
+/// its files and opens the files into the fd cache, all in recorded order.
+///
+/// Files past the capacity of the fd cache push out the files opened before them. Their
+/// lookups are warm all the same. Failures are only logged; data replay reports them.
+pub(super) fn warm_metadata(replay: &Replay, cancel: &CancelToken) -> MetadataReport {
+    let start = Instant::now();
+    let mut report = MetadataReport::default();
+    let (directories, files) = {
+        let rf = replay.records_file.read().unwrap();
+        (directories_in_order(&rf), files_in_order(&rf))
+    };
+
+    for directory in directories {
+        if cancel.is_cancelled() {
+            break;
+        }
+        // Iterating the entries issues getdents.
+        match fs::read_dir(&directory) {
+            Ok(entries) => {
+                report.directories_walked += 1;
+                report.directory_entries += entries.filter(|entry| entry.is_ok()).count() as u64;
+            }
+            Err(e) => debug!("failed to read directory {}: {e}", directory.display()),
+        }
+    }
+
+    for (record, paths) in files {
+        if cancel.is_cancelled() {
+            break;
+        }
+        for path in paths {
+            report.paths_stated += 1;
+            if let Err(e) = fs::metadata(&path) {
+                debug!("failed to stat {path}: {e}");
+            }
+        }
+        match get_or_open_file(
+            &replay.state,
+            &replay.records_file,
+            &record,
+            &replay.exclude_files_regex,
+            replay.progress.as_ref(),
+        ) {
+            Ok(_) => report.files_opened += 1,
+            Err(e) => debug!("failed to open file id {}: {e}", record.file_id),
+        }
+    }
+
+    report.wall_time_ms = start.elapsed().as_millis() as u64;
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{FileId, InodeInfo};
+
+    fn add_file(rf: &mut RecordsFile, id: u64, path: &str, timestamp: u64) {
+        rf.insert_or_update_inode_info(FileId(id), InodeInfo::new(id, 1, vec![path.into()], 0));
+        rf.insert_record(Record { file_id: FileId(id), offset: 0, length: 1, timestamp });
+    }
+
+    #[test]
+    fn test_directories_in_order() {
+        let mut rf = RecordsFile::default();
+        add_file(&mut rf, 0, "/system/lib/a.so", 30);
+        add_file(&mut rf, 1, "/system/bin/b", 10);
+        add_file(&mut rf, 2, "/system/lib/c.so", 5);
+        rf.inner.directories.insert(FileId(3), InodeInfo::new(3, 1, vec!["/vendor".into()], 0));
+        rf.insert_directory_record(Record {
+            file_id: FileId(3),
+            offset: 0,
+            length: 1,
+            timestamp: 20,
+        });
+        rf.check().unwrap();
+
+        assert_eq!(
+            directories_in_order(&rf),
+            vec![
+                PathBuf::from("/system/lib"),
+                PathBuf::from("/system/bin"),
+                PathBuf::from("/vendor"),
+            ]
+        );
+        let files: Vec<FileId> =
+            files_in_order(&rf).into_iter().map(|(record, _)| record.file_id).collect();
+        assert_eq!(files, vec![FileId(0), FileId(1), FileId(2)]);
+    }
+}
@@ -0,0 +1,297 @@
+// Copyright (C) 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Paces replay against the recorded timeline.
+//!
+//! Replaying all records as fast as possible may evict pages that are needed early in favour of
+//! pages that are needed much later. The pacer holds back a record until replay is at most a
+//! given time, or a given number of bytes, ahead of the recorded timeline. The timeline is
+//! relative: the earliest record is due when replay starts.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::format::{Record, RecordsFile};
+use crate::replay::cancel::{CancelToken, CANCEL_POLL_INTERVAL};
+
+/// Source of time for the pacer.
+pub(crate) trait Clock: Send + Sync {
+    /// Returns time elapsed since replay started.
+    fn elapsed(&self) -> Duration;
+
+    /// Blocks the caller for `duration`.
+    fn sleep(&self, duration: Duration);
+}
+
+/// Clock backed by the monotonic system clock. Starts when created.
+pub(crate) struct MonotonicClock {
+    start: Instant,
+}
+
+impl MonotonicClock {
+    pub fn new() -> Self {
+        Self { start: Instant::now() }
+    }
+}
+
+impl Clock for MonotonicClock {
+    fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        std::thread::sleep(duration)
+    }
+}
+
+/// Limits on how far replay can run ahead of the recorded timeline.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub(crate) struct PaceLimits {
+    /// A record is not replayed earlier than this before its recorded time.
+    pub max_ahead_time: Option<Duration>,
+
+    /// Bytes replayed are not allowed to exceed bytes due on the recorded timeline by more than
+    /// this.
+    pub max_ahead_bytes: Option<u64>,
+}
+
+impl PaceLimits {
+    pub fn is_enabled(&self) -> bool {
+        self.max_ahead_time.is_some() || self.max_ahead_bytes.is_some()
+    }
+}
+
+pub(crate) struct Pacer {
+    clock: Arc<dyn Clock>,
+    limits: PaceLimits,
+
+    // Timestamp, in nanoseconds, of the earliest record.
+    base_timestamp: u64,
+
+    // Relative timestamps of all the records in ascending order along with the bytes that are
+    // due by that time.
+    timeline: Vec<(u64, u64)>,
+
+    // Bytes handed out to replay so far.
+    issued_bytes: Mutex<u64>,
+}
+
+impl Pacer {
+    pub fn new(rf: &RecordsFile, limits: PaceLimits, clock: Arc<dyn Clock>) -> Self {
+        let base_timestamp = rf.inner.records.iter().map(|r| r.timestamp).min().unwrap_or(0);
+        let mut events: Vec<(u64, u64)> =
+            rf.inner.records.iter().map(|r| (r.timestamp - base_timestamp, r.length)).collect();
+        events.sort_unstable();
+
+        let mut due = 0;
+        let timeline = events
+            .into_iter()
+            .map(|(timestamp, length)| {
+                due += length;
+                (timestamp, due)
+            })
+            .collect();
+
+        Self { clock, limits, base_timestamp, timeline, issued_bytes: Mutex::new(0) }
+    }
+
+    // Returns the number of bytes due on the recorded timeline at `elapsed`.
+    fn due_bytes(&self, elapsed: Duration) -> u64 {
+        let elapsed = elapsed.as_nanos() as u64;
+        match self.timeline.partition_point(|(timestamp, _)| *timestamp <= elapsed) {
+            0 => 0,
+            index => self.timeline[index - 1].1,
+        }
+    }
+
+    // Returns the time at which at least `bytes` are due on the recorded timeline.
+    fn time_when_due(&self, bytes: u64) -> Duration {
+        let index = self.timeline.partition_point(|(_, due)| *due < bytes);
+        let timestamp = self.timeline.get(index).or(self.timeline.last()).map_or(0, |t| t.0);
+        Duration::from_nanos(timestamp)
+    }
+
+    // Returns how long `record` needs to wait before it can be replayed. Accounts for the
+    // record's bytes if it can be replayed right away.
+    fn try_issue(&self, record: &Record) -> Duration {
+        let elapsed = self.clock.elapsed();
+        let mut wait = Duration::ZERO;
+
+        if let Some(max_ahead_time) = self.limits.max_ahead_time {
+            let due_at = Duration::from_nanos(record.timestamp - self.base_timestamp);
+            wait = wait.max(due_at.saturating_sub(max_ahead_time).saturating_sub(elapsed));
+        }
+
+        let mut issued_bytes = self.issued_bytes.lock().unwrap();
+        if let Some(max_ahead_bytes) = self.limits.max_ahead_bytes {
+            let due_bytes = self.due_bytes(elapsed);
+            // A record larger than the limit is let through once replay has caught up with the
+            // timeline, otherwise it would wait forever.
+            if *issued_bytes + record.length > due_bytes + max_ahead_bytes
+                && *issued_bytes > due_bytes
+            {
+                let needed = (*issued_bytes + record.length).saturating_sub(max_ahead_bytes);
+                let due_at = self.time_when_due(needed.min(*issued_bytes));
+                wait = wait.max(due_at.saturating_sub(elapsed).max(Duration::from_millis(1)));
+            }
+        }
+
+        if wait.is_zero() {
+            *issued_bytes += record.length;
+        }
+        wait
+    }
+
+    /// Blocks until `record` can be replayed without getting too far ahead of the recorded
+    /// timeline. Returns false, without waiting any further, if `cancel` gets cancelled.
+    pub fn wait(&self, record: &Record, cancel: &CancelToken) -> bool {
+        loop {
+            if cancel.is_cancelled() {
+                return false;
+            }
+            let wait = self.try_issue(record);
+            if wait.is_zero() {
+                return true;
+            }
+            // Wake up in time to notice cancellation.
+            if cancel.is_enabled() {
+                self.clock.sleep(wait.min(CANCEL_POLL_INTERVAL));
+            } else {
+                self.clock.sleep(wait);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::format::FileId;
+    use crate::replay::cancel::CancelConditions;
+
+    // Clock that only moves forward when someone sleeps on it.
+    #[derive(Default)]
+    pub(crate) struct FakeClock {
+        now: Mutex<Duration>,
+    }
+
+    impl Clock for FakeClock {
+        fn elapsed(&self) -> Duration {
+            *self.now.lock().unwrap()
+        }
+
+        fn sleep(&self, duration: Duration) {
+            *self.now.lock().unwrap() += duration;
+        }
+    }
+
+    const MS: u64 = 1_000_000;
+    const MIB: u64 = 1024 * 1024;
+
+    // Builds a records file with one record per `(timestamp in ms, length)` pair. Timestamps
+    // start at an arbitrary boot time offset.
+    fn synthetic_records_file(records: &[(u64, u64)]) -> RecordsFile {
+        let mut rf = RecordsFile::default();
+        for (i, (timestamp, length)) in records.iter().enumerate() {
+            rf.insert_record(Record {
+                file_id: FileId(i as u64),
+                offset: 0,
+                length: *length,
+                timestamp: 5_000 * MS + timestamp * MS,
+            });
+        }
+        rf
+    }
+
+    // Replays all the records with the pacer and returns the time at which each record was
+    // released.
+    fn replay_times(rf: &RecordsFile, limits: PaceLimits) -> Vec<Duration> {
+        let clock = Arc::new(FakeClock::default());
+        let pacer = Pacer::new(rf, limits, clock.clone());
+        let cancel = CancelToken::new(CancelConditions::default(), clock.clone());
+        rf.inner
+            .records
+            .iter()
+            .map(|record| {
+                assert!(pacer.wait(record, &cancel));
+                clock.elapsed()
+            })
+            .collect()
+    }
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn test_pace_by_time() {
+        let rf = synthetic_records_file(&[(0, MIB), (10, MIB), (100, MIB), (150, MIB)]);
+        let limits = PaceLimits { max_ahead_time: Some(ms(20)), max_ahead_bytes: None };
+        assert_eq!(replay_times(&rf, limits), vec![ms(0), ms(0), ms(80), ms(130)]);
+    }
+
+    #[test]
+    fn test_pace_by_time_out_of_order_records() {
+        // Records grouped by file are not in timestamp order.
+        let rf = synthetic_records_file(&[(100, MIB), (0, MIB), (50, MIB)]);
+        let limits = PaceLimits { max_ahead_time: Some(ms(10)), max_ahead_bytes: None };
+        assert_eq!(replay_times(&rf, limits), vec![ms(90), ms(90), ms(90)]);
+    }
+
+    #[test]
+    fn test_pace_by_bytes() {
+        let rf = synthetic_records_file(&[(0, MIB), (10, MIB), (20, MIB), (30, MIB), (40, MIB)]);
+        let limits = PaceLimits { max_ahead_time: None, max_ahead_bytes: Some(2 * MIB) };
+        // At time 0, 1MiB is due and 3MiB can be replayed. Every further MiB needs to wait
+        // until another MiB is due.
+        assert_eq!(replay_times(&rf, limits), vec![ms(0), ms(0), ms(0), ms(10), ms(20)]);
+    }
+
+    #[test]
+    fn test_pace_by_bytes_large_record() {
+        let rf = synthetic_records_file(&[(0, MIB), (10, 8 * MIB), (20, MIB)]);
+        let limits = PaceLimits { max_ahead_time: None, max_ahead_bytes: Some(MIB) };
+        // The 8MiB record is larger than the limit and is let through once replay catches up
+        // with the timeline.
+        assert_eq!(replay_times(&rf, limits), vec![ms(0), ms(0), ms(10)]);
+    }
+
+    #[test]
+    fn test_pace_by_time_and_bytes() {
+        let rf = synthetic_records_file(&[(0, MIB), (10, MIB), (20, MIB), (200, MIB)]);
+        let limits = PaceLimits { max_ahead_time: Some(ms(100)), max_ahead_bytes: Some(MIB) };
+        assert_eq!(replay_times(&rf, limits), vec![ms(0), ms(0), ms(10), ms(100)]);
+    }
+
+    #[test]
+    fn test_pace_disabled() {
+        let rf = synthetic_records_file(&[(0, MIB), (1000, MIB)]);
+        assert_eq!(replay_times(&rf, PaceLimits::default()), vec![ms(0), ms(0)]);
+    }
+
+    #[test]
+    fn test_wait_is_cancelled() {
+        let rf = synthetic_records_file(&[(0, MIB), (1000, MIB)]);
+        let limits = PaceLimits { max_ahead_time: Some(ms(0)), max_ahead_bytes: None };
+        let clock = Arc::new(FakeClock::default());
+        let pacer = Pacer::new(&rf, limits, clock.clone());
+        let conditions = CancelConditions { deadline: Some(ms(250)), ..Default::default() };
+        let cancel = CancelToken::new(conditions, clock.clone());
+
+        assert!(pacer.wait(&rf.inner.records[0], &cancel));
+        assert!(!pacer.wait(&rf.inner.records[1], &cancel));
+        // The wait was cut short shortly after the deadline.
+        assert_eq!(clock.elapsed(), ms(300));
+    }
+}
@@ -0,0 +1,48 @@
+// Copyright (C) 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Callbacks through which embedders follow a replay as it goes.
+
+use std::fmt;
+
+use crate::format::{FileId, Record};
+use crate::Error;
+
+/// Receives replay progress.
+///
+/// Callbacks are invoked from the replay threads, possibly concurrently, and should return
+/// quickly as they hold up replay.
+pub trait ReplayProgress: Send + Sync {
+    /// Called each time replay opens the file of `file_id`, or fails to. A file may be opened
+    /// more than once when it gets evicted from the fd cache. Files skipped because they match
+    /// the exclude list fail with `Error::SkipPrefetch`. Files that changed since they were
+    /// recorded fail once with `Error::StaleFile` and are not opened again.
+    fn file_opened(&self, _file_id: &FileId, _result: Result<(), &Error>) {}
+
+    /// Called once `record` is done with the number of bytes read for it. Fewer bytes than the
+    /// record length are read when parts of the record are already in the page cache. Records
+    /// not reached before replay stops are not reported.
+    fn record_replayed(&self, _record: &Record, _result: Result<u64, &Error>) {}
+}
+
+impl fmt::Debug for dyn ReplayProgress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ReplayProgress")
+    }
+}
+
+/// Progress receiver that ignores everything.
+pub(crate) struct NoProgress;
+
+impl ReplayProgress for NoProgress {}
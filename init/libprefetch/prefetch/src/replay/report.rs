@@ -0,0 +1,253 @@
+// Copyright (C) 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Statistics collected during replay and the report built from them.
+
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::format::{FileId, RecordsFile};
+use crate::replay::cancel::CancelReason;
+use crate::replay::metadata::MetadataReport;
+use crate::Error;
+
+/// Errors seen while replaying the records of one file.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct FileErrors {
+    /// Id of the file in the records file.
+    pub file_id: FileId,
+
+    /// First known path of the file.
+    pub path: Option<String>,
+
+    /// Number of failed attempts to open the file.
+    pub open_errors: u64,
+
+    /// Number of failed reads of the file.
+    pub read_errors: u64,
+
+    /// The first error seen on the file.
+    pub first_error: String,
+}
+
+/// A file skipped because it changed since it was recorded.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct StaleFile {
+    /// Id of the file in the records file.
+    pub file_id: FileId,
+
+    /// First known path of the file.
+    pub path: String,
+
+    /// What changed.
+    pub reason: String,
+}
+
+/// Work done by one replay worker.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct WorkerReport {
+    /// Worker id.
+    pub id: usize,
+
+    /// Number of records replayed by the worker without error.
+    pub records_replayed: u64,
+
+    /// Time spent by the worker in milliseconds.
+    pub wall_time_ms: u64,
+}
+
+/// Page cache residency of the records, in bytes.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ResidencyReport {
+    /// Total bytes covered by the records.
+    pub record_bytes: u64,
+
+    /// Bytes of the records that were in the page cache before replay started.
+    pub resident_bytes_before: u64,
+
+    /// Bytes of the records that were in the page cache right after replay finished.
+    ///
+    /// Reads issued by asynchronous strategies, like readahead and fadvise, may still be in
+    /// progress at this point.
+    pub resident_bytes_after: u64,
+}
+
+/// Summary of a replay.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ReplayReport {
+    /// Number of records replayed without error.
+    pub records_replayed: u64,
+
+    /// Bytes of the records replayed without error.
+    pub bytes_read: u64,
+
+    /// Number of times a file was opened. A file may be opened more than once when it gets
+    /// evicted from the fd cache.
+    pub files_opened: u64,
+
+    /// Number of files skipped because they matched the exclude list.
+    pub files_skipped: u64,
+
+    /// Bytes of the records that were not read because they were already in the page cache.
+    pub bytes_skipped_resident: u64,
+
+    /// Open and read errors, ordered by file id.
+    pub file_errors: Vec<FileErrors>,
+
+    /// Files skipped because they changed since they were recorded, ordered by file id.
+    pub stale_files: Vec<StaleFile>,
+
+    /// Per worker statistics, ordered by worker id.
+    pub workers: Vec<WorkerReport>,
+
+    /// Time taken by the replay in milliseconds.
+    pub wall_time_ms: u64,
+
+    /// Page cache residency of the replayed ranges.
+    pub residency: ResidencyReport,
+
+    /// Why replay stopped before replaying all the records, if it did.
+    pub cancelled: Option<CancelReason>,
+
+    /// Outcome of the metadata warming phase, if it ran.
+    pub metadata: Option<MetadataReport>,
+}
+
+/// Statistics updated by replay workers as they go.
+#[derive(Debug, Default)]
+pub(crate) struct ReplayStats {
+    records_replayed: u64,
+    bytes_read: u64,
+    files_opened: u64,
+    bytes_skipped_resident: u64,
+    skipped_files: HashSet<FileId>,
+    file_errors: HashMap<FileId, FileErrors>,
+    stale_files: HashMap<FileId, StaleFile>,
+    workers: Vec<WorkerReport>,
+    cancelled: Option<CancelReason>,
+    metadata: Option<MetadataReport>,
+}
+
+impl ReplayStats {
+    pub fn add_replayed(&mut self, records: u64, bytes: u64) {
+        self.records_replayed += records;
+        self.bytes_read += bytes;
+    }
+
+    pub fn add_opened(&mut self) {
+        self.files_opened += 1;
+    }
+
+    pub fn add_skipped_resident(&mut self, bytes: u64) {
+        self.bytes_skipped_resident += bytes;
+    }
+
+    fn file_errors(&mut self, file_id: &FileId, error: &Error) -> &mut FileErrors {
+        self.file_errors.entry(file_id.clone()).or_insert_with(|| FileErrors {
+            file_id: file_id.clone(),
+            first_error: error.to_string(),
+            ..Default::default()
+        })
+    }
+
+    /// Accounts for failure to open the file of `file_id`. Skipped and stale files are not
+    /// errors.
+    pub fn add_open_error(&mut self, file_id: &FileId, error: &Error) {
+        match error {
+            Error::SkipPrefetch { .. } => {
+                self.skipped_files.insert(file_id.clone());
+            }
+            Error::StaleFile { path, reason } => {
+                self.stale_files.insert(
+                    file_id.clone(),
+                    StaleFile {
+                        file_id: file_id.clone(),
+                        path: path.clone(),
+                        reason: reason.clone(),
+                    },
+                );
+            }
+            _ => self.file_errors(file_id, error).open_errors += 1,
+        }
+    }
+
+    /// Returns the error the file of `file_id` was found stale with, if it was.
+    pub fn stale_error(&self, file_id: &FileId) -> Option<Error> {
+        self.stale_files.get(file_id).map(|stale| Error::StaleFile {
+            path: stale.path.clone(),
+            reason: stale.reason.clone(),
+        })
+    }
+
+    pub fn add_read_error(&mut self, file_id: &FileId, error: &Error) {
+        self.file_errors(file_id, error).read_errors += 1;
+    }
+
+    pub fn add_worker(&mut self, worker: WorkerReport) {
+        self.workers.push(worker);
+    }
+
+    pub fn set_cancelled(&mut self, reason: CancelReason) {
+        self.cancelled = Some(reason);
+    }
+
+    pub fn set_metadata(&mut self, report: MetadataReport) {
+        self.metadata = Some(report);
+    }
+
+    /// Builds the report. Paths of the files with errors are looked up in `rf`.
+    pub fn report(
+        &self,
+        rf: &RecordsFile,
+        wall_time: Duration,
+        residency: ResidencyReport,
+    ) -> ReplayReport {
+        let mut file_errors: Vec<FileErrors> = self
+            .file_errors
+            .values()
+            .map(|errors| FileErrors {
+                path: rf
+                    .inner
+                    .inode_map
+                    .get(&errors.file_id)
+                    .and_then(|info| info.paths.first().cloned()),
+                ..errors.clone()
+            })
+            .collect();
+        file_errors.sort_by(|a, b| a.file_id.cmp(&b.file_id));
+
+        let mut stale_files: Vec<StaleFile> = self.stale_files.values().cloned().collect();
+        stale_files.sort_by(|a, b| a.file_id.cmp(&b.file_id));
+
+        let mut workers = self.workers.clone();
+        workers.sort_by_key(|worker| worker.id);
+
+        ReplayReport {
+            records_replayed: self.records_replayed,
+            bytes_read: self.bytes_read,
+            files_opened: self.files_opened,
+            files_skipped: self.skipped_files.len() as u64,
+            bytes_skipped_resident: self.bytes_skipped_resident,
+            file_errors,
+            stale_files,
+            workers,
+            wall_time_ms: wall_time.as_millis() as u64,
+            residency,
+            cancelled: self.cancelled,
+            metadata: self.metadata.clone(),
+        }
+    }
+}
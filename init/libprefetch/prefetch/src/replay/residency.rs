@@ -0,0 +1,214 @@
+// Copyright (C) 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Helpers to query and drop the page cache state of the files in a records file.
+
+use std::cmp::min;
+use std::collections::HashMap;
+use std::fs::File;
+use std::num::NonZeroUsize;
+use std::ops::Range;
+use std::os::fd::AsFd;
+use std::os::unix::io::AsRawFd;
+use std::ptr::NonNull;
+
+use log::{debug, error, warn};
+use nix::errno::Errno;
+use nix::fcntl::{posix_fadvise, PosixFadviseAdvice};
+use nix::sys::mman::{mmap, munmap, MapFlags, ProtFlags};
+use regex::Regex;
+
+use crate::format::{FileId, Record, RecordsFile};
+use crate::tracer::page_size;
+use crate::Error;
+
+/// Page cache residency of a range of a file.
+pub(crate) struct Residency {
+    /// Size of a page in bytes.
+    pub page_size: u64,
+
+    /// The queried range, clipped at the end of the file.
+    pub range: Range<u64>,
+
+    /// Residency of each page that overlaps the queried range.
+    pub pages: Vec<bool>,
+}
+
+impl Residency {
+    /// Returns the number of bytes of the page aligned range that are in the page cache.
+    pub fn resident_bytes(&self) -> u64 {
+        self.pages.iter().filter(|resident| **resident).count() as u64 * self.page_size
+    }
+
+    /// Returns the parts of the queried range that are not in the page cache. Adjacent pages
+    /// are merged into one range.
+    pub fn non_resident_ranges(&self) -> Vec<Range<u64>> {
+        let start = self.range.start - (self.range.start % self.page_size);
+        let mut ranges: Vec<Range<u64>> = vec![];
+        for (i, resident) in self.pages.iter().enumerate() {
+            if *resident {
+                continue;
+            }
+            let page_start = start + i as u64 * self.page_size;
+            let page =
+                page_start.max(self.range.start)..(page_start + self.page_size).min(self.range.end);
+            match ranges.last_mut() {
+                Some(last) if last.end == page.start => last.end = page.end,
+                _ => ranges.push(page),
+            }
+        }
+        ranges
+    }
+}
+
+/// Returns page cache residency of the pages overlapping `length` bytes of `file` starting at
+/// `offset`. The range is clipped at the end of the file.
+///
+/// The file is mapped, but not accessed, to query residency with mincore(2).
+pub(crate) fn page_residency(file: &File, offset: u64, length: u64) -> Result<Residency, Error> {
+    let page_size = page_size()? as u64;
+    let start = offset - (offset % page_size);
+    let file_size = file
+        .metadata()
+        .map_err(|e| Error::Custom { error: format!("failed to stat file for residency: {e}") })?
+        .len();
+    let end = min(offset.saturating_add(length), file_size);
+    if end <= start {
+        return Ok(Residency { page_size, range: offset..offset, pages: vec![] });
+    }
+
+    let map_length = (end - start) as usize;
+    // SAFETY: This is safe because
+    // - the length is checked for zero
+    // - the offset is page aligned
+    // - the mapping is never accessed, it is only passed to mincore
+    let map_addr = unsafe {
+        mmap(
+            None,
+            NonZeroUsize::new(map_length).unwrap(),
+            ProtFlags::PROT_READ,
+            MapFlags::MAP_SHARED,
+            file.as_fd(),
+            start as libc::off_t,
+        )
+        .map_err(|e| Error::Mmap { error: e.to_string(), path: "residency".to_owned() })?
+    };
+
+    let page_count = (map_length as u64).div_ceil(page_size) as usize;
+    let mut buf: Vec<u8> = vec![0_u8; page_count];
+    // SAFETY: This is safe because
+    // - the range is mapped
+    // - buf is large enough to hold one byte per page of the mapping
+    let ret = unsafe { libc::mincore(map_addr.as_ptr(), map_length, buf.as_mut_ptr()) };
+    let mincore_error = Errno::last_raw();
+
+    // SAFETY: This is safe because the address was returned by mmap above with the same length.
+    if let Err(e) = unsafe { munmap(NonNull::new(map_addr.as_ptr()).unwrap(), map_length) } {
+        error!("failed to munmap {:p} {} with {}", map_addr.as_ptr(), map_length, e);
+    }
+
+    if ret < 0 {
+        return Err(Error::Custom {
+            error: format!("failed to query resident pages: {mincore_error}"),
+        });
+    }
+
+    Ok(Residency {
+        page_size,
+        range: offset..end,
+        pages: buf.iter().map(|page| page & 1 != 0).collect(),
+    })
+}
+
+// Returns the records of `rf` grouped by file, files in the order they are first read.
+fn records_by_file(rf: &RecordsFile) -> Vec<(&FileId, Vec<&Record>)> {
+    let mut files: Vec<(&FileId, Vec<&Record>)> = vec![];
+    let mut indexes: HashMap<&FileId, usize> = HashMap::new();
+    for record in &rf.inner.records {
+        let index = *indexes.entry(&record.file_id).or_insert_with(|| {
+            files.push((&record.file_id, vec![]));
+            files.len() - 1
+        });
+        files[index].1.push(record);
+    }
+    files
+}
+
+/// Returns the number of bytes of the records in `rf` that are in the page cache.
+///
+/// Files are opened one at a time. Files that are excluded or that cannot be opened are
+/// ignored.
+pub(crate) fn records_resident_bytes(rf: &RecordsFile, exclude_files_regex: &[Regex]) -> u64 {
+    let mut resident = 0;
+    for (id, records) in records_by_file(rf) {
+        let file = match rf.open_file(id.clone(), exclude_files_regex) {
+            Ok(file) => file,
+            Err(_) => continue,
+        };
+        for record in records {
+            match page_residency(&file, record.offset, record.length) {
+                Ok(residency) => resident += residency.resident_bytes(),
+                Err(e) => debug!("failed to get residency of {record:?}: {e}"),
+            }
+        }
+    }
+    resident
+}
+
+/// Drops clean pages of all the files in `rf` from the page cache.
+pub(crate) fn drop_cached_pages(rf: &RecordsFile, exclude_files_regex: &[Regex]) {
+    for id in rf.inner.inode_map.keys() {
+        if let Ok(file) = rf.open_file(id.clone(), exclude_files_regex) {
+            if let Err(e) =
+                posix_fadvise(file.as_raw_fd(), 0, 0, PosixFadviseAdvice::POSIX_FADV_DONTNEED)
+            {
+                warn!("failed to drop cached pages of file id: {id} with {e}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_non_resident_ranges() {
+        let residency = Residency {
+            page_size: 4096,
+            range: 1000..20000,
+            pages: vec![false, true, false, false, true],
+        };
+        assert_eq!(residency.non_resident_ranges(), vec![1000..4096, 8192..16384]);
+
+        let residency = Residency { page_size: 4096, range: 4096..10000, pages: vec![true, false] };
+        assert_eq!(residency.non_resident_ranges(), vec![8192..10000]);
+
+        let residency = Residency { page_size: 4096, range: 0..8192, pages: vec![true, true] };
+        assert!(residency.non_resident_ranges().is_empty());
+    }
+
+    #[test]
+    fn test_records_by_file() {
+        let mut rf = RecordsFile::default();
+        for (id, offset) in [(1, 0), (0, 0), (1, 4096), (0, 4096), (2, 0)] {
+            rf.insert_record(Record { file_id: FileId(id), offset, length: 4096, timestamp: 0 });
+        }
+        let files: Vec<(u64, Vec<u64>)> = records_by_file(&rf)
+            .into_iter()
+            .map(|(id, records)| (id.0, records.iter().map(|r| r.offset).collect()))
+            .collect();
+        assert_eq!(files, vec![(1, vec![0, 4096]), (0, vec![0, 4096]), (2, vec![0])]);
+    }
+}
@@ -0,0 +1,366 @@
+// Copyright (C) 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Scheduling settings of the replay threads.
+//!
+//! Replay runs early during boot along with init and zygote. These settings let a device
+//! trade replay speed for less contention.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use log::warn;
+use serde::Deserialize;
+
+use crate::args::IoPriorityClass;
+use crate::Error;
+
+// See include/uapi/linux/ioprio.h
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+
+// Android task profiles and the cgroup controllers they refer to.
+const TASK_PROFILES_PATH: &str = "/etc/task_profiles.json";
+const CGROUPS_PATH: &str = "/etc/cgroups.json";
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct CgroupController {
+    controller: String,
+    path: PathBuf,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Cgroups2 {
+    path: PathBuf,
+    #[serde(default)]
+    controllers: Vec<CgroupController>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct CgroupsDescription {
+    #[serde(default)]
+    cgroups: Vec<CgroupController>,
+    cgroups2: Option<Cgroups2>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ProfileAction {
+    name: String,
+    #[serde(default)]
+    params: HashMap<String, String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct TaskProfile {
+    name: String,
+    #[serde(default)]
+    actions: Vec<ProfileAction>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct AggregateProfile {
+    name: String,
+    profiles: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct TaskProfiles {
+    #[serde(default)]
+    profiles: Vec<TaskProfile>,
+    #[serde(default)]
+    aggregate_profiles: Vec<AggregateProfile>,
+}
+
+fn read_json<T: for<'de> Deserialize<'de>>(path: &Path) -> Result<T, Error> {
+    let file = File::open(path)
+        .map_err(|source| Error::Open { source, path: path.display().to_string() })?;
+    serde_json::from_reader(file).map_err(|e| Error::Deserialize { error: e.to_string() })
+}
+
+// Returns the cgroup directories the task profile `name` moves threads to. Only the JoinCgroup
+// action of task profiles is supported; profiles with other actions are refused.
+fn task_profile_cgroups(
+    name: &str,
+    profiles: &TaskProfiles,
+    cgroups: &CgroupsDescription,
+) -> Result<Vec<PathBuf>, Error> {
+    let invalid = |error: String| Error::InvalidArgs {
+        arg_name: "task_profile".to_owned(),
+        arg_value: name.to_owned(),
+        error,
+    };
+    if let Some(aggregate) = profiles.aggregate_profiles.iter().find(|p| p.name == name) {
+        let mut paths = vec![];
+        for profile in &aggregate.profiles {
+            paths.extend(task_profile_cgroups(profile, profiles, cgroups)?);
+        }
+        return Ok(paths);
+    }
+    let profile = profiles
+        .profiles
+        .iter()
+        .find(|p| p.name == name)
+        .ok_or_else(|| invalid("unknown task profile".to_owned()))?;
+
+    let mut controllers: HashMap<&str, PathBuf> =
+        cgroups.cgroups.iter().map(|c| (c.controller.as_str(), c.path.clone())).collect();
+    if let Some(cgroups2) = &cgroups.cgroups2 {
+        for controller in &cgroups2.controllers {
+            controllers.insert(&controller.controller, cgroups2.path.join(&controller.path));
+        }
+    }
+    let mut paths = vec![];
+    for action in &profile.actions {
+        if action.name != "JoinCgroup" {
+            return Err(invalid(format!("unsupported action {}", action.name)));
+        }
+        let param = |key: &str| {
+            action.params.get(key).ok_or_else(|| invalid(format!("JoinCgroup without {key}")))
+        };
+        let controller = param("Controller")?;
+        let root = controllers
+            .get(controller.as_str())
+            .ok_or_else(|| invalid(format!("unknown cgroup controller {controller}")))?;
+        paths.push(root.join(param("Path")?));
+    }
+    Ok(paths)
+}
+
+/// Returns the cgroup directories the Android task profile `name` moves threads to.
+pub(crate) fn resolve_task_profile(name: &str) -> Result<Vec<PathBuf>, Error> {
+    let profiles: TaskProfiles = read_json(Path::new(TASK_PROFILES_PATH))?;
+    let cgroups: CgroupsDescription = read_json(Path::new(CGROUPS_PATH))?;
+    task_profile_cgroups(name, &profiles, &cgroups)
+}
+
+fn ioprio_value(class: IoPriorityClass, level: u8) -> libc::c_int {
+    let class = match class {
+        IoPriorityClass::Realtime => 1,
+        IoPriorityClass::BestEffort => 2,
+        IoPriorityClass::Idle => 3,
+    };
+    (class << IOPRIO_CLASS_SHIFT) | level as libc::c_int
+}
+
+/// Scheduling settings applied to each replay thread.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub(crate) struct WorkerScheduling {
+    pub ioprio: Option<(IoPriorityClass, u8)>,
+    pub nice: Option<i32>,
+    pub cpu_affinity: Option<u64>,
+    pub cgroup_path: Option<PathBuf>,
+    // Cgroup directories of the task profile, resolved when the replay is built.
+    pub task_profile_cgroups: Vec<PathBuf>,
+}
+
+impl WorkerScheduling {
+    fn set_ioprio(class: IoPriorityClass, level: u8) -> Result<(), Error> {
+        // SAFETY: This is safe because ioprio_set only takes integer arguments. A `who` of 0
+        // refers to the calling thread.
+        let ret = unsafe {
+            libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ioprio_value(class, level))
+        };
+        if ret < 0 {
+            return Err(Error::Custom {
+                error: format!("failed to set io priority: {}", io::Error::last_os_error()),
+            });
+        }
+        Ok(())
+    }
+
+    fn set_nice(nice: i32) -> Result<(), Error> {
+        // SAFETY: This is safe because gettid has no arguments and setpriority only takes
+        // integer arguments. On Linux, PRIO_PROCESS with a thread id applies to that thread only.
+        let ret =
+            unsafe { libc::setpriority(libc::PRIO_PROCESS, libc::gettid() as libc::id_t, nice) };
+        if ret < 0 {
+            return Err(Error::Custom {
+                error: format!("failed to set nice value: {}", io::Error::last_os_error()),
+            });
+        }
+        Ok(())
+    }
+
+    fn set_cpu_affinity(mask: u64) -> Result<(), Error> {
+        // SAFETY: This is safe because cpu_set_t is plain data for which all zeroes is valid,
+        // and CPU_SET only touches cpus below 64 which fit in the set.
+        let ret = unsafe {
+            let mut set: libc::cpu_set_t = std::mem::zeroed();
+            for cpu in (0..64).filter(|cpu| mask & (1 << cpu) != 0) {
+                libc::CPU_SET(cpu, &mut set);
+            }
+            libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set)
+        };
+        if ret < 0 {
+            return Err(Error::Custom {
+                error: format!("failed to set cpu affinity: {}", io::Error::last_os_error()),
+            });
+        }
+        Ok(())
+    }
+
+    fn join_cgroup(path: &Path) -> Result<(), Error> {
+        let threads = path.join("cgroup.threads");
+        let file = if threads.exists() { threads } else { path.join("tasks") };
+        // SAFETY: This is safe because gettid has no arguments.
+        let tid = unsafe { libc::gettid() };
+        OpenOptions::new()
+            .write(true)
+            .open(&file)
+            .and_then(|mut f| f.write_all(tid.to_string().as_bytes()))
+            .map_err(|e| Error::Custom {
+                error: format!("failed to move thread to cgroup {}: {e}", file.display()),
+            })
+    }
+
+    /// Applies the settings to the calling thread.
+    ///
+    /// Settings are best effort. Failures are logged and do not stop replay.
+    pub fn apply(&self) {
+        let mut results = vec![];
+        if let Some((class, level)) = self.ioprio {
+            results.push(Self::set_ioprio(class, level));
+        }
+        if let Some(nice) = self.nice {
+            results.push(Self::set_nice(nice));
+        }
+        if let Some(mask) = self.cpu_affinity {
+            results.push(Self::set_cpu_affinity(mask));
+        }
+        if let Some(path) = &self.cgroup_path {
+            results.push(Self::join_cgroup(path));
+        }
+        for path in &self.task_profile_cgroups {
+            results.push(Self::join_cgroup(path));
+        }
+        for result in results {
+            if let Err(e) = result {
+                warn!("{e}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tracer::tests::setup_test_dir;
+    use std::fs;
+    use std::thread;
+
+    #[test]
+    fn test_ioprio_value() {
+        assert_eq!(ioprio_value(IoPriorityClass::Realtime, 0), 1 << 13);
+        assert_eq!(ioprio_value(IoPriorityClass::BestEffort, 4), (2 << 13) | 4);
+        assert_eq!(ioprio_value(IoPriorityClass::Idle, 7), (3 << 13) | 7);
+    }
+
+    // Returns a mask of the first cpu the calling thread may run on.
+    fn first_allowed_cpu() -> u64 {
+        // SAFETY: This is safe because sched_getaffinity writes to a set of the size passed to
+        // it, and CPU_ISSET only reads cpus below 64 which fit in the set.
+        unsafe {
+            let mut set: libc::cpu_set_t = std::mem::zeroed();
+            libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set);
+            let cpu = (0..64).find(|cpu| libc::CPU_ISSET(*cpu, &set)).unwrap();
+            1 << cpu
+        }
+    }
+
+    #[test]
+    fn test_apply_to_thread() {
+        let cgroup = setup_test_dir();
+        fs::write(cgroup.join("tasks"), "").unwrap();
+        let scheduling = WorkerScheduling {
+            ioprio: Some((IoPriorityClass::BestEffort, 7)),
+            nice: Some(10),
+            cpu_affinity: Some(first_allowed_cpu()),
+            cgroup_path: Some(cgroup.clone()),
+            task_profile_cgroups: vec![],
+        };
+
+        // Settings are applied on a separate thread to leave the test thread untouched.
+        let (tid, ioprio, nice, cpu_count) = thread::spawn(move || {
+            scheduling.apply();
+            // SAFETY: This is safe because the calls only take integer arguments and
+            // sched_getaffinity writes to a set of the size passed to it.
+            unsafe {
+                let tid = libc::gettid();
+                let ioprio = libc::syscall(libc::SYS_ioprio_get, IOPRIO_WHO_PROCESS, 0);
+                let nice = libc::getpriority(libc::PRIO_PROCESS, tid as libc::id_t);
+                let mut set: libc::cpu_set_t = std::mem::zeroed();
+                libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set);
+                (tid, ioprio, nice, libc::CPU_COUNT(&set))
+            }
+        })
+        .join()
+        .unwrap();
+
+        assert_eq!(ioprio, ioprio_value(IoPriorityClass::BestEffort, 7) as libc::c_long);
+        assert_eq!(nice, 10);
+        assert_eq!(cpu_count, 1);
+        assert_eq!(fs::read_to_string(cgroup.join("tasks")).unwrap(), tid.to_string());
+    }
+
+    #[test]
+    fn test_task_profile_cgroups() {
+        let profiles: TaskProfiles = serde_json::from_str(
+            r#"{
+                "Profiles": [
+                    {"Name": "ProcessCapacityLow", "Actions": [
+                        {"Name": "JoinCgroup", "Params": {"Controller": "cpuset", "Path": "background"}}
+                    ]},
+                    {"Name": "LowIoPriority", "Actions": [
+                        {"Name": "JoinCgroup", "Params": {"Controller": "blkio", "Path": "background"}}
+                    ]},
+                    {"Name": "TimerSlackHigh", "Actions": [
+                        {"Name": "SetTimerSlack", "Params": {"Slack": "40000000"}}
+                    ]}
+                ],
+                "AggregateProfiles": [
+                    {"Name": "SCHED_SP_BACKGROUND", "Profiles": ["ProcessCapacityLow", "LowIoPriority"]}
+                ]
+            }"#,
+        )
+        .unwrap();
+        let cgroups: CgroupsDescription = serde_json::from_str(
+            r#"{
+                "Cgroups": [{"Controller": "cpuset", "Path": "/dev/cpuset"}],
+                "Cgroups2": {"Path": "/sys/fs/cgroup", "Controllers": [
+                    {"Controller": "blkio", "Path": "."}
+                ]}
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            task_profile_cgroups("SCHED_SP_BACKGROUND", &profiles, &cgroups).unwrap(),
+            vec![
+                PathBuf::from("/dev/cpuset/background"),
+                PathBuf::from("/sys/fs/cgroup/background")
+            ]
+        );
+        assert!(task_profile_cgroups("TimerSlackHigh", &profiles, &cgroups).is_err());
+        assert!(task_profile_cgroups("Unknown", &profiles, &cgroups).is_err());
+    }
+}
@@ -0,0 +1,342 @@
+// Copyright (C) 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! io_uring based replay engine.
+//!
+//! A single thread walks the records, opens files through the shared fd cache and keeps up to
+//! `queue_depth` reads in flight. This lets the kernel keep the storage queue full without
+//! spawning one thread per outstanding IO.
+
+use std::cmp::min;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
+use std::time::Instant;
+
+use io_uring::{opcode, types, IoUring};
+use log::error;
+
+use crate::format::{FileId, Record};
+use crate::replay::cancel::CancelToken;
+use crate::replay::pace::Pacer;
+use crate::replay::report::WorkerReport;
+use crate::replay::{
+    get_or_open_file, log_open_error, non_resident_parts, scoped_log, Replay, READ_SZ,
+};
+use crate::Error;
+
+// Keeps the file open while a read on it is in flight. The fd cache may evict the file
+// before the read completes.
+struct InFlight {
+    _file: Arc<File>,
+    file_id: FileId,
+    // Index of the record the read is part of.
+    record_index: usize,
+}
+
+// A record with reads in flight. It counts as replayed once all of its reads complete without
+// error.
+struct PendingRecord {
+    record: Record,
+    // Bytes read so far for the record. Reads past the end of the file come up short.
+    bytes: u64,
+    reads_left: u64,
+    // Message of the first failed read of the record.
+    error: Option<String>,
+}
+
+// Calls `submit` until it is not interrupted by a signal. An interrupted submit must not skip
+// the record being paced.
+fn submit_retrying(mut submit: impl FnMut() -> io::Result<usize>) -> Result<(), Error> {
+    loop {
+        match submit() {
+            Ok(_) => return Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(Error::Custom { error: format!("io_uring submit failed: {e}") }),
+        }
+    }
+}
+
+pub(super) struct UringReplay {
+    ring: IoUring,
+    in_flight: Vec<Option<InFlight>>,
+    free_slots: Vec<usize>,
+    pending: HashMap<usize, PendingRecord>,
+    records_replayed: u64,
+}
+
+impl UringReplay {
+    /// Sets up an io_uring instance with `queue_depth` entries.
+    ///
+    /// This fails on kernels without io_uring support or when the process is not allowed
+    /// to use io_uring (for example, due to seccomp or selinux policy).
+    pub(super) fn new(queue_depth: u16) -> Result<Self, Error> {
+        let ring = IoUring::new(queue_depth.into())
+            .map_err(|e| Error::Custom { error: format!("io_uring setup failed: {e}") })?;
+        let queue_depth = queue_depth as usize;
+        Ok(Self {
+            ring,
+            in_flight: (0..queue_depth).map(|_| None).collect(),
+            free_slots: (0..queue_depth).rev().collect(),
+            pending: HashMap::new(),
+            records_replayed: 0,
+        })
+    }
+
+    /// Replays the records of `replay` until all are done or `cancel` gets cancelled. Records
+    /// are held back by `pacer`, if any.
+    ///
+    /// Replay runs on the calling thread, so worker scheduling settings are applied to it. The
+    /// caller runs it on a thread of its own.
+    pub(super) fn replay(
+        mut self,
+        replay: &Replay,
+        pacer: Option<&Pacer>,
+        cancel: &CancelToken,
+    ) -> Result<(), Error> {
+        let _dbg = scoped_log(0, "uring_replay");
+        replay.scheduling.apply();
+        let start = Instant::now();
+
+        // All reads land in the same scratch buffer. We never look at the data; the reads only
+        // exist to populate the page cache. So it does not matter that in flight reads
+        // overwrite each other.
+        let mut buffer = vec![0u8; READ_SZ];
+        let mut result = Ok(());
+
+        let mut index = 0;
+        'records: while result.is_ok() && !cancel.is_cancelled() {
+            let record = {
+                let rf = replay.records_file.read().unwrap();
+                match rf.inner.records.get(index) {
+                    Some(record) => record.clone(),
+                    None => break,
+                }
+            };
+            let record_index = index;
+            index += 1;
+
+            if let Some(pacer) = pacer {
+                // Reads queued so far should not wait along with this record.
+                if let Err(e) = submit_retrying(|| self.ring.submit()) {
+                    std::mem::forget(buffer);
+                    return Err(e);
+                }
+                if !pacer.wait(&record, cancel) {
+                    break;
+                }
+            }
+
+            let file = match get_or_open_file(
+                &replay.state,
+                &replay.records_file,
+                &record,
+                &replay.exclude_files_regex,
+                replay.progress.as_ref(),
+            ) {
+                Ok(file) => file,
+                Err(e) => {
+                    replay.progress.record_replayed(&record, Err(&e));
+                    replay.state.lock().unwrap().stats.add_open_error(&record.file_id, &e);
+                    // Files that changed since recording are expected after updates.
+                    if replay.exit_on_error && !matches!(e, Error::StaleFile { .. }) {
+                        result = Err(e);
+                        break;
+                    }
+                    log_open_error(&record.file_id, e);
+                    continue;
+                }
+            };
+
+            let mut parts = if replay.skip_resident {
+                let (parts, resident_bytes) = non_resident_parts(&file, &record);
+                replay.state.lock().unwrap().stats.add_skipped_resident(resident_bytes);
+                parts
+            } else {
+                vec![record.clone()]
+            };
+            parts.retain(|part| part.length > 0);
+
+            // Records with nothing to read complete right away, as no completion would finish
+            // them.
+            if parts.is_empty() {
+                replay.state.lock().unwrap().stats.add_replayed(1, 0);
+                self.records_replayed += 1;
+                replay.progress.record_replayed(&record, Ok(0));
+                continue;
+            }
+            self.pending.insert(
+                record_index,
+                PendingRecord {
+                    record: record.clone(),
+                    bytes: 0,
+                    reads_left: parts.iter().map(|part| part.length.div_ceil(READ_SZ as u64)).sum(),
+                    error: None,
+                },
+            );
+            for part in &parts {
+                let end = part.offset + part.length;
+                let mut offset = part.offset;
+                while offset < end {
+                    let slot = loop {
+                        if let Some(slot) = self.free_slots.pop() {
+                            break slot;
+                        }
+                        if let Err(e) = self.reap(1, replay, &mut result) {
+                            // Reads may still be in flight. Leak the buffer rather than let the
+                            // kernel write into freed memory.
+                            std::mem::forget(buffer);
+                            return Err(e);
+                        }
+                        if result.is_err() {
+                            break 'records;
+                        }
+                    };
+
+                    let len = min(READ_SZ as u64, end - offset) as u32;
+                    let entry =
+                        opcode::Read::new(types::Fd(file.as_raw_fd()), buffer.as_mut_ptr(), len)
+                            .offset(offset)
+                            .build()
+                            .user_data(slot as u64);
+                    self.in_flight[slot] = Some(InFlight {
+                        _file: file.clone(),
+                        file_id: record.file_id.clone(),
+                        record_index,
+                    });
+
+                    // SAFETY: This is safe because
+                    // - the fd stays open until the read completes as `in_flight` holds a
+                    //   reference to the file.
+                    // - the buffer outlives all the reads as we wait for all of them to
+                    //   complete before returning, and `len` never exceeds the length of the
+                    //   buffer.
+                    // - the submission queue never overflows as there are no more entries
+                    //   than slots.
+                    if let Err(e) = unsafe { self.ring.submission().push(&entry) } {
+                        std::mem::forget(buffer);
+                        return Err(Error::Custom {
+                            error: format!("failed to queue io_uring read: {e}"),
+                        });
+                    }
+                    offset += len as u64;
+                }
+            }
+        }
+
+        while self.free_slots.len() < self.in_flight.len() {
+            if let Err(e) = self.reap(1, replay, &mut result) {
+                std::mem::forget(buffer);
+                return Err(e);
+            }
+        }
+        replay.state.lock().unwrap().stats.add_worker(WorkerReport {
+            id: 0,
+            records_replayed: self.records_replayed,
+            wall_time_ms: start.elapsed().as_millis() as u64,
+        });
+        result
+    }
+
+    // Submits queued reads, waits for at least `want` completions and frees the slots of all
+    // completed reads.
+    //
+    // Read failures are stored in `result` when `exit_on_error` is set and logged otherwise.
+    // The returned error is reserved for failures of the ring itself. A record counts as
+    // replayed when all of its reads complete without error, and as failed with the first
+    // error otherwise.
+    fn reap(
+        &mut self,
+        want: usize,
+        replay: &Replay,
+        result: &mut Result<(), Error>,
+    ) -> Result<(), Error> {
+        loop {
+            match self.ring.submit_and_wait(want) {
+                Ok(_) => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    return Err(Error::Custom { error: format!("io_uring wait failed: {e}") })
+                }
+            }
+        }
+
+        for entry in self.ring.completion() {
+            let slot = entry.user_data() as usize;
+            let in_flight = self.in_flight[slot].take().unwrap();
+            self.free_slots.push(slot);
+
+            let pending = self.pending.get_mut(&in_flight.record_index).unwrap();
+            pending.reads_left -= 1;
+            if entry.result() < 0 {
+                let error =
+                    format!("readahead failed: {}", io::Error::from_raw_os_error(-entry.result()));
+                let e = Error::Read { error: error.clone() };
+                replay.state.lock().unwrap().stats.add_read_error(&in_flight.file_id, &e);
+                pending.error.get_or_insert(error);
+                if replay.exit_on_error {
+                    if result.is_ok() {
+                        *result = Err(e);
+                    }
+                } else {
+                    error!("readahead failed on file id: {} with: {}", in_flight.file_id, e);
+                }
+            } else {
+                let bytes = entry.result() as u64;
+                pending.bytes += bytes;
+                replay.state.lock().unwrap().stats.add_replayed(0, bytes);
+            }
+
+            if pending.reads_left == 0 {
+                let pending = self.pending.remove(&in_flight.record_index).unwrap();
+                match pending.error {
+                    Some(error) => replay
+                        .progress
+                        .record_replayed(&pending.record, Err(&Error::Read { error })),
+                    None => {
+                        self.records_replayed += 1;
+                        replay.state.lock().unwrap().stats.add_replayed(1, 0);
+                        replay.progress.record_replayed(&pending.record, Ok(pending.bytes));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_submit_retrying_interrupted() {
+        let mut calls = 0;
+        let result = submit_retrying(|| {
+            calls += 1;
+            if calls < 3 {
+                Err(io::Error::from_raw_os_error(libc::EINTR))
+            } else {
+                Ok(1)
+            }
+        });
+        assert!(result.is_ok());
+        assert_eq!(calls, 3);
+
+        let result = submit_retrying(|| Err(io::Error::from_raw_os_error(libc::EBADF)));
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,416 @@
+// Copyright (C) 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Writes records files as trace timelines that can be opened in Perfetto or chrome://tracing
+//! and overlaid on boot traces.
+//!
+//! Each mount point gets a track, a process in Chrome traces, with a track per file under it.
+//! Each record is a slice starting at the time it was read, with its offset and length as
+//! arguments. Records files do not tell how long reads take, so a slice lasts until the next
+//! record of its file, or until the next record of the records file for the last record of a
+//! file. Slices of a track thus never overlap. Timestamps are those of the trace the records
+//! file was recorded from.
+
+use std::collections::BTreeMap;
+use std::io::Write;
+
+use serde::Serialize;
+
+use crate::format::{FileId, InodeInfo, Record, RecordsFile};
+use crate::Error;
+
+// Name of the slices of records.
+const SLICE_NAME: &str = "read";
+
+// A track of the timeline.
+struct Track<'a> {
+    name: &'a str,
+    // Index of the mount track of a file track.
+    parent: Option<usize>,
+}
+
+// Mount and file tracks of `rf`, mounts first, and the index of the track of each file. Tracks
+// are ordered by name and file id so that the timeline does not depend on hash map order.
+fn tracks<'a>(
+    rf: &'a RecordsFile,
+    mounts: &'a [String],
+) -> (Vec<Track<'a>>, BTreeMap<&'a FileId, usize>) {
+    let mut tracks: Vec<Track<'a>> =
+        mounts.iter().map(|name| Track { name, parent: None }).collect();
+    let mut files: Vec<(&FileId, &InodeInfo)> = rf.inner.inode_map.iter().collect();
+    files.sort_by_key(|(id, _)| *id);
+    let mut indexes = BTreeMap::new();
+    for (id, info) in files {
+        let mount = rf.mount_name(info);
+        let parent = mounts.iter().position(|name| *name == mount);
+        indexes.insert(id, tracks.len());
+        tracks.push(Track { name: &info.paths[0], parent });
+    }
+    (tracks, indexes)
+}
+
+// A record, the index of the track of its file and its duration in nanoseconds.
+struct Slice<'a> {
+    record: &'a Record,
+    track: usize,
+    duration: u64,
+}
+
+// Slices of the records of files in `indexes`, by timestamp.
+fn slices<'a>(rf: &'a RecordsFile, indexes: &BTreeMap<&FileId, usize>) -> Vec<Slice<'a>> {
+    let mut slices: Vec<Slice<'a>> = rf
+        .inner
+        .records
+        .iter()
+        .filter_map(|record| {
+            let track = *indexes.get(&record.file_id)?;
+            Some(Slice { record, track, duration: 0 })
+        })
+        .collect();
+    slices.sort_by_key(|slice| slice.record.timestamp);
+    let mut next_of_track = BTreeMap::new();
+    let mut next = None;
+    for slice in slices.iter_mut().rev() {
+        let timestamp = slice.record.timestamp;
+        if let Some(end) = next_of_track.insert(slice.track, timestamp).or(next) {
+            slice.duration = end - timestamp;
+        }
+        next = Some(timestamp);
+    }
+    slices
+}
+
+// Sorted names of the mounts of the files of `rf`.
+fn mount_names(rf: &RecordsFile) -> Vec<String> {
+    let mut mounts: Vec<String> =
+        rf.inner.inode_map.values().map(|info| rf.mount_name(info)).collect();
+    mounts.sort_unstable();
+    mounts.dedup();
+    mounts
+}
+
+// Appends fields of protocol buffer messages to a buffer.
+#[derive(Default)]
+struct ProtoWriter {
+    buf: Vec<u8>,
+}
+
+impl ProtoWriter {
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.buf.push((value as u8) | 0x80);
+            value >>= 7;
+        }
+        self.buf.push(value as u8);
+    }
+
+    fn uint(&mut self, field: u32, value: u64) {
+        self.varint((field as u64) << 3);
+        self.varint(value);
+    }
+
+    fn bytes(&mut self, field: u32, value: &[u8]) {
+        self.varint(((field as u64) << 3) | 2);
+        self.varint(value.len() as u64);
+        self.buf.extend_from_slice(value);
+    }
+
+    fn string(&mut self, field: u32, value: &str) {
+        self.bytes(field, value.as_bytes());
+    }
+
+    fn message(&mut self, field: u32, build: impl FnOnce(&mut ProtoWriter)) {
+        let mut message = ProtoWriter::default();
+        build(&mut message);
+        self.bytes(field, &message.buf);
+    }
+}
+
+// Field numbers of the Perfetto trace protos, from protos/perfetto/trace.
+const TRACE_PACKET: u32 = 1;
+const PACKET_TIMESTAMP: u32 = 8;
+const PACKET_SEQUENCE_ID: u32 = 10;
+const PACKET_TRACK_EVENT: u32 = 11;
+const PACKET_SEQUENCE_FLAGS: u32 = 13;
+const PACKET_TRACK_DESCRIPTOR: u32 = 60;
+const TRACK_UUID: u32 = 1;
+const TRACK_NAME: u32 = 2;
+const TRACK_PARENT_UUID: u32 = 5;
+const EVENT_DEBUG_ANNOTATION: u32 = 4;
+const EVENT_TYPE: u32 = 9;
+const EVENT_TRACK_UUID: u32 = 11;
+const EVENT_NAME: u32 = 23;
+const ANNOTATION_UINT_VALUE: u32 = 3;
+const ANNOTATION_NAME: u32 = 10;
+
+const SEQUENCE_ID: u64 = 1;
+const SEQ_INCREMENTAL_STATE_CLEARED: u64 = 1;
+const TYPE_SLICE_BEGIN: u64 = 1;
+const TYPE_SLICE_END: u64 = 2;
+
+// Uuid of the track at `index`. Zero is not a valid uuid.
+fn track_uuid(index: usize) -> u64 {
+    index as u64 + 1
+}
+
+/// Writes the records of `rf` as a Perfetto protobuf trace.
+pub fn write_perfetto_trace(rf: &RecordsFile, writer: &mut dyn Write) -> Result<(), Error> {
+    let mounts = mount_names(rf);
+    let (tracks, indexes) = tracks(rf, &mounts);
+    let mut trace = ProtoWriter::default();
+    for (index, track) in tracks.iter().enumerate() {
+        trace.message(TRACE_PACKET, |packet| {
+            packet.uint(PACKET_SEQUENCE_ID, SEQUENCE_ID);
+            if index == 0 {
+                packet.uint(PACKET_SEQUENCE_FLAGS, SEQ_INCREMENTAL_STATE_CLEARED);
+            }
+            packet.message(PACKET_TRACK_DESCRIPTOR, |descriptor| {
+                descriptor.uint(TRACK_UUID, track_uuid(index));
+                descriptor.string(TRACK_NAME, track.name);
+                if let Some(parent) = track.parent {
+                    descriptor.uint(TRACK_PARENT_UUID, track_uuid(parent));
+                }
+            });
+        });
+    }
+    for Slice { record, track, duration } in slices(rf, &indexes) {
+        trace.message(TRACE_PACKET, |packet| {
+            packet.uint(PACKET_TIMESTAMP, record.timestamp);
+            packet.uint(PACKET_SEQUENCE_ID, SEQUENCE_ID);
+            packet.message(PACKET_TRACK_EVENT, |event| {
+                event.uint(EVENT_TYPE, TYPE_SLICE_BEGIN);
+                event.uint(EVENT_TRACK_UUID, track_uuid(track));
+                event.string(EVENT_NAME, SLICE_NAME);
+                for (name, value) in [("offset", record.offset), ("length", record.length)] {
+                    event.message(EVENT_DEBUG_ANNOTATION, |annotation| {
+                        annotation.string(ANNOTATION_NAME, name);
+                        annotation.uint(ANNOTATION_UINT_VALUE, value);
+                    });
+                }
+            });
+        });
+        trace.message(TRACE_PACKET, |packet| {
+            packet.uint(PACKET_TIMESTAMP, record.timestamp + duration);
+            packet.uint(PACKET_SEQUENCE_ID, SEQUENCE_ID);
+            packet.message(PACKET_TRACK_EVENT, |event| {
+                event.uint(EVENT_TYPE, TYPE_SLICE_END);
+                event.uint(EVENT_TRACK_UUID, track_uuid(track));
+            });
+        });
+    }
+    writer.write_all(&trace.buf)?;
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct ChromeArgs<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    offset: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    length: Option<u64>,
+}
+
+// An event of the Chrome JSON trace format.
+#[derive(Serialize)]
+struct ChromeEvent<'a> {
+    name: &'a str,
+    ph: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ts: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dur: Option<f64>,
+    pid: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tid: Option<usize>,
+    args: ChromeArgs<'a>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ChromeTrace<'a> {
+    trace_events: Vec<ChromeEvent<'a>>,
+    display_time_unit: &'a str,
+}
+
+/// Writes the records of `rf` as a Chrome JSON trace. Mounts are processes and files are
+/// threads.
+pub fn write_chrome_trace(rf: &RecordsFile, writer: &mut dyn Write) -> Result<(), Error> {
+    let mounts = mount_names(rf);
+    let (tracks, indexes) = tracks(rf, &mounts);
+    let mut trace_events = vec![];
+    // Processes and threads are numbered from one as trace viewers treat zero specially.
+    let pid = |index: usize| tracks[index].parent.unwrap_or(index) + 1;
+    for (index, track) in tracks.iter().enumerate() {
+        let (name, tid) = if track.parent.is_some() {
+            ("thread_name", Some(index + 1))
+        } else {
+            ("process_name", None)
+        };
+        trace_events.push(ChromeEvent {
+            name,
+            ph: "M",
+            ts: None,
+            dur: None,
+            pid: pid(index),
+            tid,
+            args: ChromeArgs { name: Some(track.name), offset: None, length: None },
+        });
+    }
+    for Slice { record, track, duration } in slices(rf, &indexes) {
+        trace_events.push(ChromeEvent {
+            name: SLICE_NAME,
+            ph: "X",
+            ts: Some(record.timestamp as f64 / 1000.0),
+            dur: Some(duration as f64 / 1000.0),
+            pid: pid(track),
+            tid: Some(track + 1),
+            args: ChromeArgs {
+                name: None,
+                offset: Some(record.offset),
+                length: Some(record.length),
+            },
+        });
+    }
+    serde_json::to_writer(writer, &ChromeTrace { trace_events, display_time_unit: "ns" })
+        .map_err(|e| Error::Serialize { error: e.to_string() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::Record;
+
+    fn records_file() -> RecordsFile {
+        let mut rf = RecordsFile::default();
+        rf.insert_or_update_inode_info(
+            FileId(1),
+            InodeInfo::new(10, 8192, vec!["/system/bin/init".to_owned()], 1),
+        );
+        rf.insert_or_update_inode_info(
+            FileId(0),
+            InodeInfo::new(11, 4096, vec!["/vendor/lib/libfoo.so".to_owned()], 2),
+        );
+        rf.insert_record(Record {
+            file_id: FileId(1),
+            offset: 4096,
+            length: 4096,
+            timestamp: 1500,
+        });
+        rf.insert_record(Record { file_id: FileId(0), offset: 0, length: 4096, timestamp: 2000 });
+        rf
+    }
+
+    #[test]
+    fn test_varint() {
+        let mut writer = ProtoWriter::default();
+        writer.varint(1);
+        writer.varint(300);
+        writer.uint(PACKET_TRACK_DESCRIPTOR, 1);
+        assert_eq!(writer.buf, vec![0x01, 0xac, 0x02, 0xe0, 0x03, 0x01]);
+    }
+
+    #[test]
+    fn test_write_perfetto_trace() {
+        let mut trace = vec![];
+        write_perfetto_trace(&records_file(), &mut trace).unwrap();
+
+        // Splits the trace into its packets.
+        let mut packets = vec![];
+        let mut rest = &trace[..];
+        while !rest.is_empty() {
+            assert_eq!(rest[0], (TRACE_PACKET << 3 | 2) as u8);
+            let len = rest[1] as usize;
+            assert!(len < 0x80);
+            packets.push(&rest[2..2 + len]);
+            rest = &rest[2 + len..];
+        }
+        // Two mount tracks, two file tracks and the beginning and end of two records.
+        assert_eq!(packets.len(), 8);
+        let mut first_track = ProtoWriter::default();
+        first_track.uint(PACKET_SEQUENCE_ID, SEQUENCE_ID);
+        first_track.uint(PACKET_SEQUENCE_FLAGS, SEQ_INCREMENTAL_STATE_CLEARED);
+        first_track.message(PACKET_TRACK_DESCRIPTOR, |descriptor| {
+            descriptor.uint(TRACK_UUID, 1);
+            descriptor.string(TRACK_NAME, "device 0:1");
+        });
+        assert_eq!(packets[0], &first_track.buf[..]);
+        // File 0 is on the second mount.
+        let mut file_track = ProtoWriter::default();
+        file_track.uint(PACKET_SEQUENCE_ID, SEQUENCE_ID);
+        file_track.message(PACKET_TRACK_DESCRIPTOR, |descriptor| {
+            descriptor.uint(TRACK_UUID, 3);
+            descriptor.string(TRACK_NAME, "/vendor/lib/libfoo.so");
+            descriptor.uint(TRACK_PARENT_UUID, 2);
+        });
+        assert_eq!(packets[2], &file_track.buf[..]);
+        let mut first_timestamp = ProtoWriter::default();
+        first_timestamp.uint(PACKET_TIMESTAMP, 1500);
+        assert!(packets[4].starts_with(&first_timestamp.buf));
+        // The first record lasts until the second one, the last one of the records file.
+        let mut first_end = ProtoWriter::default();
+        first_end.uint(PACKET_TIMESTAMP, 2000);
+        first_end.uint(PACKET_SEQUENCE_ID, SEQUENCE_ID);
+        first_end.message(PACKET_TRACK_EVENT, |event| {
+            event.uint(EVENT_TYPE, TYPE_SLICE_END);
+            event.uint(EVENT_TRACK_UUID, 4);
+        });
+        assert_eq!(packets[5], &first_end.buf[..]);
+        let mut last_end = ProtoWriter::default();
+        last_end.uint(PACKET_TIMESTAMP, 2000);
+        assert!(packets[7].starts_with(&last_end.buf));
+    }
+
+    #[test]
+    fn test_write_chrome_trace() {
+        let mut trace = vec![];
+        write_chrome_trace(&records_file(), &mut trace).unwrap();
+        let trace: serde_json::Value = serde_json::from_slice(&trace).unwrap();
+        let events = trace["traceEvents"].as_array().unwrap();
+        assert_eq!(events.len(), 6);
+        assert_eq!(
+            events[3],
+            serde_json::json!({
+                "name": "thread_name", "ph": "M", "pid": 1, "tid": 4,
+                "args": {"name": "/system/bin/init"}
+            })
+        );
+        assert_eq!(
+            events[4],
+            serde_json::json!({
+                "name": "read", "ph": "X", "ts": 1.5, "dur": 0.5, "pid": 1, "tid": 4,
+                "args": {"offset": 4096, "length": 4096}
+            })
+        );
+        assert_eq!(events[5]["dur"], 0.0);
+        assert_eq!(trace["displayTimeUnit"], "ns");
+    }
+
+    #[test]
+    fn test_slices() {
+        let mut rf = records_file();
+        rf.insert_record(Record { file_id: FileId(1), offset: 0, length: 4096, timestamp: 1800 });
+        rf.insert_record(Record { file_id: FileId(1), offset: 0, length: 4096, timestamp: 2600 });
+        let mounts = mount_names(&rf);
+        let (_, indexes) = tracks(&rf, &mounts);
+        let durations: Vec<(u64, u64)> = slices(&rf, &indexes)
+            .iter()
+            .map(|slice| (slice.record.timestamp, slice.duration))
+            .collect();
+        // Records of file 1 last until the next one of file 1, the one of file 0 until the next
+        // record of the records file, and the last record has no duration.
+        assert_eq!(durations, vec![(1500, 300), (1800, 800), (2000, 600), (2600, 0)]);
+    }
+}
@@ -574,7 +574,6 @@ impl TraceSubsystem for MemTraceSubsystem {
         // reset debug_info in case build_records_file was called twice.
         self.debug_info = DebugInfo::default();
         let mut rf = RecordsFile::default();
-        let mut directories = HashSet::new();
 
         // TODO(b/302194377): We are holding all privileged_paths in this variable and then
         // transferring it to `self.debug_info.privileged_paths` later. We can avoid this step
@@ -639,15 +638,19 @@ impl TraceSubsystem for MemTraceSubsystem {
                     continue;
                 };
 
-                // We cannot issue a normal readahead on directories. So we skip those records that
-                // belong to directories.
+                // We cannot issue a normal readahead on directories. So records that belong to
+                // directories are kept apart and replayed by walking the directories.
                 if stat.file_type().is_dir() {
-                    info!(
-                        "skipping directory readahead record for file_id:{file_id} ino:{} path:{} ",
+                    debug!(
+                        "directory readahead record for file_id:{file_id} ino:{} path:{} ",
                         stat.ino(),
                         path.to_str().unwrap()
                     );
-                    directories.insert(file_id.clone());
+                    rf.insert_or_update_directory(
+                        file_id,
+                        &stat,
+                        path.to_str().unwrap().to_owned(),
+                    );
                     continue;
                 }
 
@@ -661,7 +664,9 @@ impl TraceSubsystem for MemTraceSubsystem {
 
         for (device, inode_map) in &self.device_inode_map {
             for (inode, file_id) in inode_map {
-                if !rf.inner.inode_map.contains_key(file_id) {
+                if !rf.inner.inode_map.contains_key(file_id)
+                    && !rf.inner.directories.contains_key(file_id)
+                {
                     let major_no: MajorMinorType = major(*device);
                     let minor_no: MajorMinorType = minor(*device);
                     self.debug_info.missing_files.insert(
@@ -672,11 +677,14 @@ impl TraceSubsystem for MemTraceSubsystem {
             }
         }
 
-        // Remove all records that belong to directories or for which we did not find paths.
+        // Separate records that belong to directories and remove records for which we did not
+        // find paths.
         let mut records = vec![];
+        let mut directory_records = vec![];
         for record in take(&mut self.records) {
-            if directories.contains(&record.file_id) {
+            if rf.inner.directories.contains_key(&record.file_id) {
                 self.debug_info.directory_read_bytes += record.length;
+                directory_records.push(record);
             } else if let Some(missing_file) =
                 self.debug_info.missing_files.get_mut(&record.file_id)
             {
@@ -697,6 +705,13 @@ impl TraceSubsystem for MemTraceSubsystem {
         );
 
         rf.inner.records = coalesce_records(records, true);
+        rf.inner.directory_records = coalesce_records(directory_records, true);
+
+        // File handles let replay skip the path walk and find files that were renamed.
+        if let Err(e) = rf.insert_file_handles() {
+            warn!("failed to record file handles: {e}");
+        }
+        rf.insert_first_block_digests();
 
         Ok(rf)
     }
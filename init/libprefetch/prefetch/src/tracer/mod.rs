@@ -607,6 +607,9 @@ pub(crate) mod tests {
                 .collect();
 
             inode.paths = new_paths;
+            // The copies are different files from the ones recorded. They only stand in for them.
+            inode.handle = None;
+            inode.freshness = None;
         }
 
         modified_rf
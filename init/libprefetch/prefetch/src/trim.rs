@@ -0,0 +1,253 @@
+// Copyright (C) 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Cuts records files down to a byte budget.
+//!
+//! Replaying more than fits in the page cache of low memory devices evicts pages that boot
+//! still needs. A policy picks which records fill the budget. Within the share of the budget a
+//! policy gives to a file or a mount, records are kept in the order they were read and the
+//! record that crosses the budget is shortened to a multiple of the block size of its
+//! filesystem, or of the page size if the records file does not know the filesystem.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use serde::{Deserialize, Serialize};
+
+use crate::format::{FileId, Record, RecordsFile};
+use crate::tracer::page_size;
+use crate::Error;
+
+/// Which records are kept when a records file is cut down to a byte budget.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TrimPolicy {
+    /// Records read first are kept.
+    #[default]
+    Earliest,
+    /// Files with the most of their size read are kept whole first.
+    Densest,
+    /// The budget is shared evenly by the mounts. Mounts that read less than their share leave
+    /// the rest to the others.
+    PerMount,
+}
+
+// Keeps the records that fit the budget of their key, in order. The budget of a key without
+// one is zero. A record that crosses its budget is shortened to the whole blocks that fit.
+fn take_within_budgets<K: Eq + Hash>(
+    records: Vec<Record>,
+    mut budgets: HashMap<K, u64>,
+    block_sizes: &HashMap<FileId, u64>,
+    key: impl Fn(&Record) -> K,
+) -> Vec<Record> {
+    let mut kept = vec![];
+    for mut record in records {
+        let budget = match budgets.get_mut(&key(&record)) {
+            Some(budget) if *budget > 0 => budget,
+            _ => continue,
+        };
+        if record.length > *budget {
+            let block_size = block_sizes[&record.file_id];
+            record.length = *budget / block_size * block_size;
+            if record.length == 0 {
+                continue;
+            }
+        }
+        *budget -= record.length;
+        kept.push(record);
+    }
+    kept
+}
+
+// Hands `max_bytes` out to keys in the order of `wants`, giving each key what it asks for as
+// long as there is some left.
+fn fill_in_order<K: Eq + Hash>(wants: Vec<(K, u64)>, mut max_bytes: u64) -> HashMap<K, u64> {
+    let mut budgets = HashMap::new();
+    for (key, bytes) in wants {
+        let budget = bytes.min(max_bytes);
+        max_bytes -= budget;
+        budgets.insert(key, budget);
+    }
+    budgets
+}
+
+// Shares `max_bytes` evenly by the keys of `wants`. Keys that ask for less than their share get
+// what they ask for and the rest is shared by the others.
+fn share_evenly<K: Eq + Hash>(mut wants: Vec<(K, u64)>, mut max_bytes: u64) -> HashMap<K, u64> {
+    wants.sort_by_key(|(_, bytes)| *bytes);
+    let mut budgets = HashMap::new();
+    let count = wants.len() as u64;
+    for (i, (key, bytes)) in wants.into_iter().enumerate() {
+        let budget = bytes.min(max_bytes / (count - i as u64));
+        max_bytes -= budget;
+        budgets.insert(key, budget);
+    }
+    budgets
+}
+
+/// Cuts the records of `rf` down to `max_bytes` according to `policy`, and removes the files no
+/// record is left for. Returns the number of bytes removed.
+pub fn trim_records_file(
+    rf: &mut RecordsFile,
+    max_bytes: u64,
+    policy: TrimPolicy,
+) -> Result<u64, Error> {
+    let page_size = page_size()? as u64;
+    let records = std::mem::take(&mut rf.inner.records);
+    let total_bytes: u64 = records.iter().map(|record| record.length).sum();
+    let mut file_bytes: HashMap<FileId, u64> = HashMap::new();
+    for record in &records {
+        *file_bytes.entry(record.file_id.clone()).or_default() += record.length;
+    }
+    let block_sizes: HashMap<FileId, u64> = file_bytes
+        .keys()
+        .map(|id| {
+            let block_size = rf
+                .inner
+                .inode_map
+                .get(id)
+                .and_then(|info| rf.inner.filesystems.get(&info.device_number))
+                .map_or(page_size, |fs| fs.block_size)
+                .max(1);
+            (id.clone(), block_size)
+        })
+        .collect();
+
+    rf.inner.records = match policy {
+        TrimPolicy::Earliest => {
+            take_within_budgets(records, HashMap::from([((), max_bytes)]), &block_sizes, |_| ())
+        }
+        TrimPolicy::Densest => {
+            let mut wants: Vec<(FileId, u64)> = file_bytes.into_iter().collect();
+            let density = |(id, bytes): &(FileId, u64)| {
+                let size = rf.inner.inode_map.get(id).map_or(0, |info| info.file_size);
+                *bytes as f64 / size.max(1) as f64
+            };
+            wants.sort_by(|a, b| density(b).total_cmp(&density(a)).then_with(|| a.0.cmp(&b.0)));
+            take_within_budgets(records, fill_in_order(wants, max_bytes), &block_sizes, |record| {
+                record.file_id.clone()
+            })
+        }
+        TrimPolicy::PerMount => {
+            let mount_of = |id: &FileId| {
+                rf.inner.inode_map.get(id).map(|info| rf.mount_name(info)).unwrap_or_default()
+            };
+            let mut mount_bytes: HashMap<String, u64> = HashMap::new();
+            for (id, bytes) in &file_bytes {
+                *mount_bytes.entry(mount_of(id)).or_default() += bytes;
+            }
+            let mounts: HashMap<FileId, String> =
+                file_bytes.keys().map(|id| (id.clone(), mount_of(id))).collect();
+            take_within_budgets(
+                records,
+                share_evenly(mount_bytes.into_iter().collect(), max_bytes),
+                &block_sizes,
+                |record| mounts[&record.file_id].clone(),
+            )
+        }
+    };
+    rf.remove_orphans();
+    Ok(total_bytes - rf.inner.records.iter().map(|record| record.length).sum::<u64>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{FsInfo, InodeInfo};
+
+    // Files of 64KiB on two devices, with their records.
+    fn records_file() -> RecordsFile {
+        let mut rf = RecordsFile::default();
+        for (id, device) in [(0, 1), (1, 1), (2, 2)] {
+            rf.insert_or_update_inode_info(
+                FileId(id),
+                InodeInfo::new(id, 65536, vec![format!("/file{id}")], device),
+            );
+        }
+        for (id, offset, length, timestamp) in [
+            (0, 0, 4096, 1),
+            (1, 0, 32768, 2),
+            (2, 0, 8192, 3),
+            (0, 4096, 4096, 4),
+            (1, 32768, 32768, 5),
+        ] {
+            rf.insert_record(Record { file_id: FileId(id), offset, length, timestamp });
+        }
+        rf
+    }
+
+    fn lengths(rf: &RecordsFile) -> Vec<(u64, u64)> {
+        rf.inner.records.iter().map(|record| (record.file_id.0, record.length)).collect()
+    }
+
+    #[test]
+    fn test_trim_earliest() {
+        let mut rf = records_file();
+        assert_eq!(trim_records_file(&mut rf, 40960, TrimPolicy::Earliest).unwrap(), 81920 - 40960);
+        assert_eq!(lengths(&rf), vec![(0, 4096), (1, 32768), (2, 4096)]);
+        rf.check().unwrap();
+
+        let mut rf = records_file();
+        assert_eq!(trim_records_file(&mut rf, 1 << 20, TrimPolicy::Earliest).unwrap(), 0);
+        assert_eq!(rf.inner, records_file().inner);
+    }
+
+    #[test]
+    fn test_trim_densest() {
+        // File 1 is read whole and kept first, then file 0, as dense as file 2 but with a lower
+        // id.
+        let mut rf = records_file();
+        trim_records_file(&mut rf, 65536 + 8192, TrimPolicy::Densest).unwrap();
+        assert_eq!(lengths(&rf), vec![(0, 4096), (1, 32768), (0, 4096), (1, 32768)]);
+        rf.check().unwrap();
+        assert!(!rf.inner.inode_map.contains_key(&FileId(2)));
+    }
+
+    #[test]
+    fn test_trim_per_mount() {
+        // Device 2 reads 8KiB, less than half, and leaves the rest to device 1.
+        let mut rf = records_file();
+        trim_records_file(&mut rf, 40960, TrimPolicy::PerMount).unwrap();
+        assert_eq!(lengths(&rf), vec![(0, 4096), (1, 28672), (2, 8192)]);
+        rf.check().unwrap();
+
+        let mut rf = records_file();
+        trim_records_file(&mut rf, 8192, TrimPolicy::PerMount).unwrap();
+        assert_eq!(lengths(&rf), vec![(0, 4096), (2, 4096)]);
+    }
+
+    #[test]
+    fn test_trim_unaligned_budget() {
+        // The 100 bytes left for file 2 are less than a page, the block size when the
+        // filesystem is not known.
+        let mut rf = records_file();
+        trim_records_file(&mut rf, 36864 + 100, TrimPolicy::Earliest).unwrap();
+        assert_eq!(lengths(&rf), vec![(0, 4096), (1, 32768)]);
+        rf.check().unwrap();
+
+        // File 1 is shortened to one 16KiB block of its filesystem and leaves 4KiB to file 2.
+        let mut rf = records_file();
+        rf.inner.filesystems.insert(1, FsInfo { block_size: 16384 });
+        rf.inner.filesystems.insert(2, FsInfo { block_size: 4096 });
+        trim_records_file(&mut rf, 4096 + 20480, TrimPolicy::Earliest).unwrap();
+        assert_eq!(lengths(&rf), vec![(0, 4096), (1, 16384), (2, 4096)]);
+        rf.check().unwrap();
+    }
+
+    #[test]
+    fn test_share_evenly() {
+        let budgets = share_evenly(vec![("a", 100), ("b", 10), ("c", 100)], 120);
+        assert_eq!((budgets["a"], budgets["b"], budgets["c"]), (55, 10, 55));
+    }
+}
@@ -0,0 +1,297 @@
+// Copyright (C) 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Checks a records file against the live filesystem without reading any file data.
+//!
+//! Updates that do not change the build fingerprint, like APEX or mainline module updates, can
+//! replace files that a records file refers to. Replaying such a records file reads the wrong
+//! data or fails to open files.
+
+use std::collections::HashMap;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+
+use serde::Serialize;
+
+use crate::format::{DeviceNumber, FileId, InodeNumber, RecordsFile};
+use crate::tracer::page_size;
+use crate::Error;
+
+/// A difference between a file as recorded and as found on the filesystem.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case", tag = "kind")]
+pub enum Mismatch {
+    /// The path could not be stat'ed.
+    Missing {
+        /// Path of the file.
+        path: String,
+        /// Error returned by stat.
+        error: String,
+    },
+    /// The path refers to a different inode.
+    InodeNumber {
+        /// Path of the file.
+        path: String,
+        /// Recorded inode number.
+        expected: InodeNumber,
+        /// Inode number found on the filesystem.
+        actual: InodeNumber,
+    },
+    /// The path is on a different device.
+    DeviceNumber {
+        /// Path of the file.
+        path: String,
+        /// Recorded device number.
+        expected: DeviceNumber,
+        /// Device number found on the filesystem.
+        actual: DeviceNumber,
+    },
+    /// The file size changed.
+    FileSize {
+        /// Path of the file.
+        path: String,
+        /// Recorded file size.
+        expected: u64,
+        /// File size found on the filesystem.
+        actual: u64,
+    },
+    /// A record ends past the last block of the file.
+    RecordOutOfRange {
+        /// Offset of the record.
+        offset: u64,
+        /// Length of the record.
+        length: u64,
+        /// File size the record was checked against.
+        file_size: u64,
+    },
+}
+
+/// Mismatches of one file of the records file.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct FileMismatches {
+    /// Id of the file in the records file.
+    pub file_id: FileId,
+
+    /// Mismatches found, per path and then per record.
+    pub mismatches: Vec<Mismatch>,
+}
+
+/// Outcome of checking a records file against the filesystem.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct VerifyReport {
+    /// Number of files in the records file.
+    pub files_checked: u64,
+
+    /// Number of files whose paths all match and whose records are in range.
+    pub files_ok: u64,
+
+    /// Number of files with at least one mismatch.
+    pub files_mismatched: u64,
+
+    /// Number of paths checked.
+    pub paths_checked: u64,
+
+    /// Number of paths that could not be stat'ed.
+    pub paths_missing: u64,
+
+    /// Number of records that end past the last block of their file.
+    pub records_out_of_range: u64,
+
+    /// Mismatches, ordered by file id.
+    pub files: Vec<FileMismatches>,
+}
+
+impl VerifyReport {
+    /// Returns true if the records file matches the filesystem.
+    pub fn is_ok(&self) -> bool {
+        self.files_mismatched == 0
+    }
+}
+
+/// Stats every path of every file of `rf` and checks that inode number, device number and file
+/// size are as recorded and that records fall within the file. No file data is read.
+///
+/// Records are checked against the size found on the filesystem, or the recorded size if no
+/// path could be stat'ed. As records are block aligned, a record may end anywhere in the last
+/// block of the file.
+pub fn verify_records_file(rf: &RecordsFile) -> Result<VerifyReport, Error> {
+    let page_size = page_size()? as u64;
+    let mut records_by_file: HashMap<&FileId, Vec<(u64, u64)>> = HashMap::new();
+    for record in &rf.inner.records {
+        records_by_file.entry(&record.file_id).or_default().push((record.offset, record.length));
+    }
+
+    let mut ids: Vec<&FileId> = rf.inner.inode_map.keys().collect();
+    ids.sort();
+
+    let mut report = VerifyReport::default();
+    for id in ids {
+        let info = &rf.inner.inode_map[id];
+        let mut mismatches = vec![];
+        let mut file_size = None;
+        for path in &info.paths {
+            report.paths_checked += 1;
+            let stat = match fs::metadata(path) {
+                Ok(stat) => stat,
+                Err(e) => {
+                    report.paths_missing += 1;
+                    mismatches.push(Mismatch::Missing { path: path.clone(), error: e.to_string() });
+                    continue;
+                }
+            };
+            if stat.ino() != info.inode_number {
+                mismatches.push(Mismatch::InodeNumber {
+                    path: path.clone(),
+                    expected: info.inode_number,
+                    actual: stat.ino(),
+                });
+            }
+            if stat.dev() != info.device_number {
+                mismatches.push(Mismatch::DeviceNumber {
+                    path: path.clone(),
+                    expected: info.device_number,
+                    actual: stat.dev(),
+                });
+            }
+            if stat.len() != info.file_size {
+                mismatches.push(Mismatch::FileSize {
+                    path: path.clone(),
+                    expected: info.file_size,
+                    actual: stat.len(),
+                });
+            }
+            file_size.get_or_insert(stat.len());
+        }
+
+        let file_size = file_size.unwrap_or(info.file_size);
+        let block_size = rf
+            .inner
+            .filesystems
+            .get(&info.device_number)
+            .map_or(page_size, |fs| fs.block_size)
+            .max(1);
+        let limit = file_size.div_ceil(block_size) * block_size;
+        let mut records = records_by_file.remove(id).unwrap_or_default();
+        records.sort_unstable();
+        for (offset, length) in records {
+            if offset.saturating_add(length) > limit {
+                report.records_out_of_range += 1;
+                mismatches.push(Mismatch::RecordOutOfRange { offset, length, file_size });
+            }
+        }
+
+        report.files_checked += 1;
+        if mismatches.is_empty() {
+            report.files_ok += 1;
+        } else {
+            report.files_mismatched += 1;
+            report.files.push(FileMismatches { file_id: id.clone(), mismatches });
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::Record;
+    use crate::tracer::tests::setup_test_dir;
+    use std::path::Path;
+
+    fn create_file(dir: &Path, name: &str, len: usize) -> String {
+        let path = dir.join(name);
+        fs::write(&path, vec![1u8; len]).unwrap();
+        path.to_str().unwrap().to_owned()
+    }
+
+    fn add_file(rf: &mut RecordsFile, id: u64, path: &str, records: &[(u64, u64)]) {
+        rf.insert_or_update_inode(FileId(id), &fs::metadata(path).unwrap(), path.to_owned());
+        for (offset, length) in records {
+            rf.insert_record(Record {
+                file_id: FileId(id),
+                offset: *offset,
+                length: *length,
+                timestamp: 0,
+            });
+        }
+    }
+
+    #[test]
+    fn test_verify_matching_files() {
+        let dir = setup_test_dir();
+        let mut rf = RecordsFile::default();
+        // The record ends in the last block of the file.
+        add_file(&mut rf, 0, &create_file(&dir, "a", 10000), &[(0, 4096), (8192, 4096)]);
+        add_file(&mut rf, 1, &create_file(&dir, "b", 4096), &[(0, 4096)]);
+
+        let report = verify_records_file(&rf).unwrap();
+        assert!(report.is_ok(), "{:?}", report);
+        assert_eq!((report.files_checked, report.files_ok, report.paths_checked), (2, 2, 2));
+    }
+
+    #[test]
+    fn test_verify_mismatches() {
+        let dir = setup_test_dir();
+        let page_size = page_size().unwrap() as u64;
+        let mut rf = RecordsFile::default();
+        let ok = create_file(&dir, "ok", 8192);
+        add_file(&mut rf, 0, &ok, &[(0, 8192)]);
+        let truncated = create_file(&dir, "truncated", 2 * page_size as usize);
+        add_file(&mut rf, 1, &truncated, &[(0, page_size), (page_size, page_size)]);
+        let deleted = create_file(&dir, "deleted", 4096);
+        add_file(&mut rf, 2, &deleted, &[(0, 4096)]);
+        let replaced = create_file(&dir, "replaced", 4096);
+        add_file(&mut rf, 3, &replaced, &[(0, 4096)]);
+        add_file(&mut rf, 4, &create_file(&dir, "out_of_range", 100), &[(0, 2 * page_size)]);
+
+        fs::write(&truncated, vec![1u8; 100]).unwrap();
+        fs::remove_file(&deleted).unwrap();
+        // Writing a new file and renaming it over the old one replaces the inode, like updates
+        // do.
+        let new = create_file(&dir, "new", 4096);
+        let replaced_ino = fs::metadata(&replaced).unwrap().ino();
+        fs::rename(&new, &replaced).unwrap();
+
+        let report = verify_records_file(&rf).unwrap();
+        assert!(!report.is_ok());
+        assert_eq!((report.files_checked, report.files_ok, report.files_mismatched), (5, 1, 4));
+        assert_eq!((report.paths_missing, report.records_out_of_range), (1, 2));
+
+        let mismatches: HashMap<u64, &Vec<Mismatch>> =
+            report.files.iter().map(|file| (file.file_id.0, &file.mismatches)).collect();
+        assert!(!mismatches.contains_key(&0));
+        assert_eq!(
+            mismatches[&1],
+            &vec![
+                Mismatch::FileSize { path: truncated, expected: 2 * page_size, actual: 100 },
+                Mismatch::RecordOutOfRange { offset: page_size, length: page_size, file_size: 100 },
+            ]
+        );
+        assert!(
+            matches!(&mismatches[&2][..], [Mismatch::Missing { path, .. }] if *path == deleted)
+        );
+        assert_eq!(
+            mismatches[&3],
+            &vec![Mismatch::InodeNumber {
+                path: replaced.clone(),
+                expected: replaced_ino,
+                actual: fs::metadata(&replaced).unwrap().ino(),
+            }]
+        );
+        assert_eq!(
+            mismatches[&4],
+            &vec![Mismatch::RecordOutOfRange { offset: 0, length: 2 * page_size, file_size: 100 }]
+        );
+    }
+}